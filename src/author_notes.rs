@@ -0,0 +1,151 @@
+//! Author notes in chapter content
+
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Selector};
+
+/// What is done with the author notes before and after chapters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthorNotes {
+    /// Notes inside the chapter content are left as they are.
+    #[default]
+    Keep,
+    /// Each note is wrapped in an `<aside class="author-note">` in the chapter content, including notes next to it.
+    Separate,
+    /// Notes are removed from the chapter content.
+    Drop,
+}
+
+impl AuthorNotes {
+    /// Apply to the notes matching `selector` in `html` with chapter content `body`.
+    ///
+    /// Notes before `body` are moved to its start and notes after it to its end. Notes inside other notes are
+    /// handled with them.
+    pub fn apply(self, html: &mut Html, selector: &Selector, body: NodeId) {
+        if self == Self::Keep {
+            return;
+        }
+        let notes = html
+            .select(selector)
+            .filter(|x| {
+                !x.ancestors()
+                    .filter_map(ElementRef::wrap)
+                    .any(|x| selector.matches(&x))
+            })
+            .map(|x| x.id())
+            .collect::<Vec<_>>();
+        let in_body = |html: &Html, id: NodeId| {
+            html.tree
+                .get(id)
+                .unwrap()
+                .ancestors()
+                .any(|x| x.id() == body)
+        };
+        if self == Self::Drop {
+            for id in notes {
+                if in_body(html, id) {
+                    html.tree.get_mut(id).unwrap().detach();
+                }
+            }
+            return;
+        }
+
+        let aside = Html::parse_fragment(r#"<aside class="author-note"></aside>"#)
+            .root_element()
+            .first_child()
+            .unwrap()
+            .value()
+            .clone();
+        // Notes before the body are prepended last to first so they stay in order.
+        let mut before_body = Vec::new();
+        for id in notes {
+            let wrapper = if in_body(html, id) {
+                html.tree
+                    .get_mut(id)
+                    .unwrap()
+                    .insert_before(aside.clone())
+                    .id()
+            } else if is_before(html, id, body) {
+                before_body.push(id);
+                continue;
+            } else {
+                html.tree.get_mut(body).unwrap().append(aside.clone()).id()
+            };
+            html.tree.get_mut(wrapper).unwrap().append_id(id);
+        }
+        for id in before_body.into_iter().rev() {
+            let wrapper = html.tree.get_mut(body).unwrap().prepend(aside.clone()).id();
+            html.tree.get_mut(wrapper).unwrap().append_id(id);
+        }
+    }
+}
+
+impl std::str::FromStr for AuthorNotes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "separate" => Ok(Self::Separate),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!(
+                "unknown author notes: {s}. Expected `keep`, `separate`, or `drop`"
+            )),
+        }
+    }
+}
+impl std::fmt::Display for AuthorNotes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Keep => "keep",
+            Self::Separate => "separate",
+            Self::Drop => "drop",
+        })
+    }
+}
+
+/// If `id` comes before `other` in document order.
+fn is_before(html: &Html, id: NodeId, other: NodeId) -> bool {
+    html.tree
+        .root()
+        .descendants()
+        .map(|x| x.id())
+        .find(|&x| x == id || x == other)
+        == Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuthorNotes;
+    use scraper::{Html, Selector};
+
+    fn apply(author_notes: AuthorNotes, html: &str) -> String {
+        let mut html = Html::parse_document(html);
+        let body = html
+            .select(&Selector::parse(".chapter-content").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        author_notes.apply(&mut html, &Selector::parse(".author-note").unwrap(), body);
+        html.tree
+            .get(body)
+            .and_then(scraper::ElementRef::wrap)
+            .unwrap()
+            .html()
+    }
+
+    #[test]
+    fn author_notes() {
+        let html = r#"<div class="author-note">Before</div><div class="author-note">Before 2</div><div class="chapter-content"><p>One</p><div class="author-note">Inside</div><p>Two</p></div><div class="author-note">After</div>"#;
+        assert_eq!(
+            apply(AuthorNotes::Keep, html),
+            r#"<div class="chapter-content"><p>One</p><div class="author-note">Inside</div><p>Two</p></div>"#
+        );
+        assert_eq!(
+            apply(AuthorNotes::Drop, html),
+            r#"<div class="chapter-content"><p>One</p><p>Two</p></div>"#
+        );
+        assert_eq!(
+            apply(AuthorNotes::Separate, html),
+            r#"<div class="chapter-content"><aside class="author-note"><div class="author-note">Before</div></aside><aside class="author-note"><div class="author-note">Before 2</div></aside><p>One</p><aside class="author-note"><div class="author-note">Inside</div></aside><p>Two</p><aside class="author-note"><div class="author-note">After</div></aside></div>"#
+        );
+    }
+}
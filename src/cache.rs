@@ -0,0 +1,124 @@
+//! Cache of the raw pages of chapters
+//!
+//! Pages are saved as fetched so chapters can be processed again (e.g. with changed selectors) without
+//! downloading them.
+
+use crate::{body_response, read_text, ChapterUrl};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+    StatusCode,
+};
+use std::path::PathBuf;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Directory of chapter pages named by the paths of their [`ChapterUrl`]s.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    /// Use cached pages instead of fetching them. Otherwise pages are only saved, replacing those cached.
+    read: bool,
+}
+
+impl ResponseCache {
+    /// Cache in `dir`, which is created when a page is saved. Cached pages are only replaced unless `read`.
+    pub fn new(dir: impl Into<PathBuf>, read: bool) -> Self {
+        Self {
+            dir: dir.into(),
+            read,
+        }
+    }
+    /// Path the page at `url` is cached at.
+    pub fn path(&self, url: &Url) -> PathBuf {
+        self.dir
+            .join(format!("{}.html", ChapterUrl(url.clone()).key()))
+    }
+    /// If the page at `url` is cached and the cache is read.
+    pub fn contains(&self, url: &Url) -> bool {
+        self.read && self.path(url).is_file()
+    }
+    /// Response with the cached page at `url`, or `None` if it isn't cached or the cache isn't read.
+    pub async fn get(&self, url: &Url) -> Option<reqwest::Response> {
+        if !self.read {
+            return None;
+        }
+        let page = tokio::fs::read_to_string(self.path(url)).await.ok()?;
+        debug!("Using cached page of {url}");
+        Some(page_response(url.clone(), HeaderMap::new(), page))
+    }
+    /// Save the page of `response` to the cache and return a response with it.
+    ///
+    /// Failing to save only warns since the page can still be used.
+    pub async fn put(&self, response: reqwest::Response) -> reqwest::Result<reqwest::Response> {
+        let url = response.url().clone();
+        let headers = response.headers().clone();
+        let page = read_text(response).await?;
+        Ok(self.put_page(url, headers, page).await)
+    }
+    /// Save `page`, already read from the response from `url` with `headers`, to the cache and return a response with it.
+    ///
+    /// Like [`ResponseCache::put`] for pages which were checked before they are cached.
+    pub async fn put_page(&self, url: Url, headers: HeaderMap, page: String) -> reqwest::Response {
+        let path = self.path(&url);
+        let saved = match path.parent() {
+            Some(parent) => tokio::fs::create_dir_all(parent).await,
+            None => Ok(()),
+        };
+        if let Err(e) = saved.and(tokio::fs::write(&path, &page).await) {
+            warn!("Couldn't cache {url} at {}: {e}", path.display());
+        }
+        page_response(url, headers, page)
+    }
+}
+
+/// Successful response from `url` with `headers` and the decoded `page`.
+fn page_response(url: Url, mut headers: HeaderMap, page: String) -> reqwest::Response {
+    // The page was decoded so it is read back as UTF-8.
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    body_response(url, StatusCode::OK, headers, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseCache;
+    use url::Url;
+
+    #[tokio::test]
+    async fn cache_pages() -> anyhow::Result<()> {
+        use reqwest::ResponseBuilderExt;
+        let dir = tempfile::tempdir()?;
+        let url =
+            Url::parse("https://www.royalroad.com/fiction/12345/the-title/chapter/1/chapter-1")?;
+        let cache = ResponseCache::new(dir.path(), true);
+        assert!(!cache.contains(&url));
+        assert!(cache.get(&url).await.is_none());
+
+        let response = http::Response::builder()
+            .url(url.clone())
+            .header("etag", "\"1\"")
+            .body("<html>Page</html>")?;
+        let response = cache.put(response.into()).await?;
+        assert_eq!(response.headers()["etag"], "\"1\"");
+        assert_eq!(response.text().await?, "<html>Page</html>");
+        assert_eq!(
+            cache.path(&url),
+            dir.path().join("fiction/12345/chapter/1/chapter-1.html")
+        );
+
+        // Renaming the fiction doesn't change the chapter's url.
+        let renamed =
+            Url::parse("https://www.royalroad.com/fiction/12345/new-title/chapter/1/chapter-1")?;
+        assert!(cache.contains(&renamed));
+        let cached = cache.get(&renamed).await.unwrap();
+        assert_eq!(cached.url(), &renamed);
+        assert_eq!(cached.text().await?, "<html>Page</html>");
+        assert!(ResponseCache::new(dir.path(), false)
+            .get(&url)
+            .await
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! EPUB 3 output
+
+use crate::{escape_text, Chapter, FictionMetadata, Image};
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+use std::{
+    fmt::Write as _,
+    io::{Seek, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+use zip::{result::ZipResult, write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+
+/// Write an EPUB titled `title` for the fiction at `url` with a file per chapter and `cover`, if any.
+///
+/// The author, description, and tags of `metadata` are written as Dublin Core metadata.
+pub fn write_epub(
+    w: impl Write + Seek,
+    title: &str,
+    url: &Url,
+    metadata: &FictionMetadata,
+    cover: Option<&Image>,
+    chapters: &[Chapter],
+) -> ZipResult<()> {
+    let title = escape(title);
+    let url = escape(url.as_str());
+    let mut dc_metadata = String::new();
+    if let Some(author) = &metadata.author {
+        write!(dc_metadata, "<dc:creator>{}</dc:creator>", escape(author)).unwrap();
+    }
+    if let Some(description) = &metadata.description {
+        write!(
+            dc_metadata,
+            "<dc:description>{}</dc:description>",
+            escape(description)
+        )
+        .unwrap();
+    }
+    for tag in &metadata.tags {
+        write!(dc_metadata, "<dc:subject>{}</dc:subject>", escape(tag)).unwrap();
+    }
+    let mut zip = ZipWriter::new(w);
+
+    // The mimetype must come first and be uncompressed so readers can identify the file.
+    zip.start_file(
+        "mimetype",
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+
+    let options = SimpleFileOptions::default();
+    zip.start_file("META-INF/container.xml", options)?;
+    zip.write_all(CONTAINER.as_bytes())?;
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut cover_meta = "";
+    if let Some(cover) = cover {
+        let file = format!("cover.{}", cover.extension());
+        zip.start_file(format!("OEBPS/{file}"), options)?;
+        zip.write_all(&cover.bytes)?;
+        zip.start_file("OEBPS/cover.xhtml", options)?;
+        zip.write_all(xhtml(&title, &format!(r#"<img src="{file}" alt="Cover"/>"#)).as_bytes())?;
+        write!(
+            manifest,
+            r#"<item id="cover-image" href="{file}" media-type="{}" properties="cover-image"/><item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>"#,
+            escape(&cover.content_type)
+        )
+        .unwrap();
+        spine.push_str(r#"<itemref idref="cover"/>"#);
+        // For readers that only support EPUB 2 covers.
+        cover_meta = r#"<meta name="cover" content="cover-image"/>"#;
+    }
+    let mut nav = String::new();
+    let mut nav_points = String::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let n = i + 1;
+        let chapter_title = escape(&chapter.title);
+        let file = format!("chapter{n}.xhtml");
+        write!(
+            manifest,
+            r#"<item id="chapter{n}" href="{file}" media-type="application/xhtml+xml"/>"#
+        )
+        .unwrap();
+        write!(spine, r#"<itemref idref="chapter{n}"/>"#).unwrap();
+        write!(nav, r#"<li><a href="{file}">{chapter_title}</a></li>"#).unwrap();
+        write!(
+            nav_points,
+            r#"<navPoint id="navpoint{n}" playOrder="{n}"><navLabel><text>{chapter_title}</text></navLabel><content src="{file}"/></navPoint>"#
+        )
+        .unwrap();
+
+        zip.start_file(format!("OEBPS/{file}"), options)?;
+        zip.write_all(
+            xhtml(
+                &chapter_title,
+                &format!(
+                    r#"<h1><a class="chapter" href="{}">{chapter_title}</a></h1>{}{}"#,
+                    escape(chapter.url.as_str()),
+                    to_xhtml(&chapter.published_html()),
+                    to_xhtml(&chapter.body_html)
+                ),
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    zip.start_file("OEBPS/nav.xhtml", options)?;
+    zip.write_all(
+        xhtml(
+            &title,
+            &format!(r#"<nav epub:type="toc" id="toc"><h1>{title}</h1><ol>{nav}</ol></nav>"#),
+        )
+        .as_bytes(),
+    )?;
+
+    zip.start_file("OEBPS/toc.ncx", options)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head><meta name="dtb:uid" content="{url}"/></head>
+<docTitle><text>{title}</text></docTitle>
+<navMap>{nav_points}</navMap>
+</ncx>"#
+    )?;
+
+    zip.start_file("OEBPS/content.opf", options)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="id">{url}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:language>en</dc:language>
+<dc:source>{url}</dc:source>
+{dc_metadata}
+<meta property="dcterms:modified">{}</meta>
+{cover_meta}
+</metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+{manifest}
+</manifest>
+<spine toc="ncx">{spine}</spine>
+</package>"#,
+        timestamp(SystemTime::now())
+    )?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Escape text for use as xml content or attribute value.
+fn escape(text: &str) -> String {
+    escape_text(text).replace('"', "&quot;")
+}
+
+/// XHTML document titled with already escaped `title` with already converted `body`.
+fn xhtml(title: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>{title}</title></head>
+<body>{body}</body>
+</html>"#
+    )
+}
+
+/// Convert an html fragment to XHTML by closing void elements and escaping text.
+fn to_xhtml(html: &str) -> String {
+    let html = Html::parse_fragment(html);
+    let mut out = String::new();
+    for child in html.root_element().children() {
+        write_xhtml(&mut out, child);
+    }
+    out
+}
+
+fn write_xhtml(out: &mut String, node: NodeRef<Node>) {
+    match node.value() {
+        Node::Text(text) => out.push_str(&escape_text(text)),
+        Node::Element(element) => {
+            out.push('<');
+            out.push_str(element.name());
+            for (name, value) in element.attrs() {
+                write!(out, r#" {name}="{}""#, escape(value)).unwrap();
+            }
+            if node.has_children() {
+                out.push('>');
+                for child in node.children() {
+                    write_xhtml(out, child);
+                }
+                write!(out, "</{}>", element.name()).unwrap();
+            } else {
+                out.push_str("/>");
+            }
+        }
+        // Comments and such aren't content.
+        _ => {}
+    }
+}
+
+/// UTC timestamp as used by `dcterms:modified` (e.g. `2024-01-31T12:00:00Z`).
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since epoch. See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{timestamp, to_xhtml, write_epub};
+    use crate::{Chapter, FictionMetadata, Image};
+    use std::{
+        io::{Cursor, Read},
+        time::{Duration, UNIX_EPOCH},
+    };
+    use url::Url;
+
+    #[test]
+    fn xhtml_closes_void_elements() {
+        assert_eq!(
+            to_xhtml(r#"<p>a<br>b &amp; <img src="x.png" alt="&quot;"></p><!-- c -->"#),
+            r#"<p>a<br/>b &amp; <img src="x.png" alt="&quot;"/></p>"#
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1709210096)),
+            "2024-02-29T12:34:56Z"
+        );
+    }
+
+    #[test]
+    fn epub_layout() -> anyhow::Result<()> {
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/the-title")?;
+        let chapters = (1..=2)
+            .map(|i| {
+                Ok(Chapter {
+                    index: i - 1,
+                    title: format!("Chapter {i} <&>"),
+                    url: url.join(&format!("/fiction/12345/the-title/chapter/{i}/c"))?,
+                    body_html: format!(
+                        r#"<div class="chapter-content"><p>Content {i}<br></p></div>"#
+                    ),
+                    published: None,
+                    validators: Default::default(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut epub = Cursor::new(Vec::new());
+        let cover = Image {
+            content_type: "image/png".to_owned(),
+            bytes: b"png".to_vec(),
+        };
+        let metadata = FictionMetadata {
+            author: Some("The Author".to_owned()),
+            description: Some("A <story>.".to_owned()),
+            tags: vec!["Fantasy".to_owned(), "Action".to_owned()],
+            status: Some("Ongoing".to_owned()),
+        };
+        write_epub(
+            &mut epub,
+            "The Title",
+            &url,
+            &metadata,
+            Some(&cover),
+            &chapters,
+        )?;
+
+        let mut zip = zip::ZipArchive::new(epub)?;
+        let read = |zip: &mut zip::ZipArchive<_>, name: &str| -> anyhow::Result<String> {
+            let mut out = String::new();
+            zip.by_name(name)?.read_to_string(&mut out)?;
+            Ok(out)
+        };
+        {
+            let mimetype = zip.by_index(0)?;
+            assert_eq!(mimetype.name()?, "mimetype");
+            assert_eq!(mimetype.compression(), zip::CompressionMethod::Stored);
+        }
+        assert_eq!(read(&mut zip, "mimetype")?, "application/epub+zip");
+        let opf = read(&mut zip, "OEBPS/content.opf")?;
+        assert!(opf.contains("<dc:title>The Title</dc:title>"));
+        assert!(opf.contains("<dc:creator>The Author</dc:creator>"));
+        assert!(opf.contains("<dc:description>A &lt;story&gt;.</dc:description>"));
+        assert!(opf.contains("<dc:subject>Fantasy</dc:subject><dc:subject>Action</dc:subject>"));
+        assert!(opf.contains(
+            r#"<itemref idref="cover"/><itemref idref="chapter1"/><itemref idref="chapter2"/>"#
+        ));
+        assert!(opf.contains(r#"properties="cover-image""#));
+        assert_eq!(read(&mut zip, "OEBPS/cover.png")?, "png");
+        assert!(read(&mut zip, "OEBPS/nav.xhtml")?
+            .contains(r#"<a href="chapter2.xhtml">Chapter 2 &lt;&amp;&gt;</a>"#));
+        assert!(read(&mut zip, "OEBPS/toc.ncx")?.contains("<text>Chapter 1 &lt;&amp;&gt;</text>"));
+        assert!(read(&mut zip, "OEBPS/chapter2.xhtml")?
+            .contains(r#"<div class="chapter-content"><p>Content 2<br/></p></div>"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! RSS 2.0 feed of downloaded chapters
+
+use crate::{escape_attribute, TocChapter};
+use std::fmt::Write as _;
+use time::format_description::well_known::Rfc2822;
+use url::Url;
+
+/// RSS feed titled `title` of the fiction at `url` with an item per chapter of `chapters`.
+///
+/// Items are keyed by [`ChapterUrl::key`](crate::ChapterUrl::key) so feed readers recognize them across runs.
+pub fn rss(title: &str, url: &Url, description: Option<&str>, chapters: &[TocChapter]) -> String {
+    let mut items = String::new();
+    for chapter in chapters {
+        write!(
+            items,
+            r#"<item><title>{}</title><link>{}</link><guid isPermaLink="false">{}</guid>"#,
+            escape_attribute(&chapter.title),
+            escape_attribute(chapter.url.0.as_str()),
+            escape_attribute(&chapter.url.key())
+        )
+        .unwrap();
+        if let Some(published) = chapter.published.and_then(|x| x.format(&Rfc2822).ok()) {
+            write!(items, "<pubDate>{published}</pubDate>").unwrap();
+        }
+        items.push_str("</item>\n");
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0"><channel>
+<title>{}</title><link>{}</link><description>{}</description>
+{items}</channel></rss>
+"#,
+        escape_attribute(title),
+        escape_attribute(url.as_str()),
+        escape_attribute(description.unwrap_or(title))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rss;
+    use crate::TocChapter;
+    use time::OffsetDateTime;
+    use url::Url;
+
+    #[test]
+    fn rss_items() -> anyhow::Result<()> {
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/the-title")?;
+        let chapters = [
+            TocChapter {
+                url: url.join("the-title/chapter/1/one")?.into(),
+                title: "One & <Two>".to_owned(),
+                published: Some(OffsetDateTime::from_unix_timestamp(1_700_000_000)?),
+            },
+            TocChapter {
+                url: url.join("the-title/chapter/2/two")?.into(),
+                title: "Two".to_owned(),
+                published: None,
+            },
+        ];
+        let feed = rss("The Title", &url, None, &chapters);
+        assert!(feed.contains("<title>The Title</title><link>https://www.royalroad.com/fiction/12345/the-title</link><description>The Title</description>"));
+        assert!(feed.contains(r#"<item><title>One &amp; &lt;Two&gt;</title><link>https://www.royalroad.com/fiction/12345/the-title/chapter/1/one</link><guid isPermaLink="false">fiction/12345/chapter/1/one</guid><pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate></item>"#));
+        assert!(
+            feed.contains(r#"<guid isPermaLink="false">fiction/12345/chapter/2/two</guid></item>"#)
+        );
+        // Renaming the fiction keeps the guids.
+        let renamed = TocChapter {
+            url: Url::parse("https://www.royalroad.com/fiction/12345/renamed/chapter/2/two")?
+                .into(),
+            ..chapters[1].clone()
+        };
+        assert_eq!(renamed.url.key(), chapters[1].url.key());
+        Ok(())
+    }
+}
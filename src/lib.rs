@@ -1,28 +1,1882 @@
 #![doc=include_str!("../README.md")]
 
-use std::{collections::VecDeque, iter::FusedIterator};
+use author_notes::AuthorNotes;
+use base64::Engine;
+use cache::ResponseCache;
+use ego_tree::NodeId;
+use futures::{stream::FuturesOrdered, Stream};
+use leaky_bucket::RateLimiter;
+use owo_colors::OwoColorize;
+use reqwest::{
+    cookie::Jar,
+    header::{
+        HeaderMap, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+    },
+    ResponseBuilderExt, StatusCode,
+};
+use scraper::{ElementRef, Html, Node};
+use selectors::Selectors;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    hash::{Hash, Hasher},
+    iter::FusedIterator,
+    num::NonZeroUsize,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+use styles::Styles;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    sync::{OnceCell, Semaphore},
+    task::{JoinError, JoinHandle},
+};
+use tracing::{debug, info, warn, Level};
+use url::Url;
+use whitespace::Whitespace;
+
+pub mod author_notes;
+pub mod cache;
+pub mod epub;
+pub mod feed;
+pub mod markdown;
+pub mod sanitize;
+pub mod selectors;
+pub mod site;
+pub mod styles;
+pub mod text;
+pub mod whitespace;
+
+/// Chapter read back from previously downloaded output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedChapter {
+    pub title: String,
+    pub url: Url,
+    /// Html of the chapter after its heading.
+    pub content: String,
+}
+
+/// Parse chapters from the html of previously downloaded output.
+///
+/// Chapters are found by their headings. The content of a chapter is everything between its heading and the next.
+pub fn parse_output(html: &str) -> Vec<ParsedChapter> {
+    let selectors = Selectors::default();
+    let html = Html::parse_document(html);
+    let headings = html
+        .select(&selectors.downloaded_chapters)
+        .filter_map(|x| {
+            let url = Url::parse(x.attr("href")?).ok()?;
+            let heading = x.parent()?;
+            Some((heading, x.text().collect::<String>(), url))
+        })
+        .collect::<Vec<_>>();
+    let heading_ids = headings.iter().map(|x| x.0.id()).collect::<Vec<NodeId>>();
+
+    headings
+        .into_iter()
+        .map(|(heading, title, url)| {
+            let content = heading
+                .next_siblings()
+                .take_while(|x| !heading_ids.contains(&x.id()))
+                .filter_map(|x| {
+                    if let Some(element) = ElementRef::wrap(x) {
+                        Some(element.html())
+                    } else {
+                        x.value().as_text().map(|x| escape_text(x))
+                    }
+                })
+                .collect();
+            ParsedChapter {
+                title,
+                url,
+                content,
+            }
+        })
+        .collect()
+}
+
+/// Escape text for use as html content.
+pub fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Escape text for use as html content or attribute value.
+pub fn escape_attribute(text: &str) -> String {
+    escape_text(text).replace('"', "&quot;")
+}
+
+/// `User-Agent` identifying this tool that clients should send by default.
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Wait before the first retry of a failed request. Doubles with each retry after.
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Cookie set when confirming age to view age restricted fictions.
+///
+/// Unverified: this wasn't checked against the site's actual confirmation, which may differ (e.g. a form post or
+/// account setting). Pass the cookies of a browser that confirmed with `--cookie` if it doesn't work.
+const AGE_CONFIRMATION_COOKIE: &str = "age_confirmed=true";
+
+/// Layout of page changed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PageLayoutError {
+    #[error("main page title not found")]
+    MainTitle,
+    #[error("no chapter links found")]
+    ChapterLinks,
+    #[error("chapter title not found")]
+    ChapterTitle,
+    #[error("chapter body not found")]
+    ChapterBody,
+    #[error("no fiction links found on follows page. Is `--cookie` set to a logged in session?")]
+    FollowsLinks,
+}
+/// The error type for custom errors with the downloader.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Page layout different from expected. Perhaps the website changed?: {0}")]
+    Layout(#[from] PageLayoutError),
+    #[error("{0}")]
+    Request(#[from] reqwest::Error),
+    #[error("chapter took longer than {0:?}")]
+    Timeout(Duration),
+    #[error("fiction is behind an age confirmation. Pass `--confirm-age` to confirm you are old enough to view it.")]
+    AgeGate,
+    #[error("{0}")]
+    Task(#[from] JoinError),
+    #[error("server is overloaded or rate limiting requests{}", .retry_after.map(|x| format!(". Retry after {x:?}")).unwrap_or_default())]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("chapter was removed or unpublished ({0})")]
+    ChapterMissing(StatusCode),
+    #[error("page redirected to {0}, probably to log in. Pass the cookies of a logged in session with `--cookie`.")]
+    Redirected(Url),
+    #[error("blocked by a challenge page (e.g. Cloudflare's) instead of the requested page. Wait a while, slow down with `--time-limit`, or pass the cookies of a browser that passed the challenge with `--cookie` and the same `--user-agent`.")]
+    Blocked,
+    /// `error` happened to the chapter with index `index` at `url`. See [`Error::in_chapter`].
+    #[error("chapter {} ({url}): {error}", .index + 1)]
+    Chapter {
+        index: usize,
+        url: Url,
+        error: Box<Error>,
+    },
+}
+impl Error {
+    /// The error with the index and url of the chapter it happened to, unless it already has them.
+    pub fn in_chapter(self, index: usize, url: &Url) -> Self {
+        match self {
+            Error::Chapter { .. } => self,
+            error => Error::Chapter {
+                index,
+                url: url.clone(),
+                error: Box::new(error),
+            },
+        }
+    }
+    /// The error without the chapter it happened to.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::Chapter { error, .. } => error.inner(),
+            error => error,
+        }
+    }
+    /// If the error is likely to go away when retried: connection errors, timeouts, and server errors.
+    pub fn is_transient(&self) -> bool {
+        match self.inner() {
+            Error::Request(e) => {
+                e.is_connect()
+                    || e.is_timeout()
+                    || e.is_request()
+                    || e.is_body()
+                    || e.status().is_some_and(|x| x.is_server_error())
+            }
+            Error::Timeout(_) | Error::RateLimited { .. } => true,
+            _ => false,
+        }
+    }
+    /// If the chapter no longer exists (404 or 410) so it should be skipped instead of failing the download.
+    pub fn is_missing(&self) -> bool {
+        matches!(self.inner(), Error::ChapterMissing(_))
+    }
+}
+
+/// Wrapper over [`Url`] that compares urls as equal if they represent the same fiction regardless of url content (e.g. with same uuid but different title as same).
+///
+/// Only the path is compared so the scheme, query (e.g. tracking parameters), fragment, and a trailing slash don't matter.
+#[derive(Clone, Debug)]
+pub struct ChapterUrl(pub Url);
+impl PartialEq for ChapterUrl {
+    fn eq(&self, other: &Self) -> bool {
+        let (Some(p1), Some(p2)) = (self.segments(), other.segments()) else {
+            return false;
+        };
+        p1.clone().count() == p2.clone().count()
+            && core::iter::zip(p1, p2)
+                .enumerate()
+                .all(|(i, (p1, p2))| i == 2 || p1 == p2)
+    }
+}
+impl Eq for ChapterUrl {}
+impl Hash for ChapterUrl {
+    /// Hashes the path segments compared by `eq` so equal urls hash the same.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (i, segment) in self.segments().into_iter().flatten().enumerate() {
+            if i != 2 {
+                segment.hash(state);
+            }
+        }
+    }
+}
+impl ChapterUrl {
+    /// Path segments without the empty segment after a trailing slash.
+    fn segments(&self) -> Option<impl Iterator<Item = &str> + Clone> {
+        let path = self.0.path();
+        Some(
+            path.strip_suffix('/')
+                .unwrap_or(path)
+                .strip_prefix('/')?
+                .split('/'),
+        )
+    }
+    /// Id of the chapter's heading in html outputs, which the table of contents links to.
+    ///
+    /// Uses the chapter id of the url so it doesn't change when the title does.
+    pub fn anchor(&self) -> String {
+        let id = match self.0.path_segments().and_then(|mut x| x.nth(4)) {
+            Some(id) => id.to_owned(),
+            None => self.0.path().trim_matches('/').replace('/', "-"),
+        };
+        format!("chapter-{id}")
+    }
+    /// Path segments compared by `eq` joined with `/`, which stay the same when the fiction is renamed.
+    pub fn key(&self) -> String {
+        self.segments()
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .filter(|(i, _)| *i != 2)
+            .map(|(_, x)| x)
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+impl From<Url> for ChapterUrl {
+    fn from(value: Url) -> Self {
+        Self(value)
+    }
+}
+impl From<ChapterUrl> for Url {
+    fn from(value: ChapterUrl) -> Self {
+        value.0
+    }
+}
+
+/// Options for downloading fictions.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Concurrent connections limit. `0` indicates no limit, which is capped at [`NO_LIMIT_CAP`].
+    pub connections: usize,
+    /// Minimum time per request.
+    pub time_limit: Duration,
+    /// Most random extra time waited before each request.
+    pub jitter: Duration,
+    /// Most requests made at once before waiting for `time_limit`.
+    pub burst: NonZeroUsize,
+    /// Limit for downloading and processing each chapter.
+    pub timeout_per_chapter: Option<Duration>,
+    /// Confirm being old enough to view age restricted fictions.
+    pub confirm_age: bool,
+    /// Replace chapter images with `data:` uris so they don't depend on their host.
+    pub embed_images: bool,
+    /// With `embed_images`, save images to this directory instead and reference them by their path relative to its
+    /// parent, where the output should be. See [`ImageCache::with_dir`].
+    pub image_dir: Option<PathBuf>,
+    /// Times to retry a chapter that failed with a transient error.
+    pub retries: usize,
+    /// Log a line as each chapter starts downloading.
+    pub print_progress: bool,
+    /// What is done with chapter content after it is downloaded.
+    pub processing: Processing,
+    /// Cache the raw pages of chapters are read from and saved to.
+    pub cache: Option<ResponseCache>,
+    /// Most bytes per second read from chapter pages, shared by all connections.
+    pub max_bandwidth: Option<NonZeroUsize>,
+}
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            connections: 4,
+            time_limit: Duration::from_millis(1500),
+            jitter: Duration::from_millis(300),
+            burst: NonZeroUsize::MIN,
+            timeout_per_chapter: None,
+            confirm_age: false,
+            embed_images: false,
+            image_dir: None,
+            retries: 3,
+            print_progress: true,
+            processing: Processing::default(),
+            cache: None,
+            max_bandwidth: None,
+        }
+    }
+}
+
+/// What is done with the content of a chapter after it is downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processing {
+    /// Whitespace of chapter content.
+    pub whitespace: Whitespace,
+    /// What is done with author notes.
+    pub author_notes: AuthorNotes,
+    /// Remove scripts, frames, and event handlers from chapter content.
+    pub sanitize: bool,
+    /// What is done with the styles of chapter content.
+    pub styles: Styles,
+}
+impl Default for Processing {
+    fn default() -> Self {
+        Self {
+            whitespace: Whitespace::Keep,
+            author_notes: AuthorNotes::Keep,
+            sanitize: true,
+            styles: Styles::Keep,
+        }
+    }
+}
+impl Processing {
+    /// Process the chapter content of the element `body` of `html`, whose author notes are selected by `selectors`.
+    fn apply(self, selectors: &Selectors, html: &mut Html, body: NodeId) {
+        if let Some(selector) = &selectors.author_notes {
+            self.author_notes.apply(html, selector, body);
+        }
+        if self.sanitize {
+            sanitize::sanitize(html, body);
+        }
+        self.styles.apply(html, body);
+        self.whitespace.apply(html, body);
+    }
+}
+
+/// Downloaded fiction.
+///
+/// Its chapters are measured by their plain text, as exported by [`text::html_to_text`].
+///
+/// ```
+/// # use royalroad_dl::{Chapter, Fiction};
+/// # use url::Url;
+/// let chapter = |index: usize, body: &str| Chapter {
+///     index,
+///     title: format!("Chapter {}", index + 1),
+///     url: Url::parse(&format!("https://www.royalroad.com/fiction/1/t/chapter/{index}/c")).unwrap(),
+///     body_html: body.to_owned(),
+///     published: None,
+///     validators: Default::default(),
+/// };
+/// let fiction = Fiction {
+///     title: "The Title".to_owned(),
+///     url: Url::parse("https://www.royalroad.com/fiction/1/t").unwrap(),
+///     metadata: Default::default(),
+///     chapters: vec![
+///         chapter(0, "<p>One <b>two</b>,</p>"),
+///         chapter(1, "<p>Three</p><p>four five</p>"),
+///         chapter(2, "<p>Six seven</p>"),
+///     ],
+/// };
+/// assert_eq!(fiction.chapter_count(), 3);
+/// assert_eq!(fiction.word_count(), 7);
+/// assert_eq!(fiction.total_chars(), 28);
+/// assert_eq!(fiction.longest_chapter().unwrap().title, "Chapter 2");
+/// // The first of the chapters with the fewest words.
+/// assert_eq!(fiction.shortest_chapter().unwrap().title, "Chapter 1");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fiction {
+    /// Title text of the main page.
+    pub title: String,
+    pub url: Url,
+    pub metadata: FictionMetadata,
+    pub chapters: Vec<Chapter>,
+}
+impl Fiction {
+    /// Number of chapters downloaded.
+    pub fn chapter_count(&self) -> usize {
+        self.chapters.len()
+    }
+    /// Number of words in all chapters. See [`Chapter::word_count`].
+    pub fn word_count(&self) -> usize {
+        self.chapters.iter().map(Chapter::word_count).sum()
+    }
+    /// Number of characters in all chapters, not counting whitespace. See [`Chapter::text_len`].
+    pub fn total_chars(&self) -> usize {
+        self.chapters.iter().map(Chapter::text_len).sum()
+    }
+    /// Chapter with the most words, the first if several have as many. `None` without chapters.
+    pub fn longest_chapter(&self) -> Option<&Chapter> {
+        // `max_by_key` returns the last of equal chapters.
+        self.chapters.iter().rev().max_by_key(|x| x.word_count())
+    }
+    /// Chapter with the fewest words, the first if several have as few. `None` without chapters.
+    pub fn shortest_chapter(&self) -> Option<&Chapter> {
+        self.chapters.iter().min_by_key(|x| x.word_count())
+    }
+}
+
+/// Details about a fiction from its main page.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct FictionMetadata {
+    pub author: Option<String>,
+    /// Description with a line per paragraph.
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Completion status (e.g. `Ongoing`, `Completed`).
+    pub status: Option<String>,
+}
+
+/// Downloaded chapter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    /// Index of the chapter in the table of contents.
+    pub index: usize,
+    /// Title text of the chapter without the fiction title.
+    pub title: String,
+    pub url: Url,
+    /// Html of the chapter content with warning paragraphs removed.
+    pub body_html: String,
+    /// When the chapter was published, if its page says.
+    pub published: Option<OffsetDateTime>,
+    /// Validators of the response the chapter was read from.
+    pub validators: Validators,
+}
+impl Chapter {
+    /// Html of the chapter as written to html outputs: a heading linking to the chapter followed by its content.
+    ///
+    /// The heading is how chapters are found again by [`parse_output`] and incremental downloads.
+    /// Its id is the [`ChapterUrl::anchor`] the table of contents links to.
+    /// The time it was published, if known, follows the heading.
+    pub fn to_html(&self) -> String {
+        format!(
+            r#"<h1><a class="chapter" href="{}" id="{}">{}</a></h1>{}{}"#,
+            self.url,
+            escape_attribute(&ChapterUrl(self.url.clone()).anchor()),
+            escape_text(&self.title),
+            self.published_html(),
+            self.body_html
+        )
+    }
+    /// Number of words in the plain text of the chapter's content.
+    pub fn word_count(&self) -> usize {
+        text::html_to_text(&self.body_html, 0)
+            .split_whitespace()
+            .count()
+    }
+    /// Number of characters in the plain text of the chapter's content, not counting whitespace.
+    pub fn text_len(&self) -> usize {
+        text::html_to_text(&self.body_html, 0)
+            .chars()
+            .filter(|x| !x.is_whitespace())
+            .count()
+    }
+    /// Html of when the chapter was published, or nothing if that isn't known.
+    pub fn published_html(&self) -> String {
+        let Some(published) = self.published else {
+            return String::new();
+        };
+        format!(
+            r#"<p class="published"><time datetime="{}">{}</time></p>"#,
+            published.format(&Rfc3339).unwrap_or_default(),
+            published.date()
+        )
+    }
+}
+
+/// Time given by a `<time>` element's `datetime` (RFC 3339) or `unixtime` attribute.
+fn parse_time(element: ElementRef) -> Option<OffsetDateTime> {
+    element
+        .attr("datetime")
+        .and_then(|x| OffsetDateTime::parse(x.trim(), &Rfc3339).ok())
+        .or_else(|| {
+            let secs = element.attr("unixtime")?.trim().parse().ok()?;
+            OffsetDateTime::from_unix_timestamp(secs).ok()
+        })
+}
+
+/// `ETag` and `Last-Modified` of a response used to ask if it changed with a conditional request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+impl Validators {
+    /// Validators given by response `headers`.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+/// Title of a chapter from the title of its page, without the ` - {main_title}` the site appends.
+///
+/// The title is kept whole if it doesn't end with that or `main_title` is empty.
+pub fn strip_main_title<'a>(page_title: &'a str, main_title: &str) -> &'a str {
+    if main_title.is_empty() {
+        return page_title;
+    }
+    page_title
+        .strip_suffix(main_title)
+        .and_then(|x| x.strip_suffix(" - "))
+        .unwrap_or(page_title)
+}
+
+/// Get the chapter with index `index` from `chapter_response`.
+///
+/// May use `chapter_progress_msg` when logging. The chapter's title is its heading ([`Selectors::chapter_heading`]),
+/// or the title of its page from [`Selectors::chapter_meta_title`] or [`Selectors::title`] with the fiction's
+/// `main_title` stripped by [`strip_main_title`]. If `main_title` is empty the page title is used whole.
+pub async fn chapter_response_to_content(
+    selectors: &Selectors,
+    index: usize,
+    chapter_progress_msg: &str,
+    chapter_response: reqwest::Response,
+    main_title: &str,
+    processing: Processing,
+) -> Result<Chapter, Error> {
+    let url = chapter_response.url().to_owned();
+    let validators = Validators::from_headers(chapter_response.headers());
+    let page = read_text(chapter_response).await?;
+
+    // Parsing is CPU bound so it runs on the blocking pool instead of holding up downloads on the runtime's thread.
+    let selectors = selectors.clone();
+    let chapter_progress_msg = chapter_progress_msg.to_owned();
+    let main_title = main_title.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut chapter_html = Html::parse_document(&page);
+        // A page without the expected content may be a challenge shown instead of it.
+        let layout_error = |error| match is_challenge_page(&page) {
+            true => Error::Blocked,
+            false => Error::Layout(error),
+        };
+
+        // Get chapter title, preferring its heading to the title of the page.
+        let text = |selector| {
+            chapter_html
+                .select(selector)
+                .map(|x| match x.attr("content") {
+                    Some(content) => content.trim().to_owned(),
+                    None => x.text().collect::<String>().trim().to_owned(),
+                })
+                .find(|x| !x.is_empty())
+        };
+        let heading = selectors.chapter_heading.as_ref().and_then(text);
+        let title = match heading {
+            Some(heading) => heading,
+            None => {
+                let page_title = selectors
+                    .chapter_meta_title
+                    .as_ref()
+                    .and_then(text)
+                    .or_else(|| text(&selectors.title))
+                    .ok_or_else(|| layout_error(PageLayoutError::ChapterTitle))?;
+                strip_main_title(&page_title, &main_title).trim().to_owned()
+            }
+        };
+
+        // Remove bad paragraphs.
+        let bad_paragraphs = selectors
+            .warning_paragraphs
+            .iter()
+            .flat_map(|x| chapter_html.select(x))
+            .map(|x| {
+                debug!(
+                    "Removing {}: {} ",
+                    chapter_progress_msg.cyan(),
+                    x.inner_html()
+                );
+                x.id()
+            })
+            .collect::<Vec<_>>();
+        for id in bad_paragraphs {
+            chapter_html.tree.get_mut(id).unwrap().detach();
+        }
+
+        let body = chapter_html
+            .select(&selectors.chapter_content)
+            .next()
+            .ok_or_else(|| layout_error(PageLayoutError::ChapterBody))?
+            .id();
+        processing.apply(&selectors, &mut chapter_html, body);
+        let body_html = chapter_html
+            .tree
+            .get(body)
+            .and_then(ElementRef::wrap)
+            .unwrap()
+            .html();
+        // Not every page says when it was published so a missing time isn't an error.
+        let published = chapter_html
+            .select(&selectors.chapter_time)
+            .find_map(parse_time);
+
+        Ok(Chapter {
+            index,
+            title,
+            url,
+            body_html,
+            published,
+            validators,
+        })
+    })
+    .await?
+}
+
+/// Collect the fiction urls listed on a follows/reading list page, following its pagination.
+///
+/// Fails with [`Error::Redirected`] if a page redirects elsewhere, as pages needing a logged in session do without one.
+pub async fn follows_fictions(
+    client: &reqwest::Client,
+    selectors: &Selectors,
+    limiter: &Limiter,
+    url: &Url,
+) -> Result<Vec<Url>, Error> {
+    let mut fictions = Vec::new();
+    let mut visited = Vec::new();
+    let mut next_page = Some(url.clone());
+    while let Some(page_url) = next_page {
+        limiter.acquire_one().await;
+        info!("Reading follows page {}", page_url);
+        let response = check_challenge(client.get(page_url.clone()).send().await?)
+            .await?
+            .error_for_status()?;
+        // Pages of a list that needs logging in redirect elsewhere (e.g. the login page) when logged out.
+        if response.url().path() != page_url.path() {
+            return Err(Error::Redirected(response.url().clone()));
+        }
+        let page_html = Html::parse_document(&read_text(response).await?);
+
+        for fiction in page_html
+            .select(&selectors.follows_fictions)
+            .filter_map(|x| x.attr("href"))
+            .filter_map(|x| page_url.join(x).ok())
+        {
+            if !fictions.contains(&fiction) {
+                fictions.push(fiction);
+            }
+        }
+
+        // Continue to the next page of the list unless it was already seen.
+        next_page = page_html
+            .select(&selectors.next_page)
+            .filter_map(|x| x.attr("href"))
+            .filter_map(|x| page_url.join(x).ok())
+            .find(|x| *x != page_url && !visited.contains(x));
+        visited.push(page_url);
+    }
+    if fictions.is_empty() {
+        return Err(PageLayoutError::FollowsLinks.into());
+    }
+    Ok(fictions)
+}
+
+/// Download the chapter with index `index` at `url`.
+///
+/// Fails with [`Error::Timeout`] if this takes longer than `timeout` and [`Error::ChapterMissing`] if the chapter was removed,
+/// wrapped in [`Error::Chapter`].
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_chapter(
+    client: &reqwest::Client,
+    selectors: &Selectors,
+    index: usize,
+    url: Url,
+    chapter_progress_msg: &str,
+    main_title: &str,
+    processing: Processing,
+    cache: Option<&ResponseCache>,
+    bandwidth: Option<&Bandwidth>,
+    timeout: Option<Duration>,
+) -> Result<Chapter, Error> {
+    let chapter_url = url.clone();
+    let fetch = async {
+        let cached = match cache {
+            Some(cache) => cache.get(&url).await,
+            None => None,
+        };
+        if let Some(response) = cached {
+            return chapter_response_to_content(
+                selectors,
+                index,
+                chapter_progress_msg,
+                response,
+                main_title,
+                processing,
+            )
+            .await;
+        }
+        let response = chapter_status(check_challenge(client.get(url).send().await?).await?)?;
+        let response = match bandwidth {
+            Some(bandwidth) => bandwidth.read(response).await?,
+            None => response,
+        };
+        let response = match cache {
+            Some(cache) => {
+                let url = response.url().clone();
+                let headers = response.headers().clone();
+                let page = read_text(response).await?;
+                // Challenge pages aren't cached so the chapter is downloaded again instead of failing on every run.
+                if is_challenge_page(&page) {
+                    return Err(Error::Blocked);
+                }
+                cache.put_page(url, headers, page).await
+            }
+            None => response,
+        };
+        chapter_response_to_content(
+            selectors,
+            index,
+            chapter_progress_msg,
+            response,
+            main_title,
+            processing,
+        )
+        .await
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fetch)
+            .await
+            .unwrap_or(Err(Error::Timeout(timeout))),
+        None => fetch.await,
+    }
+    .map_err(|e| e.in_chapter(index, &chapter_url))
+}
+
+/// Get the chapter at `url` unless the server reports it unchanged since it was downloaded with `validators`.
+///
+/// Servers may ignore the conditional request so a returned chapter isn't necessarily different.
+/// Fails like [`fetch_chapter`] when rate limited or the chapter was removed. Errors are wrapped in [`Error::Chapter`].
+#[allow(clippy::too_many_arguments)]
+pub async fn refresh_chapter(
+    client: &reqwest::Client,
+    selectors: &Selectors,
+    index: usize,
+    url: Url,
+    chapter_progress_msg: &str,
+    main_title: &str,
+    processing: Processing,
+    validators: &Validators,
+) -> Result<Option<Chapter>, Error> {
+    let chapter_url = url.clone();
+    let mut request = client.get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let refresh = async {
+        let response = chapter_status(check_challenge(request.send().await?).await?)?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        chapter_response_to_content(
+            selectors,
+            index,
+            chapter_progress_msg,
+            response,
+            main_title,
+            processing,
+        )
+        .await
+        .map(Some)
+    };
+    refresh.await.map_err(|e| e.in_chapter(index, &chapter_url))
+}
+
+/// Fail with [`Error::RateLimited`] if the server asks to slow down (429 or 503), [`Error::ChapterMissing`] if the
+/// chapter was removed (404 or 410), and the error of other failed statuses.
+fn chapter_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            Err(Error::RateLimited {
+                retry_after: retry_after(response.headers()),
+            })
+        }
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(Error::ChapterMissing(response.status())),
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
+/// Text found in challenge pages shown instead of the requested page, but not in the scripts added to other pages.
+const CHALLENGE_MARKERS: &[&str] = &[
+    "<title>Just a moment...</title>",
+    "window._cf_chl_opt",
+    "Attention Required! | Cloudflare",
+    "Checking your browser before accessing",
+];
+
+/// If `page` is a challenge page (e.g. Cloudflare's "Just a moment...") shown instead of the requested page.
+fn is_challenge_page(page: &str) -> bool {
+    CHALLENGE_MARKERS.iter().any(|x| page.contains(x))
+}
+
+/// Fail with [`Error::Blocked`] if `response` is a challenge instead of the requested page.
+///
+/// Challenges are recognized by Cloudflare's `cf-mitigated` header or the content of forbidden (403) and unavailable (503)
+/// responses, which are read to check and returned in a new response otherwise.
+async fn check_challenge(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    if response
+        .headers()
+        .get("cf-mitigated")
+        .is_some_and(|x| x == "challenge")
+    {
+        return Err(Error::Blocked);
+    }
+    if !matches!(
+        response.status(),
+        StatusCode::FORBIDDEN | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let page = read_text(response).await?;
+    if is_challenge_page(&page) {
+        return Err(Error::Blocked);
+    }
+    Ok(body_response(url, status, headers, page))
+}
+
+/// Wait requested by a `Retry-After` header given as seconds or an http date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Rate limiter of requests which waits a random extra time up to its jitter after each request is allowed.
+///
+/// Requests at perfectly regular intervals are easy to tell apart from a person reading.
+#[derive(Debug)]
+pub struct Limiter {
+    limiter: RateLimiter,
+    burst: usize,
+    jitter: Duration,
+    /// When the last request was allowed without a burst.
+    ///
+    /// The bucket refills on a fixed schedule so after a pause it could allow a request right before the next refill.
+    last: Mutex<Option<tokio::time::Instant>>,
+}
+impl Limiter {
+    /// Allow a request every `interval` plus up to `jitter`.
+    pub fn new(interval: Duration, jitter: Duration) -> Self {
+        Self {
+            limiter: RateLimiter::builder()
+                .initial(1)
+                .max(1)
+                .interval(interval)
+                .build(),
+            burst: 1,
+            jitter,
+            last: Mutex::new(None),
+        }
+    }
+    /// Allow up to `burst` requests at once, at the start and after not making requests for a while.
+    pub fn with_burst(self, burst: NonZeroUsize) -> Self {
+        Self {
+            limiter: RateLimiter::builder()
+                .initial(burst.get())
+                .max(burst.get())
+                .interval(self.interval())
+                .build(),
+            burst: burst.get(),
+            ..self
+        }
+    }
+    /// Same interval, burst, and jitter with its own requests.
+    pub fn like(&self) -> Self {
+        Self::new(self.interval(), self.jitter).with_burst(NonZeroUsize::new(self.burst).unwrap())
+    }
+    /// Wait until a request is allowed.
+    pub async fn acquire_one(&self) {
+        self.acquire(1).await;
+    }
+    /// Wait until a request taking `permits` is allowed.
+    pub async fn acquire(&self, permits: usize) {
+        self.limiter.acquire(permits).await;
+        if self.burst == 1 {
+            // Keep requests at least an interval apart.
+            let at = {
+                let mut last = self.last.lock().unwrap();
+                let now = tokio::time::Instant::now();
+                let at = last.map_or(now, |x| now.max(x + self.interval()));
+                *last = Some(at);
+                at
+            };
+            tokio::time::sleep_until(at).await;
+        }
+        if !self.jitter.is_zero() {
+            tokio::time::sleep(self.jitter.mul_f64(fastrand::f64())).await;
+        }
+    }
+    /// Time between requests, not including jitter.
+    pub fn interval(&self) -> Duration {
+        self.limiter.interval()
+    }
+    /// Most requests allowed at once.
+    pub fn burst(&self) -> usize {
+        self.burst
+    }
+    /// Most extra time waited after a request is allowed.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+}
+
+/// Limit of the bytes per second read from response bodies, shared by every response read with it.
+///
+/// Bodies are counted after they are decompressed so compressed responses download less than the limit.
+#[derive(Debug)]
+pub struct Bandwidth {
+    limiter: RateLimiter,
+    bytes_per_second: usize,
+}
+impl Bandwidth {
+    /// Allow reading `bytes_per_second`, refilled every tenth of a second so reads are smooth.
+    pub fn new(bytes_per_second: NonZeroUsize) -> Self {
+        let bytes_per_second = bytes_per_second.get();
+        Self {
+            limiter: RateLimiter::builder()
+                .initial(bytes_per_second)
+                .max(bytes_per_second)
+                .refill((bytes_per_second / 10).max(1))
+                .interval(Duration::from_millis(100))
+                .build(),
+            bytes_per_second,
+        }
+    }
+    /// Read the body of `response` no faster than allowed and return a response with it.
+    pub async fn read(
+        &self,
+        mut response: reqwest::Response,
+    ) -> reqwest::Result<reqwest::Response> {
+        let url = response.url().clone();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut body = Vec::new();
+        // The next chunk isn't read while waiting so the server is slowed down too.
+        while let Some(chunk) = response.chunk().await? {
+            for part in chunk.chunks(self.bytes_per_second) {
+                self.limiter.acquire(part.len()).await;
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body_response(url, status, headers, body))
+    }
+}
+
+/// Response from `url` with `status`, `headers`, and `body` already read from another response.
+fn body_response(
+    url: Url,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: impl Into<reqwest::Body>,
+) -> reqwest::Response {
+    let mut response = http::Response::builder()
+        .url(url)
+        .status(status)
+        .body(body.into())
+        .unwrap();
+    *response.headers_mut() = headers;
+    response.into()
+}
+
+/// Text of the body of `response`, decoded as it is read so only the text is held instead of both it and the body.
+///
+/// Decodes like [`reqwest::Response::text`]: with the `charset` of the `Content-Type` header, UTF-8 if it has none
+/// or it is unknown, or the encoding of a byte order mark. Malformed sequences are replaced.
+pub async fn read_text(mut response: reqwest::Response) -> reqwest::Result<String> {
+    let charset = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| {
+            x.split(';').skip(1).find_map(|x| {
+                let (name, value) = x.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| value.trim().trim_matches('"'))
+            })
+        })
+        .and_then(|x| encoding_rs::Encoding::for_label(x.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    let mut decoder = charset.new_decoder();
+    let mut text = String::new();
+    // Reserve for the whole page when its length is known (i.e. it isn't compressed) so it isn't copied as it grows.
+    if let Some(len) = response
+        .content_length()
+        .and_then(|x| usize::try_from(x).ok())
+    {
+        text.reserve(len);
+    }
+    let mut buffer = [0; 8192];
+    let mut decode = |text: &mut String, mut chunk: &[u8], last| loop {
+        let (result, read, written, _) = decoder.decode_to_utf8(chunk, &mut buffer, last);
+        text.push_str(std::str::from_utf8(&buffer[..written]).expect("decoded to UTF-8"));
+        chunk = &chunk[read..];
+        if result == encoding_rs::CoderResult::InputEmpty {
+            break;
+        }
+    };
+    while let Some(chunk) = response.chunk().await? {
+        decode(&mut text, &chunk, false);
+    }
+    decode(&mut text, &[], true);
+    Ok(text)
+}
+
+/// Widens the interval of a [`Limiter`] while the server reports too many requests.
+///
+/// Each level doubles the permits taken per request up to [`Throttle::MAX_LEVEL`].
+#[derive(Debug, Default)]
+pub struct Throttle {
+    level: AtomicU32,
+}
+impl Throttle {
+    /// Highest level, taking `2^MAX_LEVEL` permits per request.
+    pub const MAX_LEVEL: u32 = 4;
+
+    /// Wait for `limiter` to allow a request at the current level.
+    pub async fn acquire(&self, limiter: &Limiter) {
+        limiter
+            .acquire(1 << self.level.load(Ordering::Relaxed))
+            .await;
+    }
+    /// Halve the request rate.
+    pub fn slow_down(&self) {
+        let _ = self
+            .level
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some((x + 1).min(Self::MAX_LEVEL))
+            });
+    }
+    /// Double the request rate, back towards the limiter's own.
+    pub fn speed_up(&self) {
+        let _ = self
+            .level
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1));
+    }
+}
+
+/// Get the main page at `url` after waiting for `limiter`.
+///
+/// If the page is behind an age confirmation and `confirm_age` is set the confirmation cookie is added to `jar` before getting it again.
+pub async fn fetch_main_page(
+    client: &reqwest::Client,
+    jar: &Jar,
+    selectors: &Selectors,
+    limiter: &Limiter,
+    url: &Url,
+    confirm_age: bool,
+) -> Result<Html, Error> {
+    let get = || async {
+        limiter.acquire_one().await;
+        let response = check_challenge(client.get(url.clone()).send().await?).await?;
+        Ok::<_, Error>(Html::parse_document(&read_text(response).await?))
+    };
+    let main_html = get().await?;
+    if main_html.select(&selectors.age_gate).next().is_none() {
+        return Ok(main_html);
+    }
+    if !confirm_age {
+        return Err(Error::AgeGate);
+    }
+
+    warn!("{}", "Confirming age to continue".yellow());
+    jar.add_cookie_str(AGE_CONFIRMATION_COOKIE, url);
+    let main_html = get().await?;
+    if main_html.select(&selectors.age_gate).next().is_some() {
+        return Err(Error::AgeGate);
+    }
+    Ok(main_html)
+}
+
+/// Get the title text of the main page.
+pub fn parse_main_title(
+    selectors: &Selectors,
+    main_html: &Html,
+) -> Result<String, PageLayoutError> {
+    main_html
+        .select(&selectors.title)
+        .map(|x| x.text().collect::<String>())
+        .next()
+        .ok_or(PageLayoutError::MainTitle)
+}
+
+/// Chapter listed in a table of contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TocChapter {
+    pub url: ChapterUrl,
+    /// Text of the chapter's link.
+    pub title: String,
+    /// When the chapter was published, if its row says.
+    pub published: Option<OffsetDateTime>,
+}
+
+/// Page of a table of contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TocPage {
+    pub chapters: Vec<TocChapter>,
+    /// Url of the next page if the table of contents is paginated.
+    pub next_page: Option<Url>,
+}
+
+/// Get chapters and the link to the next page from a page of the table of contents with url `base`.
+///
+/// Rows that don't link to a chapter (e.g. announcements) are dropped.
+pub fn parse_toc_page(selectors: &Selectors, html: &Html, base: &Url) -> TocPage {
+    let chapters = html
+        .select(&selectors.chapter_links) // table of chapters
+        .map(|x| {
+            let url = x.attr("data-url").expect("data-url attribute in selector"); // url for table entry
+            let title = x
+                .select(&selectors.chapter_link_title)
+                .next()
+                .unwrap_or(x)
+                .text()
+                .collect::<String>();
+            let published = x.select(&selectors.chapter_time).find_map(parse_time);
+            (url, title.trim().to_owned(), published)
+        })
+        .filter(|(x, _, _)| {
+            let is_chapter = x.contains("/chapter/");
+            if !is_chapter {
+                warn!("{} {x}", "Skipping non-chapter row:".yellow());
+            }
+            is_chapter
+        })
+        .map(|(url, title, published)| TocChapter {
+            url: base.join(url).unwrap().into(), // absolute url from relative url
+            title,
+            published,
+        })
+        .collect();
+    let next_page = html
+        .select(&selectors.next_page)
+        .filter_map(|x| x.attr("href"))
+        .filter_map(|x| base.join(x).ok())
+        .find(|x| x != base);
+    TocPage {
+        chapters,
+        next_page,
+    }
+}
+
+/// Get chapter urls from the table of contents on the main page with url `base`.
+///
+/// Only reads the main page. See [`fetch_chapter_links`] for paginated tables of contents.
+/// Fails if there are no chapters since the layout of the page probably changed.
+pub fn parse_chapter_links(
+    selectors: &Selectors,
+    main_html: &Html,
+    base: &Url,
+) -> Result<Vec<ChapterUrl>, PageLayoutError> {
+    let chapters = parse_toc_page(selectors, main_html, base).chapters;
+    if chapters.is_empty() {
+        return Err(PageLayoutError::ChapterLinks);
+    }
+    Ok(chapters.into_iter().map(|x| x.url).collect())
+}
+
+/// Get chapters from every page of the table of contents starting at `first_page`, the parsed main page at `url`.
+///
+/// Later pages are downloaded after waiting for `limiter` and their chapters are appended in order.
+/// Fails if there are no chapters on any page since the layout of the page probably changed.
+pub async fn fetch_chapter_links(
+    client: &reqwest::Client,
+    selectors: &Selectors,
+    limiter: &Limiter,
+    url: &Url,
+    first_page: TocPage,
+) -> Result<Vec<TocChapter>, Error> {
+    let TocPage {
+        mut chapters,
+        mut next_page,
+    } = first_page;
+    let mut visited = vec![url.clone()];
+    // Continue to the next page unless it was already seen.
+    while let Some(page_url) = next_page.filter(|x| !visited.contains(x)) {
+        limiter.acquire_one().await;
+        info!("Reading table of contents page {}", page_url);
+        let page_html = Html::parse_document(
+            &client
+                .get(page_url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        );
+        let page = parse_toc_page(selectors, &page_html, &page_url);
+        chapters.extend(page.chapters);
+        next_page = page.next_page;
+        visited.push(page_url);
+    }
+    if chapters.is_empty() {
+        return Err(PageLayoutError::ChapterLinks.into());
+    }
+    Ok(chapters)
+}
+
+/// Get the url of the cover image of the main page with url `base`, if any.
+pub fn parse_cover_url(selectors: &Selectors, main_html: &Html, base: &Url) -> Option<Url> {
+    main_html
+        .select(&selectors.cover)
+        .filter_map(|x| x.attr("src").or_else(|| x.attr("content")))
+        .find_map(|x| base.join(x).ok())
+}
+
+/// Completion statuses shown as labels in the fiction header.
+const STATUSES: [&str; 6] = [
+    "ONGOING",
+    "COMPLETED",
+    "HIATUS",
+    "STUB",
+    "DROPPED",
+    "INACTIVE",
+];
+
+/// Get the author, description, tags, and status of the main page. Missing details are left empty.
+pub fn parse_metadata(selectors: &Selectors, main_html: &Html) -> FictionMetadata {
+    // Text of an element or the content of a meta tag.
+    let text = |x: ElementRef| match x.attr("content") {
+        Some(content) => content.trim().to_owned(),
+        None => x.text().collect::<String>().trim().to_owned(),
+    };
+    let description = main_html.select(&selectors.description).next().map(|x| {
+        if x.attr("content").is_some() {
+            return text(x);
+        }
+        // One line per paragraph instead of the whitespace of the page's markup.
+        let paragraphs = x
+            .select(&selectors.paragraphs)
+            .map(text)
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        if paragraphs.is_empty() {
+            text(x)
+        } else {
+            paragraphs.join("\n")
+        }
+    });
+    FictionMetadata {
+        author: main_html
+            .select(&selectors.author)
+            .map(text)
+            .find(|x| !x.is_empty()),
+        description: description.filter(|x| !x.is_empty()),
+        tags: main_html
+            .select(&selectors.tags)
+            .map(text)
+            .filter(|x| !x.is_empty())
+            .collect(),
+        status: main_html.select(&selectors.labels).map(text).find_map(|x| {
+            STATUSES
+                .iter()
+                .find(|status| status.eq_ignore_ascii_case(&x))
+                .map(|status| status[..1].to_owned() + &status[1..].to_lowercase())
+        }),
+    }
+}
+
+/// Downloaded image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    /// Media type (e.g. `image/png`).
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+impl Image {
+    /// Base64 `data:` uri with the image.
+    pub fn data_uri(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.content_type,
+            base64::engine::general_purpose::STANDARD.encode(&self.bytes)
+        )
+    }
+    /// Image of a base64 `data:` uri as made by [`Image::data_uri`].
+    pub fn from_data_uri(uri: &str) -> Option<Self> {
+        let (content_type, data) = uri.strip_prefix("data:")?.split_once(";base64,")?;
+        Some(Self {
+            content_type: content_type.to_owned(),
+            bytes: base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .ok()?,
+        })
+    }
+    /// File extension for the image's media type.
+    pub fn extension(&self) -> &str {
+        match self.content_type.as_str() {
+            "image/png" => "png",
+            "image/gif" => "gif",
+            "image/webp" => "webp",
+            "image/svg+xml" => "svg",
+            _ => "jpg",
+        }
+    }
+}
+
+/// Download the image at `url` after waiting for `limiter`.
+pub async fn fetch_image(
+    client: &reqwest::Client,
+    limiter: &Limiter,
+    url: &Url,
+) -> Result<Image, Error> {
+    limiter.acquire_one().await;
+    let response = client.get(url.clone()).send().await?.error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.split(';').next())
+        .map(|x| x.trim().to_owned())
+        .filter(|x| x.starts_with("image/"))
+        .unwrap_or_else(|| "image/jpeg".to_owned());
+    Ok(Image {
+        content_type,
+        bytes: response.bytes().await?.to_vec(),
+    })
+}
+
+/// Rate limiters of each host so the site and other hosts (e.g. of images) are throttled independently.
+pub struct HostLimiters {
+    /// Limiter the limiters of hosts without one are made [`Limiter::like`].
+    like: Limiter,
+    limiters: Mutex<HashMap<String, Arc<Limiter>>>,
+}
+impl HostLimiters {
+    /// Hosts get a limiter like `like` when they are first requested from.
+    pub fn new(like: Limiter) -> Self {
+        Self {
+            like,
+            limiters: Mutex::default(),
+        }
+    }
+    /// Use `limiter` for the host of `url` (e.g. the limiter chapters of the site are fetched with).
+    pub fn insert(&self, url: &Url, limiter: Arc<Limiter>) {
+        self.limiters
+            .lock()
+            .unwrap()
+            .insert(url.host_str().unwrap_or_default().to_owned(), limiter);
+    }
+    /// Limiter of the host of `url`.
+    pub fn get(&self, url: &Url) -> Arc<Limiter> {
+        self.limiters
+            .lock()
+            .unwrap()
+            .entry(url.host_str().unwrap_or_default().to_owned())
+            .or_insert_with(|| Arc::new(self.like.like()))
+            .clone()
+    }
+}
+
+/// 64 bit FNV-1a hash of `url`, which unlike [`std::hash::DefaultHasher`] stays the same between versions.
+fn url_hash(url: &Url) -> u64 {
+    url.as_str().bytes().fold(0xcbf2_9ce4_8422_2325, |hash, x| {
+        (hash ^ u64::from(x)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Images embedded in chapters.
+///
+/// Shared between chapters so each url is only downloaded once.
+pub struct ImageCache {
+    client: reqwest::Client,
+    limiters: HostLimiters,
+    /// Directory images are saved to instead of being embedded.
+    dir: Option<PathBuf>,
+    /// `src` replacing each image's or `None` if it couldn't be downloaded.
+    sources: Mutex<HashMap<Url, Arc<OnceCell<Option<String>>>>>,
+}
+impl ImageCache {
+    /// Download images with `client` after waiting for the limiter of their host.
+    pub fn new(client: reqwest::Client, limiters: HostLimiters) -> Self {
+        Self {
+            client,
+            limiters,
+            dir: None,
+            sources: Mutex::default(),
+        }
+    }
+    /// Save images to `dir` instead of embedding them, named by a hash of their url so later downloads reuse the name.
+    ///
+    /// They are referenced by their path relative to the parent of `dir` (e.g. `images/0123456789abcdef.png`).
+    pub fn with_dir(mut self, dir: PathBuf) -> Self {
+        self.dir = Some(dir);
+        self
+    }
+    /// Get the `src` to replace that of the image at `url` with, downloading it if this is the first time it is needed.
+    ///
+    /// Warns and returns `None` if it couldn't be downloaded or saved.
+    async fn source(&self, url: &Url, chapter_progress_msg: &str) -> Option<String> {
+        let cell = self
+            .sources
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| async {
+            let image = fetch_image(&self.client, &self.limiters.get(url), url)
+                .await
+                .map_err(|e| e.to_string());
+            let source = match (image, &self.dir) {
+                (Ok(image), None) => Ok(image.data_uri()),
+                (Ok(image), Some(dir)) => {
+                    let name = format!("{:016x}.{}", url_hash(url), image.extension());
+                    tokio::fs::write(dir.join(&name), &image.bytes)
+                        .await
+                        .map(|()| match dir.file_name() {
+                            Some(dir_name) => format!("{}/{name}", dir_name.to_string_lossy()),
+                            None => name,
+                        })
+                        .map_err(|e| e.to_string())
+                }
+                (Err(e), _) => Err(e),
+            };
+            match source {
+                Ok(source) => Some(source),
+                Err(e) => {
+                    warn!(
+                        "{} {} {url}: {e}",
+                        "Couldn't download image in".yellow(),
+                        chapter_progress_msg.cyan()
+                    );
+                    None
+                }
+            }
+        })
+        .await
+        .clone()
+    }
+    /// Replace the `src` of every image in `chapter` with a `data:` uri of it, or its path if saved to a directory (see
+    /// [`ImageCache::with_dir`]).
+    ///
+    /// Images that couldn't be downloaded keep their `src`.
+    pub async fn embed(
+        &self,
+        selectors: &Selectors,
+        chapter: &mut Chapter,
+        chapter_progress_msg: &str,
+    ) {
+        let mut sources = Vec::new();
+        for src in Html::parse_fragment(&chapter.body_html)
+            .select(&selectors.images)
+            .filter_map(|x| x.attr("src"))
+        {
+            if !src.starts_with("data:") && !sources.iter().any(|x| x == src) {
+                sources.push(src.to_owned());
+            }
+        }
+        let mut replacements = HashMap::new();
+        for src in sources {
+            let Ok(url) = chapter.url.join(&src) else {
+                continue;
+            };
+            if let Some(source) = self.source(&url, chapter_progress_msg).await {
+                replacements.insert(src, source);
+            }
+        }
+        if replacements.is_empty() {
+            return;
+        }
+
+        // Only the `src` of images is replaced, not the same url elsewhere (e.g. in links or text).
+        let mut html = Html::parse_fragment(&chapter.body_html);
+        let images = html
+            .select(&selectors.images)
+            .map(|x| x.id())
+            .collect::<Vec<_>>();
+        for id in images {
+            let mut node = html.tree.get_mut(id).unwrap();
+            let Node::Element(element) = node.value() else {
+                unreachable!("only elements are selected")
+            };
+            for (name, value) in element.attrs.iter_mut() {
+                if &*name.local == "src" {
+                    if let Some(source) = replacements.get(&**value) {
+                        *value = source.as_str().into();
+                    }
+                }
+            }
+        }
+        chapter.body_html = html.root_element().inner_html();
+    }
+}
+
+/// Log `action` on the chapter with progress `chapter_progress_msg` at info level, adding its `url` when debugging.
+pub fn log_progress(action: &str, chapter_progress_msg: &str, url: &Url) {
+    if tracing::enabled!(Level::DEBUG) {
+        debug!("{action} {}: {url}", chapter_progress_msg.cyan());
+    } else {
+        info!("{action} {}", chapter_progress_msg.cyan());
+    }
+}
+
+/// Spawns the download of a chapter given the attempt number (starting at `0`).
+pub type SpawnChapter<'a> = Box<dyn FnMut(usize) -> JoinHandle<Result<Chapter, Error>> + 'a>;
+/// Chapter downloads in progress as returned by [`download_chapters`].
+pub type ChapterDownloads<'a, I> =
+    RetryBufferedIter<I, SpawnChapter<'a>, Chapter, Error, fn(&Error) -> bool>;
+
+/// Spawn tasks downloading `chapters` in order with up to `opts.connections` running at once.
+///
+/// Each chapter is given with its index out of the `chapters_len` chapters in the table of contents for progress messages.
+/// Tasks start as soon as they are buffered. Chapters failing with a transient error (see [`Error::is_transient`]) are retried up to `opts.retries` times with exponential backoff, or after the wait requested by the server when rate limited.
+/// Rate limiting also slows down all later requests with a [`Throttle`] until they succeed again.
+/// Requests for chapters also take one of `opts.connections` permits of a [`Semaphore`] while they are made so no more are in
+/// flight at once however many tasks are buffered. `0` doesn't limit them.
+pub fn download_chapters<'a>(
+    client: &'a reqwest::Client,
+    selectors: &'a Arc<Selectors>,
+    limiter: &'a Arc<Limiter>,
+    chapters: Vec<(usize, ChapterUrl)>,
+    chapters_len: usize,
+    main_title: &str,
+    opts: &DownloadOptions,
+) -> ChapterDownloads<'a, impl Iterator<Item = SpawnChapter<'a>> + 'a> {
+    let main_title: Arc<str> = main_title.into();
+    let timeout = opts.timeout_per_chapter;
+    let processing = opts.processing;
+    let cache = opts.cache.clone();
+    let bandwidth = opts.max_bandwidth.map(|x| Arc::new(Bandwidth::new(x)));
+    let retries = opts.retries;
+    let print_progress = opts.print_progress;
+    let images = opts.embed_images.then(|| {
+        let limiters = HostLimiters::new(limiter.like());
+        // Images on the site share the limit of its chapters.
+        if let Some((_, url)) = chapters.first() {
+            limiters.insert(&url.0, limiter.clone());
+        }
+        let images = ImageCache::new(client.clone(), limiters);
+        Arc::new(match &opts.image_dir {
+            Some(dir) => images.with_dir(dir.clone()),
+            None => images,
+        })
+    });
+    let throttle = Arc::new(Throttle::default());
+    let connections = Arc::new(Semaphore::new(match opts.connections {
+        0 => Semaphore::MAX_PERMITS,
+        x => x,
+    }));
+    RetryBufferedIter::new(
+        chapters.into_iter().map(move |(i, url)| {
+            let main_title = main_title.clone();
+            let images = images.clone();
+            let cache = cache.clone();
+            let bandwidth = bandwidth.clone();
+            let throttle = throttle.clone();
+            let connections = connections.clone();
+            // Wait requested by the server for the next attempt.
+            let retry_after = Arc::new(Mutex::new(None));
+            Box::new(move |attempt: usize| {
+                let limiter = limiter.clone();
+                let throttle = throttle.clone();
+                let connections = connections.clone();
+                let retry_after = retry_after.clone();
+                let client = client.clone();
+                let selectors = selectors.clone();
+                let main_title = main_title.clone();
+                let images = images.clone();
+                let cache = cache.clone();
+                let bandwidth = bandwidth.clone();
+                let url = url.clone();
+                tokio::spawn(async move {
+                    let chapter_progress_msg = format!("{}/{}", i + 1, chapters_len);
+                    if attempt > 0 {
+                        let backoff = retry_after
+                            .lock()
+                            .unwrap()
+                            .take()
+                            .unwrap_or(RETRY_BACKOFF * 2u32.pow(attempt as u32 - 1));
+                        warn!(
+                            "{} {} in {backoff:?} (attempt {}/{}): {}",
+                            "Retrying".yellow(),
+                            chapter_progress_msg.cyan(),
+                            attempt + 1,
+                            retries + 1,
+                            url.0
+                        );
+                        tokio::time::sleep(backoff).await;
+                    }
+                    // Cached pages aren't requested so they aren't rate limited.
+                    let cached = cache.as_ref().is_some_and(|x| x.contains(&url.0));
+                    let permit = match cached {
+                        true => None,
+                        // The connection is taken first so requests waiting for one don't use up the rate limit
+                        // and all start at once when connections are released.
+                        false => {
+                            let permit = connections
+                                .acquire()
+                                .await
+                                .expect("semaphore is never closed");
+                            throttle.acquire(&limiter).await;
+                            Some(permit)
+                        }
+                    };
+                    if print_progress {
+                        log_progress(
+                            if cached {
+                                "Reading cached"
+                            } else {
+                                "Downloading"
+                            },
+                            &chapter_progress_msg,
+                            &url.0,
+                        );
+                    }
+                    let chapter = fetch_chapter(
+                        &client,
+                        &selectors,
+                        i,
+                        url.0,
+                        &chapter_progress_msg,
+                        &main_title,
+                        processing,
+                        cache.as_ref(),
+                        bandwidth.as_deref(),
+                        timeout,
+                    )
+                    .await;
+                    if let Err(Error::RateLimited { retry_after: wait }) =
+                        chapter.as_ref().map_err(Error::inner)
+                    {
+                        throttle.slow_down();
+                        *retry_after.lock().unwrap() = *wait;
+                    }
+                    drop(permit);
+                    let mut chapter = chapter?;
+                    throttle.speed_up();
+                    if let Some(images) = images {
+                        images
+                            .embed(&selectors, &mut chapter, &chapter_progress_msg)
+                            .await;
+                    }
+                    Ok(chapter)
+                })
+            }) as SpawnChapter<'a>
+        }),
+        opts.connections,
+        retries,
+        Error::is_transient,
+    )
+}
+
+/// Details and table of contents of a fiction from its main page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FictionIndex {
+    /// Title text of the main page.
+    pub title: String,
+    pub metadata: FictionMetadata,
+    pub cover_url: Option<Url>,
+    /// Chapters from every page of the table of contents, in order.
+    pub toc: Vec<TocChapter>,
+}
+
+/// Get the details and table of contents of the fiction with main page at `url`.
+///
+/// This is the first step of [`download_fiction`], after which chapters are downloaded with [`download_chapters`].
+pub async fn fetch_fiction_index(
+    client: &reqwest::Client,
+    jar: &Jar,
+    selectors: &Selectors,
+    limiter: &Limiter,
+    url: &Url,
+    confirm_age: bool,
+) -> Result<FictionIndex, Error> {
+    // `Html` isn't `Send` so don't hold it across later awaits.
+    let (title, metadata, cover_url, first_page) = {
+        let main_html = fetch_main_page(client, jar, selectors, limiter, url, confirm_age).await?;
+        (
+            parse_main_title(selectors, &main_html)?,
+            parse_metadata(selectors, &main_html),
+            parse_cover_url(selectors, &main_html, url),
+            parse_toc_page(selectors, &main_html, url),
+        )
+    };
+    let toc = fetch_chapter_links(client, selectors, limiter, url, first_page).await?;
+    Ok(FictionIndex {
+        title,
+        metadata,
+        cover_url,
+        toc,
+    })
+}
+
+/// Download every chapter of the fiction with main page at `url`.
+///
+/// Chapters that were removed (see [`Error::is_missing`]) are skipped.
+/// Pages are scraped with the selectors of the [`site::Site`] of `url`, or Royal Road's if it isn't supported.
+///
+/// `client` should use `jar` as its cookie store (see [`reqwest::ClientBuilder::cookie_provider`]) so age confirmation works.
+///
+/// Every chapter is held in memory until the whole fiction is downloaded. The command line doesn't use this. It writes
+/// each chapter to its output as it arrives from [`download_chapters`] after [`fetch_fiction_index`], so memory stays
+/// bounded and an interrupted download keeps what it saved, and its output handling (incremental downloads, volumes,
+/// formats) isn't part of the library. Call those two steps the same way to process chapters as they arrive.
+pub async fn download_fiction(
+    client: &reqwest::Client,
+    jar: &Jar,
+    url: &Url,
+    opts: &DownloadOptions,
+) -> Result<Fiction, Error> {
+    let selectors =
+        Arc::new(site::Site::for_url(url).map_or_else(Selectors::default, |x| (x.selectors)()));
+    let limiter = Arc::new(Limiter::new(opts.time_limit, opts.jitter).with_burst(opts.burst));
+    let FictionIndex {
+        title,
+        metadata,
+        toc,
+        ..
+    } = fetch_fiction_index(client, jar, &selectors, &limiter, url, opts.confirm_age).await?;
+    let chapter_urls = toc.into_iter().map(|x| x.url).collect::<Vec<_>>();
+
+    let mut chapters = Vec::with_capacity(chapter_urls.len());
+    let mut chapter_responses = download_chapters(
+        client,
+        &selectors,
+        &limiter,
+        chapter_urls.iter().cloned().enumerate().collect(),
+        chapter_urls.len(),
+        &title,
+        opts,
+    );
+    for (i, url) in chapter_urls.iter().enumerate() {
+        let Some(chapter) = chapter_responses.next_result().await else {
+            break;
+        };
+        match chapter? {
+            Err(e) if e.is_missing() => warn!(
+                "{} {}: {}: {}",
+                "Skipping".yellow(),
+                format!("{}/{}", i + 1, chapter_urls.len()).cyan(),
+                url.0,
+                e.inner()
+            ),
+            chapter => chapters.push(chapter?),
+        }
+    }
+    Ok(Fiction {
+        title,
+        url: url.clone(),
+        metadata,
+        chapters,
+    })
+}
+
+/// Items buffered at once when no limit is given.
+///
+/// Buffering everything at once would start every task of a long iterator (e.g. thousands of chapters) and hold all their results in memory.
+pub const NO_LIMIT_CAP: usize = 64;
+
+/// `limit` with `0` (no limit) replaced by [`NO_LIMIT_CAP`].
+fn buffer_limit(limit: usize) -> usize {
+    if limit == 0 {
+        NO_LIMIT_CAP
+    } else {
+        limit
+    }
+}
 
 /// Buffer up to a set amount of the iterator. Useful for enabling parallelism with an iterator that spawns tasks/threads.
 pub struct BufferedIter<I: Iterator> {
     iter: I,
+    limit: usize,
     buffer: VecDeque<I::Item>,
 }
 
 impl<I: Iterator> BufferedIter<I> {
-    /// Take up to `limit` items to fill the intermediate buffer. `0` indicates no limit.
+    /// Take up to `limit` items to fill the intermediate buffer. `0` indicates no limit, which is capped at [`NO_LIMIT_CAP`].
     pub fn new(mut iter: I, limit: usize) -> Self {
-        let buffer = if limit == 0 {
-            VecDeque::from_iter(iter.by_ref())
-        } else {
-            VecDeque::from_iter(iter.by_ref().take(limit))
-        };
-
-        Self { iter, buffer }
+        let limit = buffer_limit(limit);
+        let buffer = VecDeque::from_iter(iter.by_ref().take(limit));
+        Self {
+            iter,
+            limit,
+            buffer,
+        }
     }
     /// Number of items currently buffered
     pub fn len(&self) -> usize {
         self.buffer.len()
     }
+    /// Most items buffered at once: the limit given to [`BufferedIter::new`] with `0` replaced by [`NO_LIMIT_CAP`].
+    ///
+    /// ```
+    /// # use royalroad_dl::{BufferedIter, NO_LIMIT_CAP};
+    /// assert_eq!(BufferedIter::new(0..10, 4).capacity(), 4);
+    /// assert_eq!(BufferedIter::new(0..10, 0).capacity(), NO_LIMIT_CAP);
+    /// ```
+    pub fn capacity(&self) -> usize {
+        self.limit
+    }
+    /// If no items are currently buffered
+    ///
+    /// ```
+    /// # use royalroad_dl::BufferedIter;
+    /// let mut iter = BufferedIter::new(0..1, 4);
+    /// assert!(!iter.is_empty());
+    /// iter.next();
+    /// assert!(iter.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+    /// If [`BufferedIter::capacity`] items are buffered. Not full once the iterator runs out.
+    ///
+    /// ```
+    /// # use royalroad_dl::BufferedIter;
+    /// let mut iter = BufferedIter::new(0..3, 2);
+    /// assert!(iter.is_full());
+    /// iter.next();
+    /// assert!(iter.is_full());
+    /// iter.next();
+    /// assert!(!iter.is_full());
+    /// ```
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() == self.limit
+    }
+    /// The rest of the iterator and the items taken from it which weren't yielded yet.
+    ///
+    /// ```
+    /// # use royalroad_dl::BufferedIter;
+    /// let mut iter = BufferedIter::new(0..5, 2);
+    /// iter.next();
+    /// let (rest, buffer) = iter.into_inner();
+    /// assert_eq!(buffer, [1, 2]);
+    /// assert!(rest.eq(3..5));
+    /// ```
+    pub fn into_inner(self) -> (I, VecDeque<I::Item>) {
+        (self.iter, self.buffer)
+    }
 }
 impl<I: Iterator> Iterator for BufferedIter<I> {
     type Item = I::Item;
@@ -42,5 +1896,1681 @@ impl<I: Iterator> Iterator for BufferedIter<I> {
         )
     }
 }
+/// Items from the back are taken from the iterator when yielded so the buffer keeps holding the next items from the
+/// front. Once the iterator runs out they come from the back of the buffer.
+impl<I: DoubleEndedIterator + ExactSizeIterator> DoubleEndedIterator for BufferedIter<I> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().or_else(|| self.buffer.pop_back())
+    }
+}
 impl<I: ExactSizeIterator> ExactSizeIterator for BufferedIter<I> {}
 impl<I: FusedIterator> FusedIterator for BufferedIter<I> {}
+
+/// Stream of futures polled up to a set amount at once, yielding their outputs in order.
+///
+/// Unlike [`BufferedIter`] of spawned tasks the futures only make progress while the stream is polled, so a consumer
+/// which stops polling stops taking futures from the underlying stream.
+pub struct BufferedStream<S: Stream>
+where
+    S::Item: Future,
+{
+    /// `None` once it ended.
+    stream: Option<S>,
+    limit: usize,
+    running: FuturesOrdered<S::Item>,
+}
+
+impl<S: Stream> BufferedStream<S>
+where
+    S::Item: Future,
+{
+    /// Poll up to `limit` futures at once. `0` indicates no limit.
+    ///
+    /// Outputs are held until the futures before them finish, so without a limit a slow future lets the whole rest of
+    /// the stream run and every output after it is held in memory. Pass a limit for long streams.
+    pub fn new(stream: S, limit: usize) -> Self {
+        Self {
+            stream: Some(stream),
+            limit,
+            running: FuturesOrdered::new(),
+        }
+    }
+    /// Number of futures currently buffered
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+    /// If no futures are currently buffered
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+}
+impl<S: Stream + Unpin> Stream for BufferedStream<S>
+where
+    S::Item: Future,
+{
+    type Item = <S::Item as Future>::Output;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while this.limit == 0 || this.running.len() < this.limit {
+            let Some(stream) = &mut this.stream else {
+                break;
+            };
+            match Pin::new(stream).poll_next(cx) {
+                Poll::Ready(Some(x)) => this.running.push_back(x),
+                Poll::Ready(None) => this.stream = None,
+                Poll::Pending => break,
+            }
+        }
+        match Pin::new(&mut this.running).poll_next(cx) {
+            Poll::Ready(None) if this.stream.is_some() => Poll::Pending,
+            res => res,
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let sh = self.stream.as_ref().map_or((0, Some(0)), Stream::size_hint);
+        (
+            sh.0 + self.running.len(),
+            sh.1.map(|x| x + self.running.len()),
+        )
+    }
+}
+
+/// Item of a [`TryBufferedIter`].
+pub trait TryItem {
+    /// If the item is known to have failed without waiting on it.
+    fn failed(&self) -> bool;
+}
+impl<T, E> TryItem for Result<T, E> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+/// Tasks only fail once they are awaited.
+impl<T> TryItem for JoinHandle<T> {
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+/// [`BufferedIter`] of fallible items which stops taking items from the iterator once one fails.
+///
+/// Iterating `Result`s yields the items before the first error, then the error, then ends without taking the rest.
+/// Tasks (`JoinHandle`s) are waited on with [`TryBufferedIter::next_result`] which ends after the first task that panics
+/// or returns an error. Tasks after it were already spawned when it failed so they are aborted, and no more are spawned.
+pub struct TryBufferedIter<I: Iterator> {
+    iter: I,
+    limit: usize,
+    buffer: VecDeque<I::Item>,
+    /// If an item failed so no more are taken.
+    failed: bool,
+}
+
+impl<I: Iterator> TryBufferedIter<I>
+where
+    I::Item: TryItem,
+{
+    /// Take up to `limit` items to fill the intermediate buffer. `0` indicates no limit, which is capped at [`NO_LIMIT_CAP`].
+    pub fn new(iter: I, limit: usize) -> Self {
+        let mut out = Self {
+            iter,
+            limit,
+            buffer: VecDeque::new(),
+            failed: false,
+        };
+        out.fill();
+        out
+    }
+    /// Take items until `limit` are buffered or one failed.
+    fn fill(&mut self) {
+        while !self.failed && self.buffer.len() < buffer_limit(self.limit) {
+            let Some(x) = self.iter.next() else {
+                break;
+            };
+            self.failed = x.failed();
+            self.buffer.push_back(x);
+        }
+    }
+}
+impl<I: Iterator> TryBufferedIter<I> {
+    /// Number of items currently buffered
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+    /// If no items are currently buffered
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+impl<I, T, E> Iterator for TryBufferedIter<I>
+where
+    I: Iterator<Item = Result<T, E>>,
+{
+    type Item = Result<T, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.buffer.pop_front();
+        self.fill();
+        res
+    }
+}
+impl<I, T, E> TryBufferedIter<I>
+where
+    I: Iterator<Item = JoinHandle<Result<T, E>>>,
+{
+    /// Wait for the next task in order. Once one fails the tasks after it are aborted and this returns `None`.
+    pub async fn next_result(&mut self) -> Option<Result<Result<T, E>, JoinError>> {
+        let res = self.buffer.pop_front()?.await;
+        if matches!(res, Ok(Ok(_))) {
+            self.fill();
+        } else {
+            self.failed = true;
+            for handle in self.buffer.drain(..) {
+                handle.abort();
+            }
+        }
+        Some(res)
+    }
+}
+
+/// Task of a [`RetryBufferedIter`].
+struct RetrySlot<F, T, E> {
+    spawn: F,
+    attempt: usize,
+    handle: JoinHandle<Result<T, E>>,
+    /// Final result once the task finished and won't be retried.
+    result: Option<Result<Result<T, E>, JoinError>>,
+}
+
+/// Buffered tasks that are spawned again when they fail with a retryable error.
+///
+/// The underlying iterator yields functions that spawn a task given the attempt number (starting at `0`).
+/// Up to the limit of tasks run at once. Results are yielded in order, but a finished task is replaced by the next one right away
+/// so fast tasks don't wait on a slow task before them. Their results are held until the tasks before them finish, so no
+/// more tasks are spawned while twice the limit are running or held.
+pub struct RetryBufferedIter<I, F, T, E, R>
+where
+    I: Iterator<Item = F>,
+    F: FnMut(usize) -> JoinHandle<Result<T, E>>,
+{
+    iter: I,
+    limit: usize,
+    /// Running and finished tasks in order.
+    slots: VecDeque<RetrySlot<F, T, E>>,
+    retries: usize,
+    is_retryable: R,
+}
+
+impl<I, F, T, E, R> RetryBufferedIter<I, F, T, E, R>
+where
+    I: Iterator<Item = F>,
+    F: FnMut(usize) -> JoinHandle<Result<T, E>>,
+    R: FnMut(&E) -> bool,
+{
+    /// Run up to `limit` tasks at once. `0` indicates no limit, which is capped at [`NO_LIMIT_CAP`].
+    ///
+    /// A task is retried at most `retries` times and only while `is_retryable` accepts its error.
+    pub fn new(iter: I, limit: usize, retries: usize, is_retryable: R) -> Self {
+        let mut out = Self {
+            iter,
+            limit,
+            slots: VecDeque::new(),
+            retries,
+            is_retryable,
+        };
+        out.fill();
+        out
+    }
+    /// Spawn tasks until `limit` are running or twice `limit` are running or held.
+    fn fill(&mut self) {
+        let limit = buffer_limit(self.limit);
+        let mut running = self.slots.iter().filter(|x| x.result.is_none()).count();
+        while running < limit && self.slots.len() < 2 * limit {
+            let Some(mut spawn) = self.iter.next() else {
+                break;
+            };
+            let handle = spawn(0);
+            self.slots.push_back(RetrySlot {
+                spawn,
+                attempt: 0,
+                handle,
+                result: None,
+            });
+            running += 1;
+        }
+    }
+    /// Number of tasks currently buffered, running or finished
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+    /// If no tasks are currently buffered
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+    /// Wait for the next task in order. Failed tasks are spawned again until they succeed, fail with a non-retryable error, or run out of retries.
+    ///
+    /// Later tasks which finish first are replaced while waiting.
+    pub async fn next_result(&mut self) -> Option<Result<Result<T, E>, JoinError>> {
+        loop {
+            if self.slots.front()?.result.is_some() {
+                let res = self.slots.pop_front()?.result;
+                self.fill();
+                return res;
+            }
+            // Wait for whichever running task finishes first.
+            let (i, res) = std::future::poll_fn(|cx| {
+                for (i, slot) in self.slots.iter_mut().enumerate() {
+                    if slot.result.is_some() {
+                        continue;
+                    }
+                    if let Poll::Ready(res) = Pin::new(&mut slot.handle).poll(cx) {
+                        return Poll::Ready((i, res));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            let slot = &mut self.slots[i];
+            match res {
+                Ok(Err(e)) if slot.attempt < self.retries && (self.is_retryable)(&e) => {
+                    slot.attempt += 1;
+                    slot.handle = (slot.spawn)(slot.attempt);
+                }
+                res => {
+                    slot.result = Some(res);
+                    self.fill();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::ResponseCache;
+    use crate::read_text;
+    use crate::retry_after;
+    use crate::styles::Styles;
+    use crate::url_hash;
+    use crate::Bandwidth;
+    use crate::Processing;
+    use crate::{
+        chapter_response_to_content, download_chapters, download_fiction, fetch_chapter,
+        fetch_chapter_links, fetch_main_page, follows_fictions, parse_chapter_links,
+        parse_cover_url, parse_main_title, parse_metadata, parse_toc_page, refresh_chapter,
+        selectors::Selectors, strip_main_title, BufferedIter, BufferedStream, Chapter, ChapterUrl,
+        DownloadOptions, Error, FictionMetadata, HostLimiters, Image, ImageCache, Limiter,
+        PageLayoutError, RetryBufferedIter, TocPage, TryBufferedIter, Validators, NO_LIMIT_CAP,
+    };
+    use reqwest::cookie::Jar;
+    use reqwest::header::RETRY_AFTER;
+    use scraper::Html;
+    use std::collections::VecDeque;
+    use std::hash::{BuildHasher, RandomState};
+    use std::num::NonZeroUsize;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::{Duration, SystemTime};
+    use time::OffsetDateTime;
+    use url::Url;
+
+    /// Task `i` fails transiently `i` times before succeeding.
+    fn flaky_tasks(
+        n: usize,
+        attempts: Arc<AtomicUsize>,
+    ) -> impl Iterator<Item = impl FnMut(usize) -> tokio::task::JoinHandle<Result<usize, &'static str>>>
+    {
+        (0..n).map(move |i| {
+            let attempts = attempts.clone();
+            move |attempt| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if attempt < i {
+                        Err("transient")
+                    } else {
+                        Ok(i)
+                    }
+                })
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn retry_buffered_iter_retries_transient_failures() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut iter = RetryBufferedIter::new(flaky_tasks(4, attempts.clone()), 2, 3, |_| true);
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap());
+        }
+        assert_eq!(results, vec![Ok(0), Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1 + 2 + 3 + 4);
+    }
+
+    #[tokio::test]
+    async fn retry_buffered_iter_surfaces_failures() {
+        // Out of retries.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut iter = RetryBufferedIter::new(flaky_tasks(3, attempts.clone()), 0, 1, |_| true);
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap());
+        }
+        assert_eq!(results, vec![Ok(0), Ok(1), Err("transient")]);
+
+        // Not retryable.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut iter = RetryBufferedIter::new(flaky_tasks(2, attempts.clone()), 0, 3, |_| false);
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap());
+        }
+        assert_eq!(results, vec![Ok(0), Err("transient")]);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_buffered_iter_replaces_finished_tasks_in_order() {
+        // The first task is slow so the rest finish before it.
+        let started = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..5usize).map(|i| {
+            let started = started.clone();
+            move |_| {
+                let started = started.clone();
+                tokio::spawn(async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    let delay = if i == 0 { 200 } else { 10 };
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    // Tasks after the slow one don't wait for it before starting.
+                    Ok::<_, ()>((i, started.load(Ordering::SeqCst)))
+                })
+            }
+        });
+        let mut iter = RetryBufferedIter::new(tasks, 2, 0, |_| false);
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap().unwrap());
+        }
+        assert_eq!(
+            results.iter().map(|x| x.0).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        // Up to twice the limit are buffered while the first is slow.
+        assert_eq!(results[0].1, 4);
+    }
+
+    #[tokio::test]
+    async fn retry_buffered_iter_holds_bounded_results() {
+        // The first task is stalled until released so the results after it are held.
+        let release = Arc::new(tokio::sync::Notify::new());
+        let started = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..10usize).map(|i| {
+            let release = release.clone();
+            let started = started.clone();
+            move |_| {
+                let release = release.clone();
+                started.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if i == 0 {
+                        release.notified().await;
+                    }
+                    Ok::<_, ()>(i)
+                })
+            }
+        });
+        let mut iter = RetryBufferedIter::new(tasks, 2, 0, |_| false);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), iter.next_result())
+                .await
+                .is_err()
+        );
+        assert_eq!(started.load(Ordering::SeqCst), 4);
+        assert_eq!(iter.len(), 4);
+
+        release.notify_one();
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap().unwrap());
+        }
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn buffered_stream_polls_futures_at_once_in_order() {
+        use futures::StreamExt;
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let futures = futures::stream::iter((0..5usize).map(|i| {
+            let started = started.clone();
+            async move {
+                started.fetch_add(1, Ordering::SeqCst);
+                let delay = if i == 0 { 200 } else { 10 };
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                (i, started.load(Ordering::SeqCst))
+            }
+        }));
+        let results = BufferedStream::new(futures, 2).collect::<Vec<_>>().await;
+        assert_eq!(
+            results.iter().map(|x| x.0).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        // Only the limit started while the first was slow since finished outputs wait for it.
+        assert_eq!(results[0].1, 2);
+
+        let mut stream = BufferedStream::new(
+            futures::stream::iter((0..10_000).map(std::future::ready)),
+            0,
+        );
+        // Without a limit the whole stream is taken at once.
+        assert_eq!(stream.next().await, Some(0));
+        assert_eq!(stream.len(), 9_999);
+        assert_eq!(stream.count().await, 9_999);
+    }
+
+    #[test]
+    fn try_buffered_iter_stops_at_errors() {
+        let taken = AtomicUsize::new(0);
+        let items = (0..10).map(|i| {
+            taken.fetch_add(1, Ordering::SeqCst);
+            if i == 3 {
+                Err(i)
+            } else {
+                Ok(i)
+            }
+        });
+        let iter = TryBufferedIter::new(items, 2);
+        assert_eq!(iter.collect::<Vec<_>>(), [Ok(0), Ok(1), Ok(2), Err(3)]);
+        assert_eq!(taken.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn try_buffered_iter_aborts_tasks_after_failures() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..10).map(|i| {
+            spawned.fetch_add(1, Ordering::SeqCst);
+            let finished = finished.clone();
+            tokio::spawn(async move {
+                // The task after the failure is still running when it fails.
+                let delay = if i == 2 { 200 } else { 10 };
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+                if i == 1 {
+                    Err(i)
+                } else {
+                    Ok(i)
+                }
+            })
+        });
+        let mut iter = TryBufferedIter::new(tasks, 2);
+        let mut results = Vec::new();
+        while let Some(res) = iter.next_result().await {
+            results.push(res.unwrap());
+        }
+        assert_eq!(results, [Ok(0), Err(1)]);
+        assert!(iter.is_empty());
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn limiter_burst() {
+        let limiter = Limiter::new(Duration::from_secs(3600), Duration::ZERO)
+            .with_burst(NonZeroUsize::new(3).unwrap());
+        let acquire = || tokio::time::timeout(Duration::from_millis(100), limiter.acquire_one());
+        for _ in 0..3 {
+            assert!(acquire().await.is_ok());
+        }
+        assert!(acquire().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limiter_spacing_after_pause() {
+        let interval = Duration::from_millis(40);
+        let start = tokio::time::Instant::now();
+        let limiter = Limiter::new(interval, Duration::ZERO);
+        limiter.acquire_one().await;
+        limiter.acquire_one().await;
+        // Requests waiting together after a pause, right before the bucket refills on its schedule.
+        tokio::time::sleep_until(start + interval * 5 - interval / 4).await;
+        let acquire = || async {
+            limiter.acquire_one().await;
+            tokio::time::Instant::now()
+        };
+        let (first, second) = tokio::join!(acquire(), acquire());
+        let gap = first.max(second) - first.min(second);
+        assert!(gap >= interval * 3 / 4, "{gap:?} apart");
+    }
+
+    #[test]
+    fn host_limiters() -> Result<(), url::ParseError> {
+        let site = Arc::new(Limiter::new(Duration::from_secs(1), Duration::ZERO));
+        let limiters = HostLimiters::new(
+            Limiter::new(Duration::from_secs(2), Duration::from_millis(10))
+                .with_burst(NonZeroUsize::new(3).unwrap()),
+        );
+        limiters.insert(
+            &Url::parse("https://www.royalroad.com/fiction/1")?,
+            site.clone(),
+        );
+        let get = |url: &str| Ok::<_, url::ParseError>(limiters.get(&Url::parse(url)?));
+        assert!(Arc::ptr_eq(
+            &get("https://www.royalroad.com/image.png")?,
+            &site
+        ));
+        let cdn = get("https://cdn.example.com/1.png")?;
+        assert!(!Arc::ptr_eq(&cdn, &site));
+        assert_eq!(cdn.interval(), Duration::from_secs(2));
+        assert_eq!(cdn.jitter(), Duration::from_millis(10));
+        assert_eq!(cdn.burst(), 3);
+        assert!(Arc::ptr_eq(&get("https://cdn.example.com/2.png")?, &cdn));
+        assert!(!Arc::ptr_eq(
+            &get("https://images.example.com/1.png")?,
+            &cdn
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_iter_double_ended() {
+        // Every combination of taking from the front or back for small iterators and limits.
+        for len in 0..6 {
+            for limit in 1..4 {
+                for sides in 0..1u32 << len {
+                    let mut iter = BufferedIter::new(0..len, limit);
+                    let mut expected = (0..len).collect::<VecDeque<_>>();
+                    for step in 0..len {
+                        let (x, y) = if sides >> step & 1 == 0 {
+                            (iter.next(), expected.pop_front())
+                        } else {
+                            (iter.next_back(), expected.pop_back())
+                        };
+                        assert_eq!(x, y, "{len} {limit} {sides:b}");
+                        // The buffer holds the next items from the front.
+                        assert_eq!(iter.len(), expected.len().min(limit));
+                        assert_eq!(iter.buffer.front(), expected.front());
+                    }
+                    assert_eq!(iter.next(), None);
+                    assert_eq!(iter.next_back(), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buffered_iter_no_limit_is_capped() {
+        let mut iter = BufferedIter::new(0..10_000, 0);
+        assert_eq!(iter.len(), NO_LIMIT_CAP);
+        assert_eq!(iter.next(), Some(0));
+        assert_eq!(iter.len(), NO_LIMIT_CAP);
+        assert_eq!(iter.count(), 9_999);
+    }
+
+    #[test]
+    fn chapter_url_partial_eq() -> anyhow::Result<()> {
+        let chapter_1 = ChapterUrl(Url::parse(
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title",
+        )?);
+        let chapter_2 = ChapterUrl(Url::parse("https://www.royalroad.com/fiction/12345/the-title-but-different/chapter/1234567/chapter_title")?);
+        assert_eq!(chapter_1, chapter_2);
+        assert_ne!(chapter_1.0, chapter_2.0);
+        assert_eq!(chapter_1.anchor(), "chapter-1234567");
+        assert_eq!(chapter_1.anchor(), chapter_2.anchor());
+        let state = RandomState::new();
+        assert_eq!(state.hash_one(&chapter_1), state.hash_one(&chapter_2));
+        let fiction = ChapterUrl(Url::parse("https://www.royalroad.com/fiction/12345")?);
+        assert_ne!(chapter_1, fiction);
+
+        // Scheme, query, fragment, and a trailing slash are ignored.
+        for url in [
+            "http://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title",
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title?utm_source=feed",
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title#comments",
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title/",
+        ] {
+            let url = ChapterUrl(Url::parse(url)?);
+            assert_eq!(chapter_1, url, "{}", url.0);
+            assert_eq!(state.hash_one(&chapter_1), state.hash_one(&url), "{}", url.0);
+        }
+        let other = ChapterUrl(Url::parse(
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/7654321/chapter_title/",
+        )?);
+        assert_ne!(chapter_1, other);
+        Ok(())
+    }
+
+    #[test]
+    fn chapter_titles() {
+        let main_title = "The Title | Royal Road";
+        assert_eq!(
+            strip_main_title("Chapter 1 - The Title | Royal Road", main_title),
+            "Chapter 1"
+        );
+        // Titles with the separator or a `|` of their own are only stripped at the end.
+        assert_eq!(
+            strip_main_title("1 - Start | Part 1 - The Title | Royal Road", main_title),
+            "1 - Start | Part 1"
+        );
+        assert_eq!(
+            strip_main_title("Chapter 1 | The Title | Royal Road", main_title),
+            "Chapter 1 | The Title | Royal Road"
+        );
+        assert_eq!(
+            strip_main_title("Chapter 1 - The Title | Royal Road", ""),
+            "Chapter 1 - The Title | Royal Road"
+        );
+    }
+
+    #[test]
+    fn parse_main_page_fixtures() -> anyhow::Result<()> {
+        let selectors = Selectors::default();
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/the-title")?;
+        let main_html = Html::parse_document(include_str!("../tests/fixtures/toc_promo.html"));
+        assert_eq!(
+            parse_main_title(&selectors, &main_html)?,
+            "The Title | Royal Road"
+        );
+        assert_eq!(
+            parse_cover_url(&selectors, &main_html, &url),
+            Some(Url::parse("https://www.royalroad.com/covers/12345.png")?)
+        );
+        assert_eq!(
+            parse_metadata(&selectors, &main_html),
+            FictionMetadata {
+                author: Some("The Author".to_owned()),
+                description: Some("First  paragraph & more.\nSecond paragraph.".to_owned()),
+                tags: vec!["Fantasy".to_owned(), "Action".to_owned()],
+                status: Some("Ongoing".to_owned()),
+            }
+        );
+        assert_eq!(
+            parse_metadata(
+                &selectors,
+                &Html::parse_document(
+                    r#"<meta property="books:author" content="Someone"><meta property="og:description" content="Summary">"#
+                )
+            ),
+            FictionMetadata {
+                author: Some("Someone".to_owned()),
+                description: Some("Summary".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        let age_gate = Html::parse_document(include_str!("../tests/fixtures/age_gate.html"));
+        assert_eq!(
+            parse_chapter_links(&selectors, &age_gate, &url),
+            Err(PageLayoutError::ChapterLinks)
+        );
+        assert_eq!(
+            parse_main_title(&selectors, &Html::parse_document("")),
+            Err(PageLayoutError::MainTitle)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn image_data_uri() {
+        let image = Image {
+            content_type: "image/png".to_owned(),
+            bytes: b"png".to_vec(),
+        };
+        assert_eq!(image.data_uri(), "data:image/png;base64,cG5n");
+        assert_eq!(image.extension(), "png");
+        assert_eq!(Image::from_data_uri(&image.data_uri()), Some(image));
+        assert_eq!(Image::from_data_uri("https://example.com/cover.png"), None);
+    }
+
+    #[test]
+    fn chapter_links_skip_non_chapter_rows() -> anyhow::Result<()> {
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/the-title")?;
+        let main_html = Html::parse_document(include_str!("../tests/fixtures/toc_promo.html"));
+        let chapters = parse_chapter_links(&Selectors::default(), &main_html, &url)?;
+        assert_eq!(
+            chapters,
+            vec![
+                ChapterUrl(Url::parse(
+                    "https://www.royalroad.com/fiction/12345/the-title/chapter/1000001/chapter-1"
+                )?),
+                ChapterUrl(Url::parse(
+                    "https://www.royalroad.com/fiction/12345/the-title/chapter/1000002/chapter-2"
+                )?),
+            ]
+        );
+        let published = parse_toc_page(&Selectors::default(), &main_html, &url)
+            .chapters
+            .into_iter()
+            .map(|x| x.published)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            published,
+            [
+                Some(OffsetDateTime::from_unix_timestamp(1_700_000_000)?),
+                None
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paginated_toc() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .and(matchers::query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_page_2.html")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+        let selectors = Selectors::default();
+        let limiter = Limiter::new(Duration::from_millis(1), Duration::ZERO);
+
+        let main_html = Html::parse_document(include_str!("../tests/fixtures/toc_page_1.html"));
+        let first_page = parse_toc_page(&selectors, &main_html, &url);
+        assert_eq!(first_page.chapters.len(), 2);
+        assert_eq!(first_page.next_page, Some(url.join("?page=2")?));
+        let chapters = fetch_chapter_links(
+            &reqwest::Client::new(),
+            &selectors,
+            &limiter,
+            &url,
+            first_page,
+        )
+        .await?;
+        assert_eq!(
+            chapters
+                .iter()
+                .map(|x| (x.url.0.path().to_owned(), x.title.clone()))
+                .collect::<Vec<_>>(),
+            (1..=3)
+                .map(|id| (
+                    format!("/fiction/12345/the-title/chapter/100000{id}/chapter-{id}"),
+                    format!("Chapter {id}")
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        // No chapters on any page.
+        let empty = fetch_chapter_links(
+            &reqwest::Client::new(),
+            &selectors,
+            &limiter,
+            &url,
+            TocPage {
+                chapters: Vec::new(),
+                next_page: None,
+            },
+        )
+        .await;
+        assert!(matches!(
+            empty,
+            Err(Error::Layout(PageLayoutError::ChapterLinks))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paginated_follows() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/my/follows"))
+            .and(matchers::query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/follows_page_2.html")),
+            )
+            .expect(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/my/follows"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/follows_page_1.html")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Logged out.
+        Mock::given(matchers::path("/my/history"))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", "/account/login"))
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/account/login"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<form></form>"))
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/my/reading-list"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_string(include_str!("../tests/fixtures/cloudflare_challenge.html")),
+            )
+            .mount(&server)
+            .await;
+        let base = Url::parse(&server.uri())?;
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        let limiter = Limiter::new(Duration::from_millis(1), Duration::ZERO);
+
+        // Fictions of every page, each once.
+        let fictions =
+            follows_fictions(&client, &selectors, &limiter, &base.join("/my/follows")?).await?;
+        assert_eq!(
+            fictions.iter().map(Url::path).collect::<Vec<_>>(),
+            [
+                "/fiction/12345/the-title",
+                "/fiction/23456/another-title",
+                "/fiction/34567/a-third-title"
+            ]
+        );
+
+        let history = base.join("/my/history")?;
+        assert!(matches!(
+            follows_fictions(&client, &selectors, &limiter, &history).await,
+            Err(Error::Redirected(to)) if to.path() == "/account/login"
+        ));
+        let reading_list = base.join("/my/reading-list")?;
+        assert!(matches!(
+            follows_fictions(&client, &selectors, &limiter, &reading_list).await,
+            Err(Error::Blocked)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_chapter_times_out() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let body = r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content">Content</div></body></html>"#;
+        Mock::given(matchers::path("/slow"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(body)
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/fast"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(body))
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        let base = Url::parse(&server.uri())?;
+        let timeout = Some(Duration::from_millis(200));
+
+        let slow = fetch_chapter(
+            &client,
+            &selectors,
+            0,
+            base.join("/slow")?,
+            "1/2",
+            "Title",
+            Processing::default(),
+            None,
+            None,
+            timeout,
+        )
+        .await;
+        assert!(
+            matches!(slow.as_ref().map_err(Error::inner), Err(Error::Timeout(_))),
+            "{slow:?}"
+        );
+        assert_eq!(
+            slow.unwrap_err().to_string(),
+            format!(
+                "chapter 1 ({}): chapter took longer than 200ms",
+                base.join("/slow")?
+            )
+        );
+        let fast = fetch_chapter(
+            &client,
+            &selectors,
+            1,
+            base.join("/fast")?,
+            "2/2",
+            "Title",
+            Processing::default(),
+            None,
+            None,
+            timeout,
+        )
+        .await?;
+        assert!(fast.body_html.contains("Content"));
+        Ok(())
+    }
+
+    /// Only checks confirmation against the mock's own gate and cookie. See [`AGE_CONFIRMATION_COOKIE`](crate::AGE_CONFIRMATION_COOKIE).
+    #[tokio::test]
+    async fn age_gate() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .and(matchers::header("cookie", crate::AGE_CONFIRMATION_COOKIE))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/age_gate.html")),
+            )
+            .mount(&server)
+            .await;
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+        let selectors = Selectors::default();
+        let limiter = Limiter::new(Duration::from_millis(1), Duration::ZERO);
+
+        // Error without confirmation.
+        let jar = Arc::new(Jar::default());
+        let client = reqwest::Client::builder()
+            .cookie_provider(jar.clone())
+            .build()?;
+        let main_html = fetch_main_page(&client, &jar, &selectors, &limiter, &url, false).await;
+        assert!(matches!(main_html, Err(Error::AgeGate)));
+
+        // Retry with cookie after confirmation.
+        let main_html = fetch_main_page(&client, &jar, &selectors, &limiter, &url, true).await?;
+        assert_eq!(parse_chapter_links(&selectors, &main_html, &url)?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_fiction_from_mock_server() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .mount(&server)
+            .await;
+        for id in 1..=2 {
+            Mock::given(matchers::path(format!(
+                "/fiction/12345/the-title/chapter/100000{id}/chapter-{id}"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                r#"<html><head><title>Chapter {id} - The Title | Royal Road</title></head><body><div class="chapter-content"><p>Content {id}</p></div></body></html>"#
+            )))
+            .mount(&server)
+            .await;
+        }
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+
+        let jar = Arc::new(Jar::default());
+        let client = reqwest::Client::builder()
+            .cookie_provider(jar.clone())
+            .build()?;
+        let opts = DownloadOptions {
+            time_limit: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+            ..Default::default()
+        };
+        let fiction = download_fiction(&client, &jar, &url, &opts).await?;
+        assert_eq!(fiction.title, "The Title | Royal Road");
+        assert_eq!(
+            fiction
+                .chapters
+                .iter()
+                .map(|x| x.url.path())
+                .collect::<Vec<_>>(),
+            [
+                "/fiction/12345/the-title/chapter/1000001/chapter-1",
+                "/fiction/12345/the-title/chapter/1000002/chapter-2"
+            ]
+        );
+        assert_eq!(fiction.chapters[1].index, 1);
+        assert_eq!(fiction.chapters[1].title, "Chapter 2");
+        assert_eq!(
+            fiction.chapters[1].body_html,
+            r#"<div class="chapter-content"><p>Content 2</p></div>"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chapter_title_sources() -> anyhow::Result<()> {
+        use reqwest::ResponseBuilderExt;
+        let title = |head: &str, header: &str, main_title: &str| {
+            let response = http::Response::builder()
+                .url(Url::parse("https://www.royalroad.com/fiction/12345/t/chapter/1/c").unwrap())
+                .body(format!(
+                    r#"<html><head>{head}</head><body><div class="fic-header">{header}</div><div class="chapter-content"><p>Content</p></div></body></html>"#
+                ))
+                .unwrap();
+            let main_title = main_title.to_owned();
+            async move {
+                chapter_response_to_content(
+                    &Selectors::default(),
+                    0,
+                    "1/1",
+                    response.into(),
+                    &main_title,
+                    Processing::default(),
+                )
+                .await
+                .map(|x| x.title)
+            }
+        };
+        let head = "<title>\n  Chapter 1 - The Title | Royal Road \n</title>";
+        let main_title = "The Title | Royal Road";
+        assert_eq!(title(head, "", main_title).await?, "Chapter 1");
+        // The heading is used as is, even if the title has a separator of its own.
+        assert_eq!(
+            title(
+                head,
+                "<h1> Chapter 1 - <span>Start</span> </h1><h2>The Title</h2>",
+                main_title
+            )
+            .await?,
+            "Chapter 1 - Start"
+        );
+        // Empty headings are skipped.
+        assert_eq!(title(head, "<h1> </h1>", main_title).await?, "Chapter 1");
+        // The heading doesn't depend on stripping page titles.
+        assert_eq!(title(head, "<h1>Chapter 1</h1>", "").await?, "Chapter 1");
+        // Page titles are kept whole without a main title to strip.
+        assert_eq!(
+            title(head, "", "").await?,
+            "Chapter 1 - The Title | Royal Road"
+        );
+        // The `og:title` meta tag is preferred to the page title, and stripped the same way.
+        let og = r#"<meta property="og:title" content=" Chapter 1: Start - The Title | Royal Road "><title>Royal Road</title>"#;
+        assert_eq!(title(og, "", main_title).await?, "Chapter 1: Start");
+        assert_eq!(
+            title(og, "", "").await?,
+            "Chapter 1: Start - The Title | Royal Road"
+        );
+        assert_eq!(
+            title(og, "<h1>Chapter 1</h1>", main_title).await?,
+            "Chapter 1"
+        );
+        // Empty meta tags are skipped.
+        assert_eq!(
+            title(
+                &format!(r#"<meta property="og:title" content=" ">{head}"#),
+                "",
+                main_title
+            )
+            .await?,
+            "Chapter 1"
+        );
+        assert!(matches!(
+            title("<title> </title>", "", main_title).await,
+            Err(Error::Layout(PageLayoutError::ChapterTitle))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warning_paragraphs_selector() -> anyhow::Result<()> {
+        use reqwest::ResponseBuilderExt;
+        let content = |selectors: Selectors, styles: Styles| async move {
+            let response = http::Response::builder()
+                .url(Url::parse("https://www.royalroad.com/fiction/12345/t/chapter/1/c")?)
+                .body(r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content"><p>Content</p><p class="cjWarning">Stolen</p><p class="newWarning">Stolen</p></div></body></html>"#)?;
+            anyhow::Ok(
+                chapter_response_to_content(
+                    &selectors,
+                    0,
+                    "1/1",
+                    response.into(),
+                    "Title",
+                    Processing {
+                        styles,
+                        ..Default::default()
+                    },
+                )
+                .await?
+                .body_html,
+            )
+        };
+        assert_eq!(
+            content(Selectors::default(), Styles::Keep).await?,
+            r#"<div class="chapter-content"><p>Content</p><p class="newWarning">Stolen</p></div>"#
+        );
+        let custom = Selectors {
+            warning_paragraphs: Some(scraper::Selector::parse(".newWarning").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            content(custom, Styles::Keep).await?,
+            r#"<div class="chapter-content"><p>Content</p><p class="cjWarning">Stolen</p></div>"#
+        );
+        let keep = Selectors {
+            warning_paragraphs: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            content(keep, Styles::Keep).await?.matches("Stolen").count(),
+            2
+        );
+        // Warning paragraphs are found by their classes before styles are stripped.
+        assert_eq!(
+            content(Selectors::default(), Styles::Strip).await?,
+            r#"<div class="chapter-content"><p>Content</p><p>Stolen</p></div>"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_text_decodes_like_text() -> anyhow::Result<()> {
+        let response = |content_type: Option<&str>, body: &[u8]| {
+            let mut response = http::Response::builder();
+            if let Some(content_type) = content_type {
+                response = response.header("content-type", content_type);
+            }
+            reqwest::Response::from(response.body(body.to_vec()).unwrap())
+        };
+        for (content_type, body) in [
+            (Some("text/html; charset=utf-8"), "Café ☃".as_bytes()),
+            (Some(r#"text/html; Charset="windows-1252""#), b"Caf\xe9"),
+            (
+                Some("text/html; charset=windows-1252"),
+                b"\xef\xbb\xbfCaf\xc3\xa9",
+            ),
+            (Some("text/html; charset=unknown"), "Café".as_bytes()),
+            (None, b"Caf\xff\xc3"),
+        ] {
+            assert_eq!(
+                read_text(response(content_type, body)).await?,
+                response(content_type, body).text().await?,
+                "{content_type:?}"
+            );
+        }
+        assert_eq!(
+            read_text(response(
+                Some("text/html; charset=windows-1252"),
+                b"Caf\xe9"
+            ))
+            .await?,
+            "Café"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bandwidth_limit() -> anyhow::Result<()> {
+        use reqwest::ResponseBuilderExt;
+        let response = http::Response::builder()
+            .url(Url::parse(
+                "https://www.royalroad.com/fiction/12345/t/chapter/1/c",
+            )?)
+            .header("etag", "\"1\"")
+            .body(vec![b'a'; 1500])?;
+        let bandwidth = Bandwidth::new(NonZeroUsize::new(1000).unwrap());
+        let start = std::time::Instant::now();
+        let read = bandwidth.read(response.into()).await?;
+        // A second's worth is allowed at once then the rest is read at the limit.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(read.headers()["etag"], "\"1\"");
+        assert_eq!(read.bytes().await?.len(), 1500);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chapter_published_time() -> anyhow::Result<()> {
+        use reqwest::ResponseBuilderExt;
+        let chapter = |time: &'static str| async move {
+            let response = http::Response::builder()
+                .url(Url::parse("https://www.royalroad.com/fiction/12345/t/chapter/1/c")?)
+                .body(format!(r#"<html><head><title>Chapter - Title</title></head><body>{time}<div class="chapter-content"><p>Content</p></div></body></html>"#))?;
+            anyhow::Ok(
+                chapter_response_to_content(
+                    &Selectors::default(),
+                    0,
+                    "1/1",
+                    response.into(),
+                    "Title",
+                    Processing::default(),
+                )
+                .await?,
+            )
+        };
+        let published = OffsetDateTime::from_unix_timestamp(1_700_000_000)?;
+        let datetime = chapter(r#"<time unixtime="1700000000" datetime="2023-11-14T22:13:20.0000000Z">a year ago</time>"#).await?;
+        assert_eq!(datetime.published, Some(published));
+        let unixtime = chapter(r#"<time unixtime="1700000000">a year ago</time>"#).await?;
+        assert_eq!(unixtime.published, Some(published));
+        assert!(unixtime.to_html().contains(
+            r#"</h1><p class="published"><time datetime="2023-11-14T22:13:20Z">2023-11-14</time></p><div class="chapter-content">"#
+        ));
+
+        let missing = chapter(r#"<time datetime="not a time">never</time>"#).await?;
+        assert_eq!(missing.published, None);
+        assert!(missing
+            .to_html()
+            .contains(r#"</h1><div class="chapter-content">"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_chapters() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for status in [404, 410] {
+            Mock::given(matchers::path(format!("/chapter/{status}")))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(matchers::path("/chapter/500"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        for status in [404, 410, 500] {
+            let url = Url::parse(&server.uri())?.join(&format!("/chapter/{status}"))?;
+            let e = fetch_chapter(
+                &client,
+                &selectors,
+                0,
+                url,
+                "1/1",
+                "Title",
+                Processing::default(),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(e.is_missing(), status != 500, "{status}");
+            assert_eq!(e.is_transient(), status == 500, "{status}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn challenge_pages() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let challenge = include_str!("../tests/fixtures/cloudflare_challenge.html");
+        // Other pages have a script from the challenge platform added.
+        let chapter = r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content"><p>Content</p></div><script src="/cdn-cgi/challenge-platform/scripts/jsd/main.js"></script></body></html>"#;
+        let server = MockServer::start().await;
+        for (path, response) in [
+            (
+                "/challenged",
+                ResponseTemplate::new(403)
+                    .insert_header("cf-mitigated", "challenge")
+                    .set_body_string(challenge),
+            ),
+            (
+                "/forbidden-challenge",
+                ResponseTemplate::new(403).set_body_string(challenge),
+            ),
+            (
+                "/unavailable-challenge",
+                ResponseTemplate::new(503).set_body_string(challenge),
+            ),
+            (
+                "/interstitial",
+                ResponseTemplate::new(200).set_body_string(challenge),
+            ),
+            (
+                "/forbidden",
+                ResponseTemplate::new(403).set_body_string("Forbidden"),
+            ),
+            (
+                "/chapter",
+                ResponseTemplate::new(200).set_body_string(chapter),
+            ),
+        ] {
+            Mock::given(matchers::path(path))
+                .respond_with(response)
+                .mount(&server)
+                .await;
+        }
+
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        let fetch = |path: &str| {
+            let url = Url::parse(&server.uri()).unwrap().join(path).unwrap();
+            let client = &client;
+            let selectors = &selectors;
+            async move {
+                fetch_chapter(
+                    client,
+                    selectors,
+                    0,
+                    url,
+                    "1/1",
+                    "Title",
+                    Processing::default(),
+                    None,
+                    None,
+                    None,
+                )
+                .await
+            }
+        };
+        for path in [
+            "/challenged",
+            "/forbidden-challenge",
+            "/unavailable-challenge",
+            "/interstitial",
+        ] {
+            let e = fetch(path).await.unwrap_err();
+            assert!(matches!(e.inner(), Error::Blocked), "{path}: {e:?}");
+            assert!(!e.is_transient(), "{path}");
+        }
+        let e = fetch("/forbidden").await.unwrap_err();
+        assert!(
+            matches!(e.inner(), Error::Request(e) if e.status() == Some(reqwest::StatusCode::FORBIDDEN)),
+            "{e:?}"
+        );
+        assert_eq!(
+            fetch("/chapter").await?.body_html,
+            r#"<div class="chapter-content"><p>Content</p></div>"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn challenge_pages_are_not_cached() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        // The first request is challenged, later ones get the chapter.
+        Mock::given(matchers::path("/chapter"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/cloudflare_challenge.html")),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/chapter"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content">Content</div></body></html>"#))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let cache = ResponseCache::new(dir.path(), true);
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        let url = Url::parse(&server.uri())?.join("/chapter")?;
+        let fetch = || {
+            fetch_chapter(
+                &client,
+                &selectors,
+                0,
+                url.clone(),
+                "1/1",
+                "Title",
+                Processing::default(),
+                Some(&cache),
+                None,
+                None,
+            )
+        };
+        let e = fetch().await.unwrap_err();
+        assert!(matches!(e.inner(), Error::Blocked), "{e:?}");
+        assert!(!cache.contains(&url));
+
+        // The next run downloads the chapter instead of reading the challenge from the cache.
+        let chapter = fetch().await?;
+        assert_eq!(
+            chapter.body_html,
+            r#"<div class="chapter-content">Content</div>"#
+        );
+        assert!(cache.contains(&url));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_unmodified_chapter() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/chapter"))
+            .and(matchers::header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/chapter"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v2\"")
+                    .set_body_string(r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content">Edited</div></body></html>"#),
+            )
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let selectors = Selectors::default();
+        let url = Url::parse(&server.uri())?.join("/chapter")?;
+        let refresh = |etag: &str| {
+            let validators = Validators {
+                etag: Some(etag.to_owned()),
+                last_modified: None,
+            };
+            let (client, selectors, url) = (&client, &selectors, url.clone());
+            async move {
+                refresh_chapter(
+                    client,
+                    selectors,
+                    0,
+                    url,
+                    "1/1",
+                    "Title",
+                    Processing::default(),
+                    &validators,
+                )
+                .await
+            }
+        };
+        assert_eq!(refresh("\"v1\"").await?, None);
+        let chapter = refresh("\"v0\"").await?.unwrap();
+        assert!(chapter.body_html.contains("Edited"));
+        assert_eq!(chapter.validators.etag.as_deref(), Some("\"v2\""));
+        Ok(())
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, " 120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        headers.insert(RETRY_AFTER, date.parse().unwrap());
+        assert!(retry_after(&headers).is_some_and(|x| x <= Duration::from_secs(60)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let body = r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content">Content</div></body></html>"#;
+        Mock::given(matchers::path("/flaky"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/flaky"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(body))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let selectors = Arc::new(Selectors::default());
+        let limiter = Arc::new(Limiter::new(Duration::from_millis(1), Duration::ZERO));
+        let base = Url::parse(&server.uri())?;
+        let mut downloads = download_chapters(
+            &client,
+            &selectors,
+            &limiter,
+            vec![
+                (0, ChapterUrl(base.join("/flaky")?)),
+                (1, ChapterUrl(base.join("/missing")?)),
+            ],
+            2,
+            "Title",
+            &DownloadOptions::default(),
+        );
+        let flaky = downloads.next_result().await.unwrap()??;
+        assert!(flaky.body_html.contains("Content"));
+        let missing = downloads.next_result().await.unwrap()?;
+        assert!(
+            matches!(
+                missing.as_ref().map_err(Error::inner),
+                Err(Error::ChapterMissing(reqwest::StatusCode::NOT_FOUND))
+            ),
+            "{missing:?}"
+        );
+        assert!(downloads.next_result().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn embed_images() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/banner.png"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "image/png")
+                    .set_body_bytes(b"png".to_vec()),
+            )
+            // Once with and once without the query.
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/missing.png"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        let base = Url::parse(&server.uri())?;
+        let images = ImageCache::new(
+            reqwest::Client::new(),
+            HostLimiters::new(Limiter::new(Duration::from_millis(1), Duration::ZERO)),
+        );
+        let selectors = Selectors::default();
+
+        for index in 0..2 {
+            let mut chapter = Chapter {
+                index,
+                title: "Chapter".to_owned(),
+                url: base.join(&format!("/fiction/1/t/chapter/{index}/c"))?,
+                body_html: r#"<div class="chapter-content"><img src="/banner.png"><p>Text <a href="/banner.png">/banner.png</a> <code>&lt;img src="/banner.png"&gt;</code></p><img src="/banner.png"><img src="/missing.png"><img src="/banner.png?size=1&amp;v=2"></div>"#.to_owned(),
+                published: None,
+                validators: Default::default(),
+            };
+            images.embed(&selectors, &mut chapter, "1/2").await;
+            // Links and text with the same url are left as they are.
+            assert_eq!(
+                chapter.body_html,
+                r#"<div class="chapter-content"><img src="data:image/png;base64,cG5n"><p>Text <a href="/banner.png">/banner.png</a> <code>&lt;img src="/banner.png"&gt;</code></p><img src="data:image/png;base64,cG5n"><img src="/missing.png"><img src="data:image/png;base64,cG5n"></div>"#
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_images_to_dir() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/banner.png"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "image/png")
+                    .set_body_bytes(b"png".to_vec()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let base = Url::parse(&server.uri())?;
+        let dir = tempfile::tempdir()?;
+        let image_dir = dir.path().join("images");
+        std::fs::create_dir(&image_dir)?;
+        let images = ImageCache::new(
+            reqwest::Client::new(),
+            HostLimiters::new(Limiter::new(Duration::from_millis(1), Duration::ZERO)),
+        )
+        .with_dir(image_dir.clone());
+
+        let mut chapter = Chapter {
+            index: 0,
+            title: "Chapter".to_owned(),
+            url: base.join("/fiction/1/t/chapter/1/c")?,
+            body_html: r#"<div class="chapter-content"><img src="/banner.png"><img src="/banner.png"></div>"#.to_owned(),
+            published: None,
+            validators: Default::default(),
+        };
+        images
+            .embed(&Selectors::default(), &mut chapter, "1/1")
+            .await;
+        // Named by the hash of its url, which is the same in later runs.
+        let name = format!("{:016x}.png", url_hash(&base.join("/banner.png")?));
+        assert_eq!(
+            chapter.body_html,
+            format!(
+                r#"<div class="chapter-content"><img src="images/{name}"><img src="images/{name}"></div>"#
+            )
+        );
+        assert_eq!(std::fs::read(image_dir.join(&name))?, b"png");
+        assert_eq!(
+            url_hash(&Url::parse("https://example.com/a.png")?),
+            0xfa37_8f65_3a39_84b6
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chapter_word_count() -> Result<(), url::ParseError> {
+        let chapter = Chapter {
+            index: 0,
+            title: "Chapter".to_owned(),
+            url: Url::parse("https://www.royalroad.com/fiction/1/t/chapter/1/c")?,
+            body_html: r#"<div class="chapter-content"><p>One <b>two</b>,</p><p>three&nbsp;four</p><p>five</p></div>"#.to_owned(),
+            published: None,
+            validators: Default::default(),
+        };
+        assert_eq!(chapter.word_count(), 5);
+        assert_eq!(chapter.text_len(), 20);
+        Ok(())
+    }
+}
@@ -1,161 +1,1175 @@
-use leaky_bucket::RateLimiter;
+use anstream::{eprintln, print, println};
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use encoding_rs::Encoding;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use owo_colors::OwoColorize;
 use regex::Regex;
-use royalroad_dl::BufferedIter;
-use scraper::Html;
+use reqwest::{cookie::Jar, Proxy};
+use royalroad_dl::{
+    author_notes::AuthorNotes,
+    cache::ResponseCache,
+    download_chapters, escape_attribute, escape_text, fetch_chapter_links, fetch_fiction_index,
+    fetch_image, fetch_main_page, follows_fictions, log_progress,
+    markdown::html_to_markdown,
+    parse_main_title, parse_metadata, parse_output, parse_toc_page, refresh_chapter,
+    selectors::{self, SelectorError, Selectors},
+    site::{Site, ROYAL_ROAD},
+    styles::Styles,
+    text::html_to_text,
+    whitespace::Whitespace,
+    Chapter, ChapterUrl, DownloadOptions, Error, FictionIndex, FictionMetadata, Image, Limiter,
+    Processing, Throttle, TocChapter, Validators, RETRY_BACKOFF, USER_AGENT,
+};
+use scraper::{Html, Selector};
 use std::{
     borrow::Cow,
-    num::NonZeroU64,
-    path::PathBuf,
+    collections::HashSet,
+    ffi::OsString,
+    future::Future,
+    io::{BufRead, IsTerminal, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+    process::ExitCode,
     sync::{Arc, OnceLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::watch,
+};
+use tracing::{
+    error,
+    field::{Field, Visit},
+    info, warn, Level,
+};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
 };
 use url::Url;
 
-mod selectors;
 const END_HTML: &str = "</body></html>";
+/// Stylesheet of `--style default`: a centered column of comfortably spaced text with a rule above each chapter.
+const DEFAULT_CSS: &str = "body{max-width:40em;margin:0 auto;padding:1em;line-height:1.6}\
+img{max-width:100%}\
+.cover{display:block;margin:0 auto}\
+h1{margin-top:3em;padding-top:1em;border-top:1px solid #8888}";
+/// Stylesheet of `--theme dark`: light text on a dark background when the reader's system prefers a dark color scheme.
+const DARK_CSS: &str = "@media (prefers-color-scheme:dark){\
+body{background:#121212;color:#ddd}\
+a{color:#8ab4f8}\
+a:visited{color:#c58af9}}";
+/// Magic bytes at the start of gzip compressed files.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
-/// Layout of page changed.
-#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
-enum PageLayoutError {
-    #[error("main page title not found")]
-    MainTitle,
-    #[error("no chapter links found")]
-    ChapterLinks,
-    #[error("chapter title not found")]
-    ChapterTitle,
-    #[error("chapter body not found")]
-    ChapterBody,
-}
-/// The error type for custom errors with the downloader.
-#[derive(thiserror::Error, Debug)]
-enum Error {
-    #[error("Page layout different from expected. Perhaps the website changed?: {0}")]
-    Layout(#[from] PageLayoutError),
-    #[error("{0}")]
-    Request(#[from] reqwest::Error),
-}
-
-/// Wrapper over [`Url`] that compares urls as equal if they represent the same fiction regardless of url content (e.g. with same uuid but different title as same).
-#[derive(Clone, Debug)]
-struct ChapterUrl(pub Url);
-impl PartialEq for ChapterUrl {
-    fn eq(&self, other: &Self) -> bool {
-        let Some(iter) = self
-            .0
-            .path_segments()
-            .zip(other.0.path_segments())
-            .map(|(p1, p2)| core::iter::zip(p1, p2))
-        else {
-            return false;
-        };
-        iter.enumerate().all(|(i, (p1, p2))| i == 2 || p1 == p2)
-    }
-}
-impl Eq for ChapterUrl {}
-impl From<Url> for ChapterUrl {
-    fn from(value: Url) -> Self {
-        Self(value)
-    }
-}
-impl From<ChapterUrl> for Url {
-    fn from(value: ChapterUrl) -> Self {
-        value.0
-    }
-}
+/// Longest file name in bytes returned by [`sanitize_path`].
+///
+/// Below the common 255 byte limit to leave room for the suffixes of files next to outputs (e.g. `.meta.json`).
+const MAX_FILE_NAME_LEN: usize = 200;
+/// Names of devices on Windows which can't be used as the name of a file, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
 
 /// Convert path to something that can be saved to file.
+///
+/// The name is normalized to NFC (with the `unicode-normalization` feature), runs of illegal characters and the
+/// underscores around them are replaced by a single `_`, trailing dots and spaces removed, reserved device names get
+/// an `_` appended, and names longer than [`MAX_FILE_NAME_LEN`] are truncated before their extension.
 pub fn sanitize_path(path: &str) -> Cow<'_, str> {
     static REGEX: OnceLock<Regex> = OnceLock::new();
     // See https://en.wikipedia.org/wiki/Filename#Comparison_of_filename_limitations
-    let regex = REGEX.get_or_init(|| Regex::new(r#"[\x00-\x1F\x7F"*/:<>?\\|]+"#).unwrap());
-    regex.replace_all(path, "_")
-}
-
-/// - Seek to after the last content previously downloaded in preparation for writing new content.
-/// - Retrieves cached chapters.
-async fn start_incremental_append(f: &mut tokio::fs::File) -> std::io::Result<Vec<ChapterUrl>> {
-    let previous_download = {
-        let mut s = String::new();
-        f.read_to_string(&mut s).await?;
-        s
+    let regex = REGEX.get_or_init(|| {
+        Regex::new(r#"_*[\x00-\x1F\x7F"*/:<>?\\|][\x00-\x1F\x7F"*/:<>?\\|_]*"#).unwrap()
+    });
+    // Composed and decomposed forms of the same title would otherwise be saved to different files.
+    #[cfg(feature = "unicode-normalization")]
+    let mut path = {
+        use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+        match is_nfc_quick(path.chars()) {
+            IsNormalized::Yes => Cow::Borrowed(path),
+            IsNormalized::No | IsNormalized::Maybe => Cow::Owned(path.nfc().collect()),
+        }
     };
+    #[cfg(not(feature = "unicode-normalization"))]
+    let mut path = Cow::Borrowed(path);
+    let replaced = match regex.replace_all(&path, "_") {
+        Cow::Owned(x) => Some(x),
+        Cow::Borrowed(_) => None,
+    };
+    if let Some(replaced) = replaced {
+        path = Cow::Owned(replaced);
+    }
+
+    // Windows drops trailing dots and spaces so names differing by them would collide.
+    let trimmed = path.trim_end_matches(['.', ' ']).len();
+    if trimmed != path.len() {
+        path.to_mut().truncate(trimmed);
+    }
+    // Reserved names are reserved regardless of extension (e.g. `nul.html`).
+    let device = path.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(device))
+    {
+        let end = device.len();
+        path.to_mut().insert(end, '_');
+    }
+    if path.len() > MAX_FILE_NAME_LEN {
+        let extension = path
+            .rsplit_once('.')
+            .map(|x| x.1)
+            .filter(|x| (1..=8).contains(&x.len()) && x.bytes().all(|x| x.is_ascii_alphanumeric()))
+            .map_or(String::new(), |x| format!(".{x}"));
+        let mut end = MAX_FILE_NAME_LEN - extension.len();
+        while !path.is_char_boundary(end) {
+            end -= 1;
+        }
+        let stem = path[..end].trim_end_matches(['.', ' ']);
+        path = Cow::Owned(format!("{stem}{extension}"));
+    }
+    if path.is_empty() {
+        path = Cow::Borrowed("_");
+    }
+    path
+}
 
-    // Get cached chapters.
-    let previous_html = Html::parse_document(&previous_download);
-    let cached_chapters = previous_html
-        .select(selectors::downloaded_chapters())
+/// Retrieves cached chapters from previously downloaded content.
+fn cached_chapters(
+    selectors: &Selectors,
+    format: Format,
+    previous_download: &str,
+) -> Vec<ChapterUrl> {
+    if let Some(regex) = heading_regex(format) {
+        return regex
+            .captures_iter(previous_download)
+            .filter_map(|x| Url::parse(&x[1]).ok())
+            .map(Into::into)
+            .collect();
+    }
+    let previous_html = Html::parse_document(previous_download);
+    previous_html
+        .select(&selectors.downloaded_chapters)
         .filter_map(|x| {
             x.attr("href")
                 .and_then(|x| Url::parse(x).ok())
                 .map(Into::into)
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+/// Regex capturing the url of chapter headings in outputs of `format` other than html.
+fn heading_regex(format: Format) -> Option<&'static Regex> {
+    match format {
+        Format::Markdown => {
+            static REGEX: OnceLock<Regex> = OnceLock::new();
+            // Chapter headings written by `html_to_markdown` (e.g. `# [Title](url)`).
+            Some(
+                REGEX
+                    .get_or_init(|| Regex::new(r"(?m)^# \[.*\]\(<?([^\s<>]+)>?\)[ \t]*$").unwrap()),
+            )
+        }
+        Format::Text => {
+            static REGEX: OnceLock<Regex> = OnceLock::new();
+            // Url after chapter heading underlines written by `html_to_text`.
+            Some(REGEX.get_or_init(|| Regex::new(r"(?m)^=+\r?\n(\S+)$").unwrap()))
+        }
+        Format::Html | Format::Epub => None,
+    }
+}
+
+/// Url and byte range of each chapter as written by [`Output::write_chapter`] in `previous_download`.
+///
+/// Ranges don't include the separators between chapters.
+fn chapter_sections(format: Format, previous_download: &str) -> Vec<(ChapterUrl, Range<usize>)> {
+    let starts: Vec<(usize, &str)> = match heading_regex(format) {
+        Some(regex) => regex
+            .captures_iter(previous_download)
+            .map(|x| {
+                let start = x.get(0).unwrap().start();
+                // Text headings start at the title before their underline.
+                let start = if format == Format::Text {
+                    previous_download[..start.saturating_sub(1)]
+                        .rfind('\n')
+                        .map_or(0, |x| x + 1)
+                } else {
+                    start
+                };
+                (start, x.get(1).unwrap().as_str())
+            })
+            .collect(),
+        None => {
+            const HEADING: &str = r#"<h1><a class="chapter" href=""#;
+            previous_download
+                .match_indices(HEADING)
+                .filter_map(|(start, _)| {
+                    let url = &previous_download[start + HEADING.len()..];
+                    Some((start, &url[..url.find('"')?]))
+                })
+                .collect()
+        }
+    };
+    let end =
+        resume_offset(format, previous_download.as_bytes()).unwrap_or(previous_download.len());
+    let separator = format.separator();
+    starts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &(start, url))| {
+            let end = match starts.get(i + 1) {
+                Some(&(next, _)) => previous_download[..next]
+                    .strip_suffix(separator)
+                    .map_or(next, str::len),
+                None => end,
+            };
+            Some((Url::parse(url).ok()?.into(), start..end))
+        })
+        .collect()
+}
+
+/// Content previously downloaded to the output path.
+struct PreviousDownload {
+    /// Uncompressed content.
+    content: Vec<u8>,
+    /// If the content was gzip compressed.
+    compressed: bool,
+    /// Where new content goes according to the sidecar metadata, if it is consistent with the content.
+    saved_offset: Option<usize>,
+}
+
+impl PreviousDownload {
+    /// Where new content should be written, preferring the sidecar metadata to finding the end in the content.
+    fn resume_offset(&self, format: Format) -> Option<usize> {
+        self.saved_offset
+            .or_else(|| resume_offset(format, &self.content))
+    }
+    /// Replace the file at `path` with the content, compressing it if it was compressed.
+    ///
+    /// The content is written to [`temp_path`] and renamed over `path` once finished so `path` is left as it was if
+    /// writing fails or is interrupted.
+    async fn write(&self, path: &Path) -> std::io::Result<()> {
+        let temp_path = temp_path(path);
+        let written = async {
+            let f = File::create(&temp_path).await?;
+            if self.compressed {
+                let mut f = GzipEncoder::new(f);
+                f.write_all(&self.content).await?;
+                f.shutdown().await
+            } else {
+                let mut f = f;
+                f.write_all(&self.content).await?;
+                f.shutdown().await
+            }
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        tokio::fs::rename(temp_path, path).await
+    }
+}
+
+/// Sibling of `path` that replacing content is written to before it is renamed over `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".tmp");
+    path.into()
+}
+
+/// Read content previously downloaded to `path`, decompressing it if it is gzip compressed.
+async fn read_previous_download(path: &Path) -> std::io::Result<PreviousDownload> {
+    let content = tokio::fs::read(path).await?;
+    if !content.starts_with(&GZIP_MAGIC) {
+        return Ok(PreviousDownload {
+            content,
+            compressed: false,
+            saved_offset: None,
+        });
+    }
+
+    let mut decoder = GzipDecoder::new(&content[..]);
+    decoder.multiple_members(true);
+    let mut decompressed = Vec::new();
+    match decoder.read_to_end(&mut decompressed).await {
+        Ok(_) => (),
+        // An interrupted download leaves the stream unfinished but everything flushed before is intact.
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            warn!(
+                "{}",
+                "Compressed output is incomplete. Resuming after its last complete content."
+                    .yellow()
+            )
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(PreviousDownload {
+        content: decompressed,
+        compressed: true,
+        saved_offset: None,
+    })
+}
+
+/// Offset of the last `END_HTML` in `previous_download`, where new content should be written.
+///
+/// Markdown and text have no end so new content is written at the end.
+fn resume_offset(format: Format, previous_download: &[u8]) -> Option<usize> {
+    if matches!(format, Format::Markdown | Format::Text) {
+        return Some(previous_download.len());
+    }
+    previous_download
+        .windows("</body>".len())
+        .rposition(|x| x == b"</body>")
+}
+
+/// If the chapter `section` of an output in `format` has its heading and a body after it as written by
+/// [`Output::write_chapter`], rather than being cut off.
+fn is_complete_chapter(format: Format, section: &str) -> bool {
+    match format {
+        // The chapter content is a single element after the heading and publication time.
+        Format::Html | Format::Epub => section
+            .split_once("</h1>")
+            .is_some_and(|(_, body)| body.trim_end().ends_with("</div>")),
+        // Text headings are the title, its underline, and the url.
+        Format::Markdown | Format::Text => section
+            .lines()
+            .skip(if format == Format::Text { 3 } else { 1 })
+            .any(|x| !x.trim().is_empty()),
+    }
+}
+
+/// Remove the last chapter of `previous_download`, written in `format` with `encoding`, if it was cut off (e.g. by
+/// an interrupted download) so it is downloaded again instead of being kept as cached.
+///
+/// Returns the url of the removed chapter.
+fn drop_truncated_chapter(
+    format: Format,
+    encoding: &'static Encoding,
+    previous_download: &mut PreviousDownload,
+) -> Option<ChapterUrl> {
+    let content = encoding.decode(&previous_download.content).0;
+    let (url, range) = chapter_sections(format, &content).pop()?;
+    if is_complete_chapter(format, &content[range.clone()]) {
+        return None;
+    }
+    let before = &content[..range.start];
+    let before = before.strip_suffix(format.separator()).unwrap_or(before);
+    let mut truncated = encoding.encode(before).0.into_owned();
+    if format == Format::Html {
+        truncated.extend_from_slice(END_HTML.as_bytes());
+    }
+    previous_download.content = truncated;
+    previous_download.saved_offset = None;
+    Some(url)
+}
 
+/// Seek to after the last content previously downloaded in preparation for writing new content.
+///
+/// Returns the offset writing starts at.
+async fn start_incremental_append(
+    f: &mut tokio::fs::File,
+    format: Format,
+    previous_download: &PreviousDownload,
+) -> std::io::Result<u64> {
     // Start appending at end of file before last `END_HTML`.
-    if let Some(offset) = previous_download.rfind("</body>") {
-        f.seek(std::io::SeekFrom::Start(offset.try_into().unwrap()))
+    match previous_download.resume_offset(format) {
+        Some(offset) => {
+            f.seek(std::io::SeekFrom::Start(offset.try_into().unwrap()))
+                .await
+        }
+        None => Ok(0),
+    }
+}
+
+/// Start of the table of contents in html outputs.
+const TOC_START: &str = r#"<nav id="toc">"#;
+const TOC_END: &str = "</nav>";
+
+/// Html table of contents linking to each chapter heading in the html output `content`.
+///
+/// Headings are read at their offsets saved in `meta` if it has every chapter of `content`, otherwise the whole
+/// output is parsed to find them. Headings without an id (e.g. written by older versions) are listed without a link.
+fn toc_html(
+    selectors: &Selectors,
+    content: &[u8],
+    encoding: &'static Encoding,
+    meta: Option<&OutputMeta>,
+) -> String {
+    let mut toc = format!("{TOC_START}<h2>Contents</h2><ol>");
+    let mut push = |html: &Html| {
+        for heading in html.select(&selectors.downloaded_chapters) {
+            let title = escape_text(&heading.text().collect::<String>());
+            match heading.attr("id") {
+                Some(id) => {
+                    toc += &format!(
+                        r##"<li><a href="#{}">{title}</a></li>"##,
+                        escape_attribute(id)
+                    )
+                }
+                None => toc += &format!("<li>{title}</li>"),
+            }
+        }
+    };
+    match meta.and_then(|x| x.headings(content)) {
+        Some(headings) => {
+            for heading in headings {
+                push(&Html::parse_fragment(&encoding.decode(heading).0));
+            }
+        }
+        None => push(&Html::parse_document(&encoding.decode(content).0)),
+    }
+    toc + "</ol>" + TOC_END
+}
+
+/// Put a table of contents of the chapters in the html output `content` before its first chapter, replacing any
+/// previous one.
+///
+/// `meta` has the offsets of the chapters in `content` if it was saved. Returns how far content after the table of
+/// contents moved, or `None` if there are no chapters to list.
+fn insert_toc(
+    content: &mut Vec<u8>,
+    selectors: &Selectors,
+    encoding: &'static Encoding,
+    meta: Option<&OutputMeta>,
+) -> Option<i64> {
+    let find = |content: &[u8], needle: &str, from: usize| {
+        content[from..]
+            .windows(needle.len())
+            .position(|x| x == needle.as_bytes())
+            .map(|x| x + from)
+    };
+    let range = match find(content, TOC_START, 0) {
+        Some(start) => match find(content, TOC_END, start) {
+            Some(end) => start..end + TOC_END.len(),
+            None => return None,
+        },
+        None => match find(content, r#"<h1><a class="chapter""#, 0) {
+            Some(start) => start..start,
+            // Nothing to list.
+            None => return None,
+        },
+    };
+    let toc = toc_html(selectors, content, encoding, meta);
+    let (toc, _, _) = encoding.encode(&toc);
+    let shift = i64::try_from(toc.len()).unwrap() - i64::try_from(range.len()).unwrap();
+    content.splice(range, toc.iter().copied());
+    Some(shift)
+}
+
+/// Write a table of contents of the chapters in the html output at `path` with [`insert_toc`].
+///
+/// The whole file is rewritten since the table of contents grows as chapters are appended.
+/// Returns how far content after the table of contents moved.
+async fn write_toc(
+    path: &Path,
+    selectors: &Selectors,
+    encoding: &'static Encoding,
+    meta: Option<&OutputMeta>,
+) -> std::io::Result<i64> {
+    let mut output = read_previous_download(path).await?;
+    let Some(shift) = insert_toc(&mut output.content, selectors, encoding, meta) else {
+        return Ok(0);
+    };
+    output.write(path).await?;
+    Ok(shift)
+}
+
+/// File content is written to.
+enum OutputFile {
+    Plain(File),
+    /// Compressed output can't seek so `END_HTML` is only written on shutdown.
+    Gzip(Box<GzipEncoder<File>>),
+}
+
+/// How content is written to an output file.
+#[derive(Debug, Clone, Copy)]
+struct OutputFormat {
+    kind: Format,
+    encoding: &'static Encoding,
+    /// Columns text is wrapped to. `0` indicates no wrapping.
+    wrap: usize,
+}
+
+impl OutputFormat {
+    /// Chapter html as written to outputs of this format.
+    fn render_chapter(&self, chapter_html: &str) -> String {
+        match self.kind {
+            Format::Markdown => html_to_markdown(chapter_html),
+            Format::Text => html_to_text(chapter_html, self.wrap),
+            Format::Html | Format::Epub => chapter_html.to_owned(),
+        }
+    }
+}
+
+/// Output file which writes content in the output format.
+struct Output {
+    f: OutputFile,
+    format: OutputFormat,
+    /// If the file already has chapters which new chapters are separated from.
+    has_chapters: bool,
+    /// Bytes written before compression.
+    written: u64,
+    /// Offset in the content before compression where the next write goes.
+    offset: u64,
+    /// Temporary file written instead of the file at the second path, which it replaces once shut down.
+    replacing: Option<(PathBuf, PathBuf)>,
+}
+/// Content at the start of new output files.
+#[derive(Debug, Clone, Copy)]
+struct Header<'a> {
+    main_title: &'a str,
+    /// Source of the cover image of html outputs: a `data:` uri or a path relative to the output.
+    cover: Option<&'a str>,
+    /// Author, description, and such written to html outputs.
+    metadata: Option<&'a FictionMetadata>,
+    /// CSS embedded in the `<head>` of html outputs.
+    style: Option<&'a str>,
+}
+
+impl Output {
+    /// Create a new output file at `path` starting with the file headers.
+    async fn create(
+        path: &Path,
+        format: OutputFormat,
+        compressed: bool,
+        header: Header<'_>,
+    ) -> std::io::Result<Self> {
+        let f = File::create(path).await?;
+        let mut output = Output {
+            f: if compressed {
+                OutputFile::Gzip(Box::new(GzipEncoder::new(f)))
+            } else {
+                OutputFile::Plain(f)
+            },
+            format,
+            has_chapters: false,
+            written: 0,
+            offset: 0,
+            replacing: None,
+        };
+        output.write_header(header).await?;
+        Ok(output)
+    }
+    /// Write already encoded `bytes`.
+    async fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.written += bytes.len() as u64;
+        self.offset += bytes.len() as u64;
+        match &mut self.f {
+            OutputFile::Plain(f) => f.write_all(bytes).await,
+            OutputFile::Gzip(f) => f.write_all(bytes).await,
+        }
+    }
+    /// Write `s` encoding characters not representable in the output encoding as html entities.
+    async fn write_str(&mut self, s: &str) -> std::io::Result<()> {
+        let (bytes, _, _) = self.format.encoding.encode(s);
+        self.write_bytes(&bytes).await
+    }
+    /// Write title, file headers, and cover.
+    async fn write_header(&mut self, header: Header<'_>) -> std::io::Result<()> {
+        if self.format.kind != Format::Html {
+            return Ok(());
+        }
+        let mut meta = String::new();
+        let mut info = String::new();
+        if let Some(metadata) = header.metadata {
+            if let Some(author) = &metadata.author {
+                meta += &format!(
+                    r#"<meta name="author" content="{}">"#,
+                    escape_attribute(author)
+                );
+                info += &format!(r#"<p class="author">by {}</p>"#, escape_text(author));
+            }
+            if let Some(description) = &metadata.description {
+                meta += &format!(
+                    r#"<meta name="description" content="{}">"#,
+                    escape_attribute(&description.replace('\n', " "))
+                );
+            }
+            if !metadata.tags.is_empty() {
+                let tags = metadata.tags.join(", ");
+                meta += &format!(
+                    r#"<meta name="keywords" content="{}">"#,
+                    escape_attribute(&tags)
+                );
+                info += &format!(r#"<p class="tags">{}</p>"#, escape_text(&tags));
+            }
+            if let Some(status) = &metadata.status {
+                info += &format!(r#"<p class="status">{}</p>"#, escape_text(status));
+            }
+            if let Some(description) = &metadata.description {
+                info += r#"<div class="description">"#;
+                for paragraph in description.lines() {
+                    info += &format!("<p>{}</p>", escape_text(paragraph));
+                }
+                info += "</div>";
+            }
+        }
+        if let Some(css) = header.style {
+            // `</` would end the element early. `\/` is an escaped `/` in CSS.
+            meta += &format!("<style>{}</style>", css.replace("</", "<\\/"));
+        }
+        self.write_str(&format!(
+            r#"<html><head><meta charset="{}"><title>{}</title>{meta}</head><body>"#,
+            self.format.encoding.name(),
+            escape_text(header.main_title)
+        ))
+        .await?;
+        if let Some(cover) = header.cover {
+            self.write_str(&format!(
+                r#"<img class="cover" src="{}" alt="Cover">"#,
+                escape_attribute(cover)
+            ))
             .await?;
+        }
+        if !info.is_empty() {
+            self.write_str(&format!(r#"<div class="fiction-info">{info}</div>"#))
+                .await?;
+        }
+        Ok(())
+    }
+    /// Write chapter content and end with `END_HTML` in case of ctrl-c.
+    ///
+    /// Returns the offset the chapter starts at.
+    async fn write_chapter(&mut self, chapter_content: &str) -> std::io::Result<u64> {
+        let has_chapters = std::mem::replace(&mut self.has_chapters, true);
+        if matches!(self.format.kind, Format::Markdown | Format::Text) {
+            if has_chapters {
+                self.write_str(self.format.kind.separator()).await?;
+            }
+            let start = self.offset;
+            self.write_str(&self.format.render_chapter(chapter_content))
+                .await?;
+            if let OutputFile::Gzip(f) = &mut self.f {
+                f.flush().await?;
+            }
+            return Ok(start);
+        }
+        let start = self.offset;
+        self.write_str(chapter_content).await?;
+        match &mut self.f {
+            OutputFile::Plain(f) => {
+                f.write_all(END_HTML.as_bytes()).await?;
+                // Seek before `END_HTML` so it is overwritten on next chapter content
+                f.seek(std::io::SeekFrom::Current(
+                    -i64::try_from(END_HTML.len()).unwrap(),
+                ))
+                .await?;
+            }
+            // Flush so the chapter can be recovered if the stream is never finished.
+            OutputFile::Gzip(f) => f.flush().await?,
+        }
+        Ok(start)
+    }
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        match &mut self.f {
+            OutputFile::Plain(f) => f.shutdown().await?,
+            OutputFile::Gzip(f) => {
+                if self.format.kind == Format::Html {
+                    f.write_all(END_HTML.as_bytes()).await?;
+                }
+                f.shutdown().await?
+            }
+        }
+        if let Some((temp_path, path)) = self.replacing.take() {
+            tokio::fs::rename(temp_path, path).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Ask before overwriting `path` if it exists and back it up first.
+async fn confirm_overwrite(path: &Path, assume_yes: bool) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if !ask(
+        format!("Path ({}) already exists. Overwrite it?", path.display()),
+        assume_yes,
+        || std::io::stdin().lock(),
+    )
+    .await?
+    {
+        anyhow::bail!("Path ({}) already exists. Move the item at the path or pass `--incremental` to use it as previous chapter cache.", path.display());
+    }
+    backup(path, assume_yes, || std::io::stdin().lock()).await?;
+    Ok(())
+}
+
+/// Path of volume `volume` (from 1) of the output at `path` with `--volume-size` (e.g. `Title - Vol 01.html`).
+fn volume_path(path: &Path, volume: usize) -> PathBuf {
+    // Compressed outputs are numbered before the extension of their format.
+    if path.extension().is_some_and(|x| x == "gz") {
+        let mut path = volume_path(&path.with_extension(""), volume).into_os_string();
+        path.push(".gz");
+        return path.into();
     }
-    Ok(cached_chapters)
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem} - Vol {volume:02}.{}", extension.to_string_lossy()),
+        None => format!("{stem} - Vol {volume:02}"),
+    };
+    path.with_file_name(name)
 }
 
-/// Get final content for chapter from `chapter_response`.
+/// End `output` at `path`, list its chapters in its table of contents if it is html, and save its `meta`.
 ///
-/// May use `chapter_progress_msg` when logging.
-async fn chapter_response_to_content(
-    chapter_progress_msg: &str,
-    chapter_response: reqwest::Response,
-    main_title: &str,
-) -> Result<String, Error> {
-    let url = chapter_response.url().to_owned();
-    let mut chapter_html = Html::parse_document(&chapter_response.text().await?);
-
-    let mut out = String::new();
-
-    // Write chapter title.
-    let chapter_title = chapter_html
-        .select(selectors::title())
-        .map(|x| x.inner_html())
-        .next()
-        .ok_or(PageLayoutError::ChapterTitle)?;
-    let chapter_title = chapter_title
-        .strip_suffix(&main_title)
-        .and_then(|x| x.strip_suffix(" - "))
-        .unwrap_or(&chapter_title);
-    out.push_str(&format!(
-        r#"<h1><a class="chapter" href="{}">{}</a></h1>"#,
-        url, chapter_title
-    ));
-
-    // Remove bad paragraphs.
-    let bad_paragraphs = chapter_html
-        .select(selectors::warning_paragraphs())
-        .map(|x| {
-            println!("Removing {}: {} ", chapter_progress_msg, x.inner_html());
-            x.id()
+/// Returns the number of bytes written to it.
+async fn finish_output(
+    mut output: Output,
+    path: &Path,
+    selectors: &Selectors,
+    mut meta: Option<&mut OutputMeta>,
+) -> anyhow::Result<u64> {
+    if let Some(meta) = &mut meta {
+        meta.end = Some(output.offset);
+    }
+    output.shutdown().await?;
+    if output.format.kind == Format::Html {
+        let shift = write_toc(path, selectors, output.format.encoding, meta.as_deref()).await?;
+        if let Some(meta) = &mut meta {
+            meta.shift(shift);
+        }
+    }
+    if let Some(meta) = &meta {
+        meta.save(path).await?;
+    }
+    Ok(output.written)
+}
+
+/// Ask the user `question` with [`confirm`], reading the answer from the `input` it returns on the blocking thread
+/// pool so waiting for it doesn't hold up the runtime.
+async fn ask<R: BufRead>(
+    question: String,
+    assume_yes: bool,
+    input: impl FnOnce() -> R + Send + 'static,
+) -> std::io::Result<bool> {
+    tokio::task::spawn_blocking(move || confirm(&question, assume_yes, input())).await?
+}
+
+/// Ask the user `question`. Answers yes without reading `input` if `assume_yes`.
+fn confirm(question: &str, assume_yes: bool, mut input: impl BufRead) -> std::io::Result<bool> {
+    print!("{question} [y/N] ");
+    if assume_yes {
+        println!("y");
+        return Ok(true);
+    }
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes" | "YES"))
+}
+
+/// Copy `path` to a backup next to it before it is overwritten.
+///
+/// Asks before replacing an existing backup.
+async fn backup<R: BufRead>(
+    path: &Path,
+    assume_yes: bool,
+    input: impl FnOnce() -> R + Send + 'static,
+) -> std::io::Result<()> {
+    let backup_path = PathBuf::from({
+        let mut out = path.to_owned().into_os_string();
+        out.push(".bk");
+        out
+    });
+    if backup_path.exists()
+        && !ask(
+            format!(
+                "Backup ({}) already exists. Overwrite it?",
+                backup_path.display()
+            ),
+            assume_yes,
+            input,
+        )
+        .await?
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Backup ({}) already exists.", backup_path.display()),
+        ));
+    }
+    warn!(
+        "{} {}",
+        "Overwriting file after backing up to".yellow(),
+        backup_path.display()
+    );
+    tokio::fs::copy(path, &backup_path).await?;
+    Ok(())
+}
+
+/// Open the output file at `path` ready to write new chapters after any cached chapters.
+///
+/// - `previous_download` is the existing content at `path` for incremental downloads.
+/// - A new file is gzip compressed if `compress` or the file it replaces was.
+/// - Returns `None` without touching the file if an incremental download has no new chapters.
+#[allow(clippy::too_many_arguments)]
+async fn open_output(
+    path: &Path,
+    format: OutputFormat,
+    previous_download: Option<&PreviousDownload>,
+    compress: bool,
+    has_cached_chapters: bool,
+    new_chapters: usize,
+    header: Header<'_>,
+    assume_yes: bool,
+) -> std::io::Result<Option<Output>> {
+    let Some(previous_download) = previous_download else {
+        return Ok(Some(Output::create(path, format, compress, header).await?));
+    };
+
+    if has_cached_chapters {
+        if new_chapters == 0 {
+            return Ok(None);
+        }
+        if previous_download.compressed {
+            // Compressed content can't be appended to in place so rewrite it up to where new content goes.
+            // It is rewritten next to the file which is only replaced once finished so it isn't lost if the download fails.
+            let content = &previous_download.content;
+            let temp_path = temp_path(path);
+            let mut output = Output {
+                f: OutputFile::Gzip(Box::new(GzipEncoder::new(File::create(&temp_path).await?))),
+                format,
+                has_chapters: true,
+                written: 0,
+                offset: 0,
+                replacing: Some((temp_path, path.to_owned())),
+            };
+            output
+                .write_bytes(
+                    &content[..previous_download
+                        .resume_offset(format.kind)
+                        .unwrap_or(content.len())],
+                )
+                .await?;
+            Ok(Some(output))
+        } else {
+            let mut f = File::options().write(true).open(path).await?;
+            let offset = start_incremental_append(&mut f, format.kind, previous_download).await?;
+            Ok(Some(Output {
+                f: OutputFile::Plain(f),
+                format,
+                has_chapters: true,
+                written: 0,
+                offset,
+                replacing: None,
+            }))
+        }
+    } else {
+        // Will be replacing file so backup first.
+        warn!("{}", "Couldn't find a previous chapter URL.".yellow());
+        backup(path, assume_yes, || std::io::stdin().lock()).await?;
+        Ok(Some(
+            Output::create(
+                path,
+                format,
+                compress || previous_download.compressed,
+                header,
+            )
+            .await?,
+        ))
+    }
+}
+
+/// Parse the name of an encoding to write output with.
+fn parse_encoding(label: String) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes())
+        // Some encodings (e.g. UTF-16) can only be decoded so use what they would be written as.
+        .map(Encoding::output_encoding)
+        .ok_or_else(|| format!("unknown encoding: {label}"))
+}
+
+/// Value which shouldn't be shown (e.g. credentials) so is hidden from its `Debug` output.
+#[derive(Clone)]
+struct Redacted<T>(T);
+impl<T> std::fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Parse `name=value` cookies from a `Cookie` header value or Netscape `cookies.txt` file.
+fn parse_cookies(cookies: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    for line in cookies.lines().map(str::trim) {
+        // `#HttpOnly_` marks a cookie line in `cookies.txt` while other `#` lines are comments.
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // domain, include subdomains, path, secure, expiry, name, value
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [_, _, _, _, _, name, value] => out.push(format!("{name}={value}")),
+            _ => out.extend(
+                line.split(';')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_owned),
+            ),
+        }
+    }
+    out
+}
+
+/// Parse a CSS selector given as an option.
+fn parse_selector(css: String) -> Result<Selector, SelectorError> {
+    selectors::parse(&css)
+}
+
+/// Parse the date given to `--since`.
+fn parse_since(since: String) -> Result<OffsetDateTime, String> {
+    since_date(&since, OffsetDateTime::now_utc())
+}
+
+/// Start of `since`, either a `YYYY-MM-DD` date (UTC) or a time before `now` (e.g. `12h`, `7d`, `2w`).
+fn since_date(since: &str, now: OffsetDateTime) -> Result<OffsetDateTime, String> {
+    if let Ok(date) = time::Date::parse(since, &Iso8601::DATE) {
+        return Ok(date.midnight().assume_utc());
+    }
+    let ago = since.strip_suffix(['h', 'd', 'w']).and_then(|n| {
+        let n = n.parse::<u16>().ok()?;
+        Some(match since.chars().last()? {
+            'h' => time::Duration::hours(n.into()),
+            'd' => time::Duration::days(n.into()),
+            _ => time::Duration::weeks(n.into()),
         })
-        .collect::<Vec<_>>();
-    for id in bad_paragraphs {
-        chapter_html.tree.get_mut(id).unwrap().detach();
+    });
+    ago.and_then(|x| now.checked_sub(x))
+        .ok_or_else(|| format!("invalid date {since:?}: expected YYYY-MM-DD or a time ago like 7d"))
+}
+
+/// Parse the url of a proxy to send requests through.
+fn parse_proxy(url: String) -> Result<Proxy, String> {
+    Proxy::all(&url).map_err(|e| format!("invalid proxy {url:?}: {e}"))
+}
+
+/// Name of the config file looked up in the current directory and the platform config directory.
+const CONFIG_FILE_NAME: &str = "royalroad-dl.toml";
+
+/// Defaults of [`Options`] read from a config file. Keys are the names of the options' fields (e.g. `time_limit`).
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    time_limit: Option<u64>,
+    jitter: Option<u64>,
+    burst: Option<usize>,
+    connections: Option<usize>,
+    volume_size: Option<usize>,
+    max_bandwidth: Option<usize>,
+    timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    timeout_per_chapter: Option<u64>,
+    retries: Option<usize>,
+    user_agent: Option<String>,
+    proxy: Option<String>,
+    no_compression: Option<bool>,
+    notify: Option<String>,
+    cookie_file: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    format: Option<String>,
+    output_encoding: Option<String>,
+    wrap: Option<usize>,
+    gzip: Option<bool>,
+    author_notes: Option<String>,
+    style: Option<String>,
+    title_suffix: Option<String>,
+    theme: Option<String>,
+    incremental: Option<bool>,
+    assume_yes: Option<bool>,
+    no_color: Option<bool>,
+    no_progress: Option<bool>,
+    quiet: Option<bool>,
+    confirm_age: Option<bool>,
+    no_cover: Option<bool>,
+    embed_images: Option<bool>,
+    no_sanitize: Option<bool>,
+    strip_styles: Option<bool>,
+    no_strip_title: Option<bool>,
+    min_chars: Option<usize>,
+    skip_empty: Option<bool>,
+    keep_going: Option<bool>,
+}
+
+impl Config {
+    /// Read the config file at `path`, or else the first of [`CONFIG_FILE_NAME`] in the current directory and the
+    /// platform config directory that exists. No config file is an empty config unless `path` is given.
+    fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => {
+                let found = [Some(PathBuf::new()), dirs::config_dir()]
+                    .into_iter()
+                    .flatten()
+                    .map(|x| x.join(CONFIG_FILE_NAME))
+                    .find(|x| x.is_file());
+                match found {
+                    Some(path) => path,
+                    None => return Ok(Self::default()),
+                }
+            }
+        };
+        let config = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| Ok(toml::from_str(&x)?))
+            .map_err(|e| e.context(format!("failed to read config file {}", path.display())))?;
+        tracing::debug!("Read config file {}", path.display());
+        Ok(config)
+    }
+    /// Arguments setting the config's options, except those already given in the command line `args`.
+    fn args(&self, args: &[OsString]) -> Vec<OsString> {
+        let serde_json::Value::Object(fields) =
+            serde_json::to_value(self).expect("config serializes")
+        else {
+            unreachable!("config serializes to an object")
+        };
+        // Arguments after `--` are positional.
+        let args = args
+            .iter()
+            .take_while(|x| *x != "--")
+            .filter_map(|x| x.to_str())
+            .collect::<Vec<_>>();
+        let given = |long: &str| {
+            let short = match long {
+                "--time-limit" => Some('t'),
+                "--connections" => Some('c'),
+                "--incremental" => Some('i'),
+                "--assume-yes" => Some('y'),
+                "--quiet" => Some('q'),
+                _ => None,
+            };
+            args.iter().any(|x| {
+                if let Some(x) = x.strip_prefix(long) {
+                    return x.is_empty() || x.starts_with('=');
+                }
+                // Short flags can be combined (e.g. `-iy`) and followed by a short option with its value (e.g. `-it2000`).
+                match (short, x.strip_prefix('-')) {
+                    (Some(short), Some(x)) if !x.starts_with('-') => {
+                        for c in x.chars() {
+                            if c == short {
+                                return true;
+                            }
+                            if !matches!(c, 'i' | 'y' | 'q' | 'v') {
+                                break;
+                            }
+                        }
+                        false
+                    }
+                    _ => false,
+                }
+            })
+        };
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let long = format!("--{}", key.replace('_', "-"));
+                if given(&long) {
+                    return None;
+                }
+                match value {
+                    serde_json::Value::Bool(true) => Some(long),
+                    serde_json::Value::String(x) => Some(format!("{long}={x}")),
+                    serde_json::Value::Number(x) => Some(format!("{long}={x}")),
+                    _ => None,
+                }
+            })
+            .map(OsString::from)
+            .collect()
     }
+}
 
-    let chapter_content = chapter_html
-        .select(selectors::chapter_content())
-        .map(|x| x.html())
-        .next()
-        .ok_or(PageLayoutError::ChapterBody)?;
-    out.push_str(&chapter_content);
+/// The config file given by `--config` in the command line `args`, if any.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().take_while(|x| *x != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|x| x.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
 
-    Ok(out)
+/// Command line arguments with those set by the config file before them.
+/// Options given on the command line override the config file's.
+fn args_with_config() -> anyhow::Result<Vec<OsString>> {
+    let mut args = std::env::args_os().skip(1).collect::<Vec<_>>();
+    // The config only has download options.
+    if args.iter().any(|x| x == "--repair" || x == "--dedupe")
+        || args.first().is_some_and(|x| x == "info" || x == "convert")
+    {
+        return Ok(args);
+    }
+    // Options of the `download` command follow it.
+    let command = match args.first().is_some_and(|x| x == "download") {
+        true => 1,
+        false => 0,
+    };
+    let config = Config::load(config_path(&args).as_deref())?;
+    let config_args = config.args(&args);
+    args.splice(command..command, config_args);
+    Ok(args)
 }
 
 /// Incremental periodic downloader for RoyalRoad.
 #[derive(Debug, Clone, bpaf::Bpaf)]
 #[bpaf(options, version)]
+enum Cli {
+    Repair {
+        /// Repair an output left corrupted by interrupted or older downloads and exit.
+        #[bpaf(long("repair"), argument("PATH"))]
+        path: PathBuf,
+        /// Replace an existing backup without asking.
+        #[bpaf(short('y'), long)]
+        assume_yes: bool,
+    },
+    Dedupe {
+        /// Remove chapters written more than once in an html output, put its chapters in table of contents order,
+        /// and exit. Doesn't download anything.
+        #[bpaf(long("dedupe"), argument("PATH"))]
+        path: PathBuf,
+        /// Replace an existing backup without asking.
+        #[bpaf(short('y'), long)]
+        assume_yes: bool,
+    },
+    /// Print the title, author, chapter count, status, tags, and description of a fiction without downloading it.
+    #[bpaf(command)]
+    Info {
+        /// Confirm being old enough to view age restricted fictions.
+        /// How the site confirms age wasn't verified so this may not work.
+        #[bpaf(long)]
+        confirm_age: bool,
+        /// `User-Agent` header sent with requests.
+        #[bpaf(long, argument("UA"), fallback(USER_AGENT.to_owned()), display_fallback)]
+        user_agent: String,
+        /// The main page of the fiction.
+        #[bpaf(positional("URL"))]
+        url: Url,
+    },
+    /// Convert an html output of a previous download to EPUB without downloading anything.
+    #[bpaf(command)]
+    Convert {
+        /// Overwrite OUTPUT if it exists without asking.
+        #[bpaf(short('y'), long)]
+        assume_yes: bool,
+        /// Html output to convert, which can be gzip compressed.
+        #[bpaf(positional("INPUT"))]
+        input: PathBuf,
+        /// Path of the EPUB. Defaults to INPUT with the `epub` extension.
+        #[bpaf(positional("OUTPUT"), optional)]
+        output: Option<PathBuf>,
+    },
+    /// Download fictions, the same as without a command.
+    #[bpaf(command("download"))]
+    DownloadCommand(#[bpaf(external(options), map(Box::new))] Box<Options>),
+    Download(#[bpaf(external(options), map(Box::new))] Box<Options>),
+}
+
+#[derive(Debug, Clone, bpaf::Bpaf)]
 struct Options {
     /// Custom output path.
     #[bpaf(short, long, argument("PATH"))]
@@ -163,168 +1177,4426 @@ struct Options {
     /// Minimum ms per request. Can't be zero.
     #[bpaf(short, long, argument("MS"), fallback(NonZeroU64::new(1500).unwrap()), display_fallback)]
     time_limit: NonZeroU64,
-    /// Concurrent connections limit. Zero indicates no limit.
+    /// Most random ms waited on top of `--time-limit` before each request so they aren't perfectly regular.
+    #[bpaf(long, argument("MS"), fallback(300), display_fallback)]
+    jitter: u64,
+    /// Most requests made at once, at the start and after a pause, before waiting for `--time-limit`. Can't be zero.
+    /// Higher bursts download the first chapters faster but are less friendly to the server and more likely to be
+    /// rate limited.
+    #[bpaf(long, argument("N"), fallback(NonZeroUsize::MIN), display_fallback)]
+    burst: NonZeroUsize,
+    /// Concurrent connections limit. Zero indicates no limit other than the rate limit, capped at 64.
     #[bpaf(short, long, argument("NUM"), fallback(4), display_fallback)]
     connections: usize,
+    /// Most bytes per second read from chapter pages. The `--connections` downloading at once share it, so each reads
+    /// a part of it. Compressed pages count their decompressed size so less is downloaded.
+    #[bpaf(long, argument("BYTES"), optional)]
+    max_bandwidth: Option<NonZeroUsize>,
+    /// Limit in seconds for each request. Zero indicates no limit.
+    #[bpaf(long, argument("SECONDS"), fallback(30), display_fallback)]
+    timeout: u64,
+    /// Limit in seconds for connecting to the server. Zero indicates no limit.
+    #[bpaf(long, argument("SECONDS"), fallback(10), display_fallback)]
+    connect_timeout: u64,
+    /// Limit in seconds for downloading and processing each chapter.
+    #[bpaf(long, argument("SECONDS"))]
+    timeout_per_chapter: Option<NonZeroU64>,
+    /// Times to retry a chapter after connection errors, timeouts, and server errors.
+    #[bpaf(long, argument("NUM"), fallback(3), display_fallback)]
+    retries: usize,
     /// Incremental download. Auto-detect previously downloaded and only download new.
     #[bpaf(short, long)]
     incremental: bool,
-    /// The main page (e.g. table of contents) of the content to download.
-    #[bpaf(positional("URL"))]
-    url: Url,
+    /// With `--incremental`, also download cached chapters again and rewrite those that were edited.
+    /// Conditional requests use the validators saved in the `.meta.json` file next to the output.
+    /// The output is backed up to `<output>.bk` before it is rewritten.
+    #[bpaf(long)]
+    refresh: bool,
+    /// Save the raw page of each downloaded chapter in DIR and read chapters from there instead of downloading them
+    /// again, e.g. to try other selectors. `--refresh` downloads chapters instead of reading them.
+    #[bpaf(long, argument("DIR"))]
+    cache_dir: Option<PathBuf>,
+    /// With `--cache-dir`, download every chapter and replace its cached page instead of reading it.
+    #[bpaf(long)]
+    no_cache: bool,
+    /// Save the fiction as a directory (PATH if given) with `index.html`, `metadata.json`, and `images/` holding the
+    /// cover and, with `--embed-images`, the images of chapters, which are linked instead of embedded. Only for html.
+    #[bpaf(long)]
+    bundle_dir: bool,
+    /// Save each chapter to its own file in a directory (PATH if given) with an `index.html` linking them.
+    #[bpaf(long)]
+    split: bool,
+    /// Split the output into volumes of at most N chapters named like `Title - Vol 01.html`, each with its own header
+    /// and table of contents. `--incremental` adds chapters to the last volume and starts a new one when it is full.
+    #[bpaf(long, argument("N"), optional)]
+    volume_size: Option<NonZeroUsize>,
+    /// Gzip compress the output, adding `.gz` to its name unless PATH is given. Incremental downloads read compressed
+    /// outputs and keep them compressed whether or not this is given.
+    #[bpaf(long)]
+    gzip: bool,
+    /// Write chapters last to first, after `--between`, `--from`, `--to`, and `--since` select them.
+    /// Chapters are numbered in the reversed order. Can't be used with `--incremental` since new chapters are appended.
+    #[bpaf(long)]
+    reverse: bool,
+    /// Answer yes to prompts: overwrite an existing output without `--incremental` and replace an existing backup.
+    /// Overwritten outputs are always backed up first.
+    #[bpaf(short('y'), long)]
+    assume_yes: bool,
+    /// Don't color output. Color is also disabled when not writing to a terminal or `NO_COLOR` is set.
+    #[bpaf(long)]
+    no_color: bool,
+    /// Print a line per chapter instead of showing a progress bar. Always the case when not writing to a terminal.
+    #[bpaf(long)]
+    no_progress: bool,
+    /// Log more. Once adds chapter urls and removed warning paragraphs, twice adds everything.
+    #[bpaf(short('v'), long("verbose"), req_flag(()), count)]
+    verbose: usize,
+    /// Only log errors.
+    #[bpaf(short, long)]
+    quiet: bool,
+    /// Confirm being old enough to view age restricted fictions.
+    /// How the site confirms age wasn't verified, so if this doesn't work pass the cookies of a browser that
+    /// confirmed with `--cookie`.
+    #[bpaf(long)]
+    confirm_age: bool,
+    /// Don't download the cover image to embed in html and EPUB outputs.
+    #[bpaf(long)]
+    no_cover: bool,
+    /// Keep the warning paragraphs hidden in chapters instead of removing them.
+    #[bpaf(long)]
+    keep_warnings: bool,
+    /// Remove elements matching the CSS selector from chapters instead of the default warning paragraphs.
+    #[bpaf(long, argument::<String>("CSS"), parse(parse_selector), optional)]
+    warning_selector: Option<Selector>,
+    /// What is done with author notes before, after, and inside chapters: `keep` keeps those inside chapters as
+    /// they are, `separate` wraps every note in an `<aside class="author-note">` in the chapter, and `drop` removes them.
+    #[bpaf(long, argument("MODE"), fallback(AuthorNotes::Keep), display_fallback)]
+    author_notes: AuthorNotes,
+    /// Download chapter images and embed them in the output so they don't depend on their host. With `--bundle-dir`
+    /// they are saved to its `images/` instead.
+    #[bpaf(long)]
+    embed_images: bool,
+    /// Keep scripts, frames, and event handler attributes in chapters instead of removing them so they don't run when
+    /// the output is opened in a browser.
+    #[bpaf(long)]
+    no_sanitize: bool,
+    /// Remove the `style`, `class`, and `id` attributes of elements in chapters, leaving plain semantic html.
+    /// Warning paragraphs are removed before so they don't need their hiding styles.
+    #[bpaf(long)]
+    strip_styles: bool,
+    /// Keep the whole titles of pages: the site's suffix (e.g. ` | Royal Road`) isn't removed from the fiction's title
+    /// in file names and EPUB metadata, nor ` - ` and the fiction's title from chapter titles.
+    #[bpaf(long)]
+    no_strip_title: bool,
+    /// Suffix removed from the fiction's title instead of the site's (e.g. ` | Royal Road`), for mirrors or when the
+    /// site changes its titles. Chapter titles still have ` - ` and the fiction's title removed.
+    #[bpaf(long, argument("SUFFIX"))]
+    title_suffix: Option<String>,
+    /// Warn about chapters with fewer than N characters of text, not counting whitespace, such as announcements and
+    /// paywall stubs.
+    #[bpaf(long, argument("N"), fallback(20), display_fallback)]
+    min_chars: usize,
+    /// Skip chapters with fewer than `--min-chars` characters of text instead of writing them.
+    /// With `--incremental` they are downloaded again by the next run.
+    #[bpaf(long)]
+    skip_empty: bool,
+    /// Skip chapters, and fictions when downloading several, that fail to download instead of stopping.
+    /// Exits with an error at the end if any failed.
+    #[bpaf(long)]
+    keep_going: bool,
+    /// Print the end of run summary as a single JSON line instead.
+    #[bpaf(long)]
+    summary_json: bool,
+    /// Print the number of words in each downloaded chapter and their total, min, max, and mean.
+    /// The totals are included in `--summary-json`.
+    #[bpaf(long)]
+    stats: bool,
+    /// Print the chapters that would be downloaded and exit without downloading them or touching the output.
+    /// With `--incremental` chapters are marked as cached or new.
+    #[bpaf(long)]
+    list: bool,
+    /// With `--incremental`, print the number of new chapters and exit without downloading them or touching the output.
+    /// Exits with code 0 if there are none and 3 if there are some.
+    #[bpaf(long)]
+    check: bool,
+    /// Treat each URL as a follows/reading list page and download every fiction listed on it.
+    /// Implies `--incremental` so only new chapters of each fiction are downloaded.
+    #[bpaf(long)]
+    follows: bool,
+    /// Cookie sent with requests to the site of URL (e.g. "name=value; name2=value2").
+    #[bpaf(long, argument::<String>("COOKIE"), map(Redacted), optional)]
+    cookie: Option<Redacted<String>>,
+    /// Read cookies sent with requests to the site of URL from PATH.
+    /// Either a `Cookie` header value or a Netscape `cookies.txt` export.
+    #[bpaf(long, argument("PATH"))]
+    cookie_file: Option<PathBuf>,
+    /// `User-Agent` header sent with requests.
+    #[bpaf(long, argument("UA"), fallback(USER_AGENT.to_owned()), display_fallback)]
+    user_agent: String,
+    /// Send requests through the HTTP or SOCKS5 proxy at URL (e.g. "socks5h://127.0.0.1:9050").
+    /// Defaults to the proxy given by the `HTTP_PROXY`/`HTTPS_PROXY`/`ALL_PROXY` environment variables, if any.
+    #[bpaf(long, argument::<String>("URL"), parse(parse_proxy), optional)]
+    proxy: Option<Proxy>,
+    #[bpaf(external, optional)]
+    between: Option<Between>,
+    /// Only download chapters starting at the N-th (1-based) in the table of contents.
+    #[bpaf(long, argument("N"))]
+    from: Option<NonZeroUsize>,
+    /// Only download chapters up to the N-th (1-based) in the table of contents. Clamped to the last chapter.
+    #[bpaf(long, argument("N"))]
+    to: Option<NonZeroUsize>,
+    /// Only download chapters published on or after DATE, either `YYYY-MM-DD` (UTC) or a time ago like `7d`.
+    /// Units are `h`, `d`, and `w`. Chapters without a date are downloaded.
+    #[bpaf(long, argument::<String>("DATE"), parse(parse_since), optional)]
+    since: Option<OffsetDateTime>,
+    #[bpaf(external, optional)]
+    http_version: Option<HttpVersion>,
+    /// Don't ask for gzip or brotli compressed responses, which use less bandwidth.
+    #[bpaf(long)]
+    no_compression: bool,
+    #[bpaf(external, optional)]
+    content_whitespace: Option<ContentWhitespace>,
+    /// Character encoding of the output. Characters it can't represent are written as html entities.
+    /// [default: UTF-8]
+    #[bpaf(
+        long,
+        argument::<String>("CHARSET"),
+        parse(parse_encoding),
+        fallback(encoding_rs::UTF_8)
+    )]
+    output_encoding: &'static Encoding,
+    /// Output format: `html`, `epub`, `markdown`, or `text`. EPUB can't be used with `--incremental`.
+    #[bpaf(long, argument("FORMAT"), fallback(Format::Html), display_fallback)]
+    format: Format,
+    /// Hard wrap paragraphs of `--format text` to COLS columns. Zero indicates no wrapping.
+    #[bpaf(long, argument("COLS"), fallback(0), display_fallback)]
+    wrap: usize,
+    /// Stylesheet embedded in html outputs: `default`, `none`, or the path of a CSS file.
+    /// Incremental downloads keep the stylesheet the output was created with.
+    #[bpaf(long, argument("STYLE"), fallback(Style::Default), display_fallback)]
+    style: Style,
+    /// Write an RSS feed of the chapters in the output to PATH, rewritten on every run so incremental downloads add
+    /// their new chapters. Can't be used with `--follows` or several URLs.
+    #[bpaf(long, argument("PATH"))]
+    feed: Option<PathBuf>,
+    /// POST a JSON notification of new chapters to the webhook at URL when a download adds any.
+    /// It has the fiction's `title` and `url`, the `count` of new chapters, and their titles as `chapters`.
+    #[bpaf(long, argument("URL"))]
+    notify: Option<Url>,
+    /// Color theme added to the `--style` of html outputs: `dark` follows the reader's system preference for a dark
+    /// color scheme. Can't be used with a CSS file given to `--style`.
+    #[bpaf(long, argument("THEME"))]
+    theme: Option<Theme>,
+    /// Read default options from the TOML config file at PATH. Options given on the command line override it.
+    /// Defaults to `royalroad-dl.toml` in the current directory, or else in the platform config directory.
+    /// Its keys are option names with `_` instead of `-` (e.g. `time_limit = 2000`, `embed_images = true`).
+    #[bpaf(long, argument("PATH"))]
+    #[allow(dead_code)] // Read before parsing by `config_path`.
+    config: Option<PathBuf>,
+    /// Also download the fictions listed in the file at PATH, or stdin if `-`, after the URLs given as arguments.
+    /// One URL per line. Blank lines and lines starting with `#` are ignored, as are URLs given more than once.
+    #[bpaf(long, argument("PATH"))]
+    url_file: Option<PathBuf>,
+    /// The main pages (e.g. table of contents) of the fictions to download, downloaded in order.
+    #[bpaf(positional("URL"), many)]
+    urls: Vec<Url>,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    // Parse cli options.
-    let opt = options().run();
-
-    let client = reqwest::Client::builder().build().unwrap();
-
-    // Get main document.
-    let main_html = Html::parse_document(&client.get(opt.url.clone()).send().await?.text().await?);
-
-    // Extract title.
-    let main_title = main_html
-        .select(selectors::title())
-        .map(|x| x.inner_html())
-        .next()
-        .ok_or(PageLayoutError::MainTitle)?;
-
-    // Start output file. Either create new or reuse previous if incremental download.
-    let path = opt.path.unwrap_or(PathBuf::from(format!(
-        "{}.html",
-        sanitize_path(
+impl Options {
+    /// Title of a fiction on `site` from the title of its main page, without its suffix unless `--no-strip-title`.
+    fn fiction_title<'a>(&self, site: &Site, title: &'a str) -> &'a str {
+        match &self.title_suffix {
+            _ if self.no_strip_title => title,
+            Some(suffix) => title.strip_suffix(suffix.as_str()).unwrap_or(title),
+            None => site.strip_title_suffix(title),
+        }
+    }
+    /// Title of the fiction stripped from the end of chapter titles, which is empty with `--no-strip-title` so they
+    /// are kept whole.
+    fn chapter_title_suffix<'a>(&self, main_title: &'a str) -> &'a str {
+        if self.no_strip_title {
+            ""
+        } else {
             main_title
-                .strip_suffix(" | Royal Road")
-                .unwrap_or(&main_title)
-        )
-    )));
-    println!("Saving to {}", path.display());
-    let incremental = opt.incremental && path.exists();
-    if !opt.incremental && path.exists() {
-        anyhow::bail!("Path ({}) already exists. Move the item at the path or pass `--incremental` to use it as previous chapter cache.", path.display());
+        }
     }
-    let mut f = if incremental {
-        File::options().read(true).write(true).open(&path).await?
-    } else {
-        File::create(&path).await?
-    };
-
-    // Get previously downloaded chapters as applicable.
-    let cached_chapters = if incremental {
-        let cached_chapters = start_incremental_append(&mut f).await?;
-        if cached_chapters.is_empty() {
-            // Will be replacing file so backup first.
-            let backup_path = {
-                let mut out = path.clone().into_os_string();
-                out.push(".bk");
-                out
-            };
-            println!(
-                "Couldn't find a previous chapter URL.\nOverwriting file after backing up to {}",
-                std::path::Path::new(&backup_path).display()
-            );
-            tokio::fs::copy(&path, &backup_path).await?;
+    /// What is done with chapter content after it is downloaded.
+    fn processing(&self) -> Processing {
+        Processing {
+            whitespace: match self.content_whitespace {
+                Some(ContentWhitespace::Pretty) => Whitespace::Pretty,
+                Some(ContentWhitespace::Minify) => Whitespace::Minify,
+                None => Whitespace::Keep,
+            },
+            author_notes: self.author_notes,
+            sanitize: !self.no_sanitize,
+            styles: if self.strip_styles {
+                Styles::Strip
+            } else {
+                Styles::Keep
+            },
         }
-        cached_chapters
-    } else {
-        Vec::new()
-    };
-
-    // If no known chapter to resume from
-    if cached_chapters.is_empty() {
-        // Start writing file from beginning.
-        f.seek(std::io::SeekFrom::Start(0)).await?;
-        // Write title and file headers.
-        f.write_all(
-            format!(
-                r#"<html><head><meta charset="UTF-8"><title>{}</title></head><body>"#,
-                main_title
-            )
-            .as_bytes(),
-        )
-        .await?;
     }
+    /// Options for downloading each fiction.
+    fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            connections: self.connections,
+            time_limit: Duration::from_millis(self.time_limit.get()),
+            jitter: Duration::from_millis(self.jitter),
+            burst: self.burst,
+            timeout_per_chapter: self
+                .timeout_per_chapter
+                .map(|x| Duration::from_secs(x.get())),
+            confirm_age: self.confirm_age,
+            embed_images: self.embed_images,
+            image_dir: None,
+            retries: self.retries,
+            print_progress: !self.progress_bar(),
+            processing: self.processing(),
+            cache: self
+                .cache_dir
+                .as_ref()
+                .map(|dir| ResponseCache::new(dir, !self.no_cache && !self.refresh)),
+            max_bandwidth: self.max_bandwidth,
+        }
+    }
+    /// If only the table of contents is read, without downloading chapters or touching the output.
+    fn dry_run(&self) -> bool {
+        self.list || self.check
+    }
+    /// If progress is shown with a progress bar instead of a line per chapter.
+    fn progress_bar(&self) -> bool {
+        !self.no_progress && !self.quiet && std::io::stdout().is_terminal()
+    }
+    /// Most detailed level logged.
+    fn log_level(&self) -> Level {
+        match (self.quiet, self.verbose) {
+            (true, _) => Level::ERROR,
+            (false, 0) => Level::INFO,
+            (false, 1) => Level::DEBUG,
+            (false, _) => Level::TRACE,
+        }
+    }
+}
 
-    // Get chapters with a rate limit.
-    let limiter = Arc::new(
-        RateLimiter::builder()
-            .initial(1)
-            .interval(Duration::from_millis(opt.time_limit.get()))
-            .build(),
+/// Progress bar of downloading `len` chapters.
+fn progress_bar(len: usize) -> ProgressBar {
+    let bar = ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stdout());
+    bar.set_style(
+        ProgressStyle::with_template("[{bar:40}] {pos}/{len} ({prefix} buffered) {wide_msg}")
+            .unwrap()
+            .progress_chars("=> "),
     );
-    let (chapters_len, chapter_responses) = {
-        let mut chapters = main_html
-            .select(selectors::chapter_links()) // table of chapters
-            .map(|x| x.attr("data-url").expect("data-url attribute in selector")) // url for table entry
-            .map(|x| opt.url.join(x).unwrap().into()) // absolute url from relative url
-            .enumerate()
-            .collect::<Vec<_>>();
-        if chapters.is_empty() {
-            return Err(Error::Layout(PageLayoutError::ChapterLinks).into());
-        }
+    bar
+}
 
-        let chapters_len = chapters.len();
+/// Chapters that failed to download, which are skipped with `--keep-going`.
+///
+/// Chapters that were removed are always skipped.
+struct Failures {
+    keep_going: bool,
+    chapters_len: usize,
+    /// Index and url of each failed chapter.
+    failed: Vec<(usize, Url)>,
+    /// Index and url of each removed chapter.
+    missing: Vec<(usize, Url)>,
+    /// Chapters with fewer characters of text are warned about as nearly empty.
+    min_chars: usize,
+    /// Skip nearly empty chapters instead of only warning about them.
+    skip_empty: bool,
+    /// Index and url of each nearly empty chapter skipped.
+    empty: Vec<(usize, Url)>,
+}
+impl Failures {
+    fn new(keep_going: bool, chapters_len: usize) -> Self {
+        Self {
+            keep_going,
+            chapters_len,
+            failed: Vec::new(),
+            missing: Vec::new(),
+            min_chars: 0,
+            skip_empty: false,
+            empty: Vec::new(),
+        }
+    }
+    /// Warn about chapters with fewer than `min_chars` characters of text, and skip them if `skip_empty`.
+    fn empty_chapters(mut self, min_chars: usize, skip_empty: bool) -> Self {
+        self.min_chars = min_chars;
+        self.skip_empty = skip_empty;
+        self
+    }
 
-        if incremental {
-            // Don't download chapters already downloaded.
-            chapters.retain(|(_, x)| !cached_chapters.contains(x));
+    /// Get the downloaded chapter or record its failure if it was removed, nearly empty and skipped, or keeping going.
+    fn check(
+        &mut self,
+        (i, url): (usize, Url),
+        chapter: Result<Chapter, royalroad_dl::Error>,
+    ) -> anyhow::Result<Option<Chapter>> {
+        match chapter {
+            Ok(chapter) if chapter.text_len() < self.min_chars => {
+                warn!(
+                    "{} {}: {url}: {} has {} characters of text",
+                    if self.skip_empty {
+                        "Skipping"
+                    } else {
+                        "Nearly empty"
+                    }
+                    .yellow(),
+                    format!("{}/{}", i + 1, self.chapters_len).cyan(),
+                    chapter.title,
+                    chapter.text_len()
+                );
+                if !self.skip_empty {
+                    return Ok(Some(chapter));
+                }
+                self.empty.push((i, url));
+                Ok(None)
+            }
+            Ok(chapter) => Ok(Some(chapter)),
+            Err(e) if e.is_missing() => {
+                warn!(
+                    "{} {}: {url}: {}",
+                    "Skipping".yellow(),
+                    format!("{}/{}", i + 1, self.chapters_len).cyan(),
+                    e.inner()
+                );
+                self.missing.push((i, url));
+                Ok(None)
+            }
+            Err(e) if self.keep_going => {
+                error!(
+                    "{} {}: {url}: {}",
+                    "Failed".red(),
+                    format!("{}/{}", i + 1, self.chapters_len).cyan(),
+                    e.inner()
+                );
+                self.failed.push((i, url));
+                Ok(None)
+            }
+            Err(e) => Err(e.in_chapter(i, &url).into()),
         }
+    }
+}
 
-        // GET urls and Buffer tasks for concurrency.
-        let chapter_responses = BufferedIter::new(
-            chapters.into_iter().map(move |(i, url)| {
-                let limiter = limiter.clone();
-                let client = client.clone();
-                tokio::spawn(async move {
-                    limiter.acquire_one().await;
-                    println!("Downloading {}/{}: {}", i + 1, chapters_len, url.0);
-                    (i, client.get(url.0).send().await)
-                })
-            }),
-            opt.connections,
-        );
-        (chapters_len, chapter_responses)
-    };
-    // Save each chapter to file.
-    for handle in chapter_responses {
-        let (i, chapter_response) = handle.await?;
+/// Counts of what happened to the chapters of a fiction in a run.
+#[derive(Debug, serde::Serialize)]
+struct Summary<'a> {
+    url: &'a str,
+    /// Chapters in the table of contents.
+    found: usize,
+    downloaded: usize,
+    /// Chapters already in the incremental output.
+    cached: usize,
+    /// Cached chapters rewritten by `--refresh`.
+    refreshed: usize,
+    /// Chapters outside of `--between`, `--from`, `--to`, and `--since`.
+    skipped: usize,
+    /// Chapters that were removed (404 or 410).
+    missing: usize,
+    /// Nearly empty chapters skipped by `--skip-empty`.
+    empty: usize,
+    failed: usize,
+    elapsed_secs: f64,
+    bytes_written: u64,
+    /// Word counts of the downloaded chapters with `--stats`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<WordTotals>,
+}
+impl Summary<'_> {
+    /// Print to stderr as text or a single JSON line, unless logging is below info level.
+    fn print(&self, json: bool) -> anyhow::Result<()> {
+        if !tracing::enabled!(Level::INFO) {
+            return Ok(());
+        }
+        if json {
+            eprintln!("{}", serde_json::to_string(self)?);
+        } else {
+            eprintln!(
+                "{} chapters found: {} downloaded, {} cached ({} refreshed), {} skipped, {} missing, {} empty, {} failed. Wrote {} bytes in {:.1}s.",
+                self.found,
+                self.downloaded,
+                self.cached,
+                self.refreshed,
+                self.skipped,
+                self.missing,
+                self.empty,
+                self.failed,
+                self.bytes_written,
+                self.elapsed_secs
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Word counts of the chapters downloaded in a run for `--stats`.
+#[derive(Debug, Default)]
+struct WordStats {
+    /// Index and title of each chapter with its word count.
+    chapters: Vec<(usize, String, usize)>,
+}
+/// Totals of [`WordStats`] included in the summary.
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct WordTotals {
+    total: usize,
+    min: usize,
+    max: usize,
+    mean: f64,
+}
+impl WordStats {
+    fn add(&mut self, chapter: &Chapter) {
+        self.chapters
+            .push((chapter.index, chapter.title.clone(), chapter.word_count()));
+    }
+    /// Totals over all chapters, if any were added.
+    fn totals(&self) -> Option<WordTotals> {
+        let words = self.chapters.iter().map(|(_, _, words)| *words);
+        Some(WordTotals {
+            total: words.clone().sum(),
+            min: words.clone().min()?,
+            max: words.clone().max()?,
+            mean: words.sum::<usize>() as f64 / self.chapters.len() as f64,
+        })
+    }
+    /// Print a table of chapters and their word counts to stderr followed by the totals, unless logging is below info
+    /// level.
+    fn print(&self, len: usize) {
+        let Some(totals) = self.totals() else {
+            return;
+        };
+        if !tracing::enabled!(Level::INFO) {
+            return;
+        }
+        let width = len.to_string().len();
+        let words_width = totals.total.to_string().len();
+        for (i, title, words) in &self.chapters {
+            eprintln!(
+                "{} {words:>words_width$} {title}",
+                format!("{:>width$}", i + 1).cyan()
+            );
+        }
+        eprintln!(
+            "{} words in {} chapters: min {}, max {}, mean {:.0}",
+            totals.total,
+            self.chapters.len(),
+            totals.min,
+            totals.max,
+            totals.mean
+        );
+    }
+}
+
+/// Metadata saved next to the output (`<output>.meta.json`) to resume incremental downloads and for `--refresh`.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct OutputMeta {
+    #[serde(default)]
+    title: Option<String>,
+    /// Offset in the uncompressed output after the last chapter, where new chapters are written.
+    #[serde(default)]
+    end: Option<u64>,
+    chapters: Vec<ChapterMeta>,
+}
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct ChapterMeta {
+    url: String,
+    /// Index in the table of contents when downloaded.
+    #[serde(default)]
+    index: Option<usize>,
+    /// Offset of the chapter heading in the uncompressed output.
+    #[serde(default)]
+    offset: Option<u64>,
+    #[serde(flatten)]
+    validators: Validators,
+}
+impl OutputMeta {
+    /// Path of the metadata of the output at `output`.
+    fn path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".meta.json");
+        path.into()
+    }
+    /// Read the metadata of the output at `output`, if it was saved.
+    async fn open(output: &Path) -> anyhow::Result<Self> {
+        match tokio::fs::read(Self::path(output)).await {
+            Ok(x) => Ok(serde_json::from_slice(&x)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn save(&self, output: &Path) -> anyhow::Result<()> {
+        tokio::fs::write(Self::path(output), serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+    fn get(&self, url: &ChapterUrl) -> Option<&ChapterMeta> {
+        self.chapters
+            .iter()
+            .find(|x| Url::parse(&x.url).is_ok_and(|x| ChapterUrl(x) == *url))
+    }
+    fn get_mut(&mut self, url: &ChapterUrl) -> Option<&mut ChapterMeta> {
+        self.chapters
+            .iter_mut()
+            .find(|x| Url::parse(&x.url).is_ok_and(|x| ChapterUrl(x) == *url))
+    }
+    /// Move the saved offsets by `shift` bytes after content before every chapter changed length.
+    fn shift(&mut self, shift: i64) {
+        for offset in self
+            .chapters
+            .iter_mut()
+            .map(|x| &mut x.offset)
+            .chain([&mut self.end])
+            .flatten()
+        {
+            *offset = offset.saturating_add_signed(shift);
+        }
+    }
+    /// Record a downloaded chapter written at `offset`, or where it was before if `None`.
+    fn set(&mut self, chapter: &Chapter, offset: Option<u64>) {
+        let mut meta = ChapterMeta {
+            url: chapter.url.to_string(),
+            index: Some(chapter.index),
+            offset,
+            validators: chapter.validators.clone(),
+        };
+        match self.get_mut(&ChapterUrl(chapter.url.clone())) {
+            Some(x) => {
+                meta.offset = meta.offset.or(x.offset);
+                *x = meta;
+            }
+            None => self.chapters.push(meta),
+        }
+    }
+    /// Urls of the saved chapters if the metadata is consistent with `previous_download`.
+    fn cached_chapters(&self, previous_download: &PreviousDownload) -> Option<Vec<ChapterUrl>> {
+        previous_download.saved_offset?;
+        self.chapters
+            .iter()
+            .map(|x| Url::parse(&x.url).ok().map(ChapterUrl))
+            .collect()
+    }
+    /// Saved end of the chapters if it is consistent with `previous_download` (e.g. the output wasn't edited since).
+    fn resume_offset(&self, format: Format, previous_download: &[u8]) -> Option<usize> {
+        let end = usize::try_from(self.end?).ok()?;
+        let rest = previous_download.get(end..)?;
+        let consistent = match format {
+            Format::Html => rest.starts_with(b"</body>"),
+            Format::Markdown | Format::Text | Format::Epub => rest.is_empty(),
+        };
+        consistent.then_some(end)
+    }
+    /// Html of each chapter heading in the html output `content` read at the saved offsets, if they are consistent
+    /// with it and every heading in it has one.
+    fn headings<'a>(&self, content: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+        const HEADING: &[u8] = br#"<h1><a class="chapter" href=""#;
+        let mut chapters = self
+            .chapters
+            .iter()
+            .map(|x| Some((usize::try_from(x.offset?).ok()?, x.url.as_bytes())))
+            .collect::<Option<Vec<_>>>()?;
+        chapters.sort_unstable();
+        let count = content
+            .windows(HEADING.len())
+            .filter(|x| *x == HEADING)
+            .count();
+        if count != chapters.len() || chapters.windows(2).any(|x| x[0].0 == x[1].0) {
+            return None;
+        }
+        chapters
+            .into_iter()
+            .map(|(offset, url)| {
+                let heading = content.get(offset..)?;
+                heading.strip_prefix(HEADING)?.strip_prefix(url)?;
+                let end = heading.windows("</h1>".len()).position(|x| x == b"</h1>")?;
+                Some(&heading[..end + "</h1>".len()])
+            })
+            .collect()
+    }
+    /// Find the offsets of chapters and their end again in the `previous_download` after it was rewritten.
+    fn locate(&mut self, format: OutputFormat, previous_download: &str) {
+        let encoded_len = |x: &str| format.encoding.encode(x).0.len() as u64;
+        let (mut offset, mut last) = (0, 0);
+        for (url, range) in chapter_sections(format.kind, previous_download) {
+            offset += encoded_len(&previous_download[last..range.start]);
+            last = range.start;
+            if let Some(x) = self.get_mut(&url) {
+                x.offset = Some(offset);
+            }
+        }
+        let end = resume_offset(format.kind, previous_download.as_bytes())
+            .unwrap_or(previous_download.len());
+        self.end = Some(offset + encoded_len(&previous_download[last..end]));
+    }
+}
+
+/// Download `chapters` again unless the server reports them unchanged since saved in `meta`.
+///
+/// Chapters failing with a transient error are retried up to `retries` times like [`download_chapters`], and rate
+/// limiting slows down later requests with a [`Throttle`].
+/// Chapters that can't be downloaded, such as removed chapters, are skipped since their cached copy is kept.
+#[allow(clippy::too_many_arguments)]
+async fn refresh_chapters(
+    client: &reqwest::Client,
+    selectors: &Selectors,
+    limiter: &Limiter,
+    chapters: Vec<(usize, ChapterUrl)>,
+    chapters_len: usize,
+    main_title: &str,
+    processing: Processing,
+    retries: usize,
+    meta: &OutputMeta,
+) -> Vec<Chapter> {
+    let throttle = Throttle::default();
+    let mut out = Vec::new();
+    for (i, url) in chapters {
+        let chapter_progress_msg = format!("{}/{chapters_len}", i + 1);
+        let validators = meta
+            .get(&url)
+            .map(|x| x.validators.clone())
+            .unwrap_or_default();
+        let mut attempt = 0;
+        let refreshed = loop {
+            throttle.acquire(limiter).await;
+            log_progress("Refreshing", &chapter_progress_msg, &url.0);
+            let refreshed = refresh_chapter(
+                client,
+                selectors,
+                i,
+                url.0.clone(),
+                &chapter_progress_msg,
+                main_title,
+                processing,
+                &validators,
+            )
+            .await;
+            let e = match refreshed {
+                Err(e) if attempt < retries && e.is_transient() => e,
+                refreshed => break refreshed,
+            };
+            let wait = match e.inner() {
+                Error::RateLimited { retry_after } => {
+                    throttle.slow_down();
+                    *retry_after
+                }
+                _ => None,
+            };
+            attempt += 1;
+            let backoff = wait.unwrap_or(RETRY_BACKOFF * 2u32.pow(attempt as u32 - 1));
+            warn!(
+                "{} {} in {backoff:?} (attempt {}/{}): {}",
+                "Retrying".yellow(),
+                chapter_progress_msg.cyan(),
+                attempt + 1,
+                retries + 1,
+                url.0
+            );
+            tokio::time::sleep(backoff).await;
+        };
+        if refreshed.is_ok() {
+            throttle.speed_up();
+        }
+        match refreshed {
+            Ok(Some(chapter)) => out.push(chapter),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "{} {}: {}: {}",
+                "Couldn't refresh".yellow(),
+                chapter_progress_msg.cyan(),
+                url.0,
+                e.inner()
+            ),
+        }
+    }
+    out
+}
+
+/// Replace the sections of `chapters` in `previous_download` which differ from how they would be written now.
+///
+/// Returns the new content and the number of chapters replaced.
+fn replace_chapters(
+    format: OutputFormat,
+    previous_download: &str,
+    chapters: &[Chapter],
+) -> (String, usize) {
+    let mut replacements = chapter_sections(format.kind, previous_download)
+        .into_iter()
+        .filter_map(|(url, range)| {
+            let chapter = chapters.iter().find(|x| ChapterUrl(x.url.clone()) == url)?;
+            // Round trip through the encoding so unrepresentable characters compare as written.
+            let rendered = format.render_chapter(&chapter.to_html());
+            let rendered = format
+                .encoding
+                .decode(&format.encoding.encode(&rendered).0)
+                .0
+                .into_owned();
+            (previous_download[range.clone()] != rendered).then_some((range, rendered))
+        })
+        .collect::<Vec<_>>();
+    let replaced = replacements.len();
+    let mut content = previous_download.to_owned();
+    // Replace from the end so earlier ranges stay valid.
+    replacements.sort_by_key(|x| std::cmp::Reverse(x.0.start));
+    for (range, rendered) in replacements {
+        content.replace_range(range, &rendered);
+    }
+    (content, replaced)
+}
+
+/// Metadata about a fiction saved in its bundle directory.
+#[derive(Debug, serde::Serialize)]
+struct Metadata<'a> {
+    title: &'a str,
+    url: &'a str,
+    chapters: usize,
+    #[serde(flatten)]
+    details: &'a FictionMetadata,
+}
+
+/// Name of the file chapter `index` of `len` chapters titled `title` is saved to with `--split`.
+///
+/// The 1-based index is zero-padded to the width of `len` so the files sort in order.
+fn split_file_name(index: usize, len: usize, title: &str, format: Format) -> String {
+    let width = len.to_string().len();
+    sanitize_path(&format!(
+        "{:0width$} - {title}.{}",
+        index + 1,
+        format.extension()
+    ))
+    .into_owned()
+}
+
+/// Index at the start of a file named by [`split_file_name`].
+fn split_file_index(name: &str) -> Option<usize> {
+    name.split_once(" - ")?.0.parse().ok()
+}
+
+/// Chapter files previously saved with `--split` to `dir` and the url of the chapter in each, in order.
+async fn split_files(
+    dir: &Path,
+    selectors: &Selectors,
+    format: Format,
+    encoding: &'static Encoding,
+) -> std::io::Result<Vec<(String, ChapterUrl)>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if split_file_index(&name).is_none() || !name.ends_with(&format!(".{}", format.extension()))
+        {
+            continue;
+        }
+        let content = read_previous_download(&entry.path()).await?.content;
+        if let Some(url) = cached_chapters(selectors, format, &encoding.decode(&content).0)
+            .into_iter()
+            .next()
+        {
+            files.push((name, url));
+        }
+    }
+    files.sort_by_key(|(name, _)| split_file_index(name));
+    Ok(files)
+}
+
+/// Write the `index.html` at `path` linking to each chapter file in `files`.
+async fn write_split_index(
+    path: &Path,
+    files: &[(String, ChapterUrl)],
+    header: Header<'_>,
+    encoding: &'static Encoding,
+) -> std::io::Result<u64> {
+    let format = OutputFormat {
+        kind: Format::Html,
+        encoding,
+        wrap: 0,
+    };
+    let mut output = Output::create(path, format, false, header).await?;
+    let mut toc = format!("{TOC_START}<h2>Contents</h2><ol>");
+    for (name, _) in files {
+        // Characters `sanitize_path` keeps which would end the path of a url.
+        let href = name
+            .replace('%', "%25")
+            .replace('#', "%23")
+            .replace(' ', "%20");
+        let title = name.rsplit_once('.').map_or(&**name, |x| x.0);
+        toc += &format!(
+            r#"<li><a href="{}">{}</a></li>"#,
+            escape_attribute(&href),
+            escape_text(title)
+        );
+    }
+    output
+        .write_str(&(toc + "</ol>" + TOC_END + END_HTML))
+        .await?;
+    output.shutdown().await?;
+    Ok(output.written)
+}
+
+// Inclusive range of chapters given by their urls.
+// Not a doc comment since bpaf would make it group help, which can't be adjacent.
+#[derive(Debug, Clone, bpaf::Bpaf)]
+#[bpaf(adjacent)]
+struct Between {
+    /// Only download chapters from START_URL to END_URL inclusive.
+    #[bpaf(long("between"))]
+    _between: (),
+    /// Url of the first chapter to download.
+    #[bpaf(positional("START_URL"))]
+    start: Url,
+    /// Url of the last chapter to download.
+    #[bpaf(positional("END_URL"))]
+    end: Url,
+}
+impl Between {
+    /// Indices of the chapters in the range.
+    fn range(&self, chapters: &[ChapterUrl]) -> anyhow::Result<RangeInclusive<usize>> {
+        let position = |url: &Url| {
+            let url = ChapterUrl(url.clone());
+            chapters.iter().position(|x| *x == url).ok_or_else(|| {
+                anyhow::anyhow!("Chapter ({}) not found in table of contents.", url.0)
+            })
+        };
+        let start = position(&self.start)?;
+        let end = position(&self.end)?;
+        if end < start {
+            anyhow::bail!(
+                "End chapter ({}) comes before start chapter ({}).",
+                self.end,
+                self.start
+            );
+        }
+        Ok(start..=end)
+    }
+}
+
+/// Remove chapters listed again later in the table of contents (e.g. bonus links), keeping the first of each.
+fn dedup_chapters(toc: &mut Vec<TocChapter>) {
+    let mut seen = HashSet::new();
+    toc.retain(|x| {
+        let first = seen.insert(x.url.clone());
+        if !first {
+            warn!("{} {}", "Skipping duplicate chapter:".yellow(), x.url.0);
+        }
+        first
+    });
+}
+
+/// Indices of the chapters from the `from`-th to the `to`-th (1-based) of `len`, inclusive.
+fn index_range(
+    from: Option<NonZeroUsize>,
+    to: Option<NonZeroUsize>,
+    len: usize,
+) -> anyhow::Result<RangeInclusive<usize>> {
+    let start = from.map_or(0, |x| x.get() - 1);
+    if start >= len {
+        anyhow::bail!(
+            "Can't start at chapter {} of a fiction with only {len} chapters.",
+            start + 1
+        );
+    }
+    let end = to.map_or(len, NonZeroUsize::get).min(len) - 1;
+    if end < start {
+        anyhow::bail!(
+            "`--to` ({}) comes before `--from` ({}).",
+            end + 1,
+            start + 1
+        );
+    }
+    Ok(start..=end)
+}
+
+/// Force the HTTP protocol version instead of negotiating with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bpaf::Bpaf)]
+enum HttpVersion {
+    /// Only use HTTP/1.
+    #[bpaf(long("http1-only"))]
+    Http1Only,
+    /// Use HTTP/2 without negotiating it first.
+    #[bpaf(long("http2"))]
+    Http2,
+}
+
+/// Rewrite the whitespace of chapter content instead of keeping the page's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bpaf::Bpaf)]
+enum ContentWhitespace {
+    /// Put block elements on their own indented lines so outputs of different runs diff well.
+    #[bpaf(long("pretty"))]
+    Pretty,
+    /// Collapse whitespace to shrink the output.
+    #[bpaf(long("minify"))]
+    Minify,
+}
+
+/// Stylesheet embedded in html outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Style {
+    /// [`DEFAULT_CSS`].
+    Default,
+    None,
+    /// CSS file.
+    Path(PathBuf),
+}
+impl std::str::FromStr for Style {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Style::Default,
+            "none" => Style::None,
+            _ => Style::Path(s.into()),
+        })
+    }
+}
+impl std::fmt::Display for Style {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Style::Default => f.write_str("default"),
+            Style::None => f.write_str("none"),
+            Style::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+impl Style {
+    /// CSS of the stylesheet, if any.
+    async fn css(&self) -> anyhow::Result<Option<Cow<'static, str>>> {
+        Ok(match self {
+            Style::Default => Some(DEFAULT_CSS.into()),
+            Style::None => None,
+            Style::Path(path) => Some(
+                tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("reading stylesheet {}: {e}", path.display()))?
+                    .into(),
+            ),
+        })
+    }
+}
+
+/// Color theme embedded in html outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Theme {
+    /// [`DARK_CSS`].
+    Dark,
+}
+impl std::str::FromStr for Theme {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("unknown theme: {s}. Expected `dark`")),
+        }
+    }
+}
+
+/// Format of the output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Html,
+    /// EPUB 3 with a file per chapter. Always UTF-8.
+    Epub,
+    /// Markdown with chapters separated by `---`.
+    Markdown,
+    /// Plain text with underlined chapter titles.
+    Text,
+}
+impl Format {
+    /// File extension of outputs in this format.
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Epub => "epub",
+            Format::Markdown => "md",
+            Format::Text => "txt",
+        }
+    }
+    /// Text between chapters of outputs in this format.
+    fn separator(self) -> &'static str {
+        match self {
+            Format::Markdown => "\n---\n\n",
+            Format::Text => "\n\n",
+            Format::Html | Format::Epub => "",
+        }
+    }
+}
+impl std::str::FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(Format::Html),
+            "epub" => Ok(Format::Epub),
+            "markdown" => Ok(Format::Markdown),
+            "text" => Ok(Format::Text),
+            _ => Err(format!(
+                "unknown format: {s}. Expected `html`, `epub`, `markdown`, or `text`"
+            )),
+        }
+    }
+}
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Html => "html",
+            Format::Epub => "epub",
+            Format::Markdown => "markdown",
+            Format::Text => "text",
+        })
+    }
+}
+
+/// Print the index, title, and url of `chapters` from the table of contents `toc`.
+///
+/// With `cached` each chapter is marked as cached or new.
+fn list_chapters(
+    toc: &[TocChapter],
+    chapters: &[(usize, ChapterUrl)],
+    cached: Option<&HashSet<ChapterUrl>>,
+) {
+    let width = toc.len().to_string().len();
+    for (i, url) in chapters {
+        let status = match cached {
+            Some(cached) if cached.contains(url) => format!(" {}", "cached".green()),
+            Some(_) => format!(" {}", "new".yellow()),
+            None => String::new(),
+        };
+        println!(
+            "{}{status} {} {}",
+            format!("{:>width$}", i + 1).cyan(),
+            toc[*i].title,
+            url.0
+        );
+    }
+}
+
+/// Set once ctrl-c is pressed so downloads stop after the chapter being written.
+#[derive(Debug, Clone)]
+struct Interrupt(watch::Receiver<bool>);
+impl Interrupt {
+    /// Handle ctrl-c by setting the interrupt. A second ctrl-c exits immediately.
+    fn install() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                // Never interrupted, so keep `tx` to not close the channel.
+                return std::future::pending().await;
+            }
+            warn!(
+                "{}",
+                "Interrupted. Finishing the current chapter. Press ctrl-c again to exit immediately."
+                    .yellow()
+            );
+            let _ = tx.send(true);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+        Self(rx)
+    }
+    fn is_set(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// Wait for `fut` unless interrupted first, in which case `None` is returned.
+    async fn or<T>(&self, fut: impl Future<Output = Option<T>>) -> Option<T> {
+        let mut rx = self.0.clone();
+        tokio::select! {
+            biased;
+            Ok(_) = rx.wait_for(|x| *x) => None,
+            x = fut => x,
+        }
+    }
+}
+
+/// Download the fiction with main page at `url`.
+///
+/// Returns the number of new chapters downloaded, or that would be downloaded with `--list` or `--check`.
+#[allow(clippy::too_many_arguments)]
+async fn download(
+    client: &reqwest::Client,
+    jar: &Jar,
+    site: &Site,
+    selectors: &Arc<Selectors>,
+    limiter: &Arc<Limiter>,
+    opt: &Options,
+    url: &Url,
+    interrupt: &Interrupt,
+) -> anyhow::Result<usize> {
+    let start = Instant::now();
+    let mut style = opt.style.css().await?;
+    if opt.theme == Some(Theme::Dark) {
+        style = Some(style.unwrap_or_default() + DARK_CSS);
+    }
+    // Get title, details, and chapters.
+    let FictionIndex {
+        title: main_title,
+        metadata,
+        cover_url,
+        mut toc,
+    } = fetch_fiction_index(client, jar, selectors, limiter, url, opt.confirm_age).await?;
+    dedup_chapters(&mut toc);
+
+    // Start output file. Either create new or reuse previous if incremental download.
+    let name = opt.fiction_title(site, &main_title);
+    let base_path = if opt.bundle_dir || opt.split {
+        let dir = opt
+            .path
+            .clone()
+            .unwrap_or(PathBuf::from(&*sanitize_path(name)));
+        if !opt.dry_run() {
+            tokio::fs::create_dir_all(if opt.bundle_dir {
+                dir.join("images")
+            } else {
+                dir.clone()
+            })
+            .await?;
+        }
+        // Split chapters are linked from an html index whatever their format and bundles are html.
+        dir.join("index.html")
+    } else {
+        // Sanitized with the extension so it is kept if the name is truncated.
+        opt.path
+            .clone()
+            .unwrap_or(PathBuf::from(&*sanitize_path(&format!(
+                "{name}.{}{}",
+                opt.format.extension(),
+                if opt.gzip { ".gz" } else { "" }
+            ))))
+    };
+    // Volumes are numbered from 1 and chapters are added to the last, the ones before it being full.
+    let mut volume = match opt.volume_size {
+        Some(_) if opt.incremental => (1..)
+            .find(|&x| !volume_path(&base_path, x + 1).exists())
+            .unwrap(),
+        _ => 1,
+    };
+    let mut path = match opt.volume_size {
+        Some(_) => volume_path(&base_path, volume),
+        None => base_path.clone(),
+    };
+    if !opt.dry_run() {
+        info!("Saving to {}", path.display());
+    }
+    // Split chapters can be saved before their index is so it's their directory that is reused.
+    let incremental = opt.incremental
+        && if opt.split {
+            path.parent().is_some_and(Path::exists)
+        } else {
+            path.exists()
+        };
+    if !opt.incremental && !opt.dry_run() {
+        confirm_overwrite(&path, opt.assume_yes).await?;
+    }
+
+    // Get previously downloaded chapters as applicable.
+    let mut previous_download = if incremental && !opt.split {
+        Some(read_previous_download(&path).await?)
+    } else {
+        None
+    };
+    // Metadata saved next to the output. Outputs which aren't incremental replace it.
+    let mut meta = match (opt.format, &mut previous_download) {
+        (Format::Epub, _) => None,
+        _ if opt.split => None,
+        (_, Some(previous_download)) => {
+            let meta = OutputMeta::open(&path).await?;
+            previous_download.saved_offset =
+                meta.resume_offset(opt.format, &previous_download.content);
+            Some(meta)
+        }
+        (_, None) => Some(OutputMeta::default()),
+    };
+    // The output is backed up before it is first rewritten, which keeps the backup of it as it was before this run.
+    let mut backed_up = false;
+    // Saved metadata is only consistent with outputs that were finished.
+    if let Some(previous_download) = previous_download
+        .as_mut()
+        .filter(|x| x.saved_offset.is_none() && opt.format != Format::Epub)
+    {
+        if let Some(url) =
+            drop_truncated_chapter(opt.format, opt.output_encoding, previous_download)
+        {
+            warn!(
+                "{} {}",
+                "Last chapter looks cut off by an interrupted download. Downloading it again:"
+                    .yellow(),
+                url.0
+            );
+            if !opt.dry_run() {
+                backup(&path, opt.assume_yes, || std::io::stdin().lock()).await?;
+                previous_download.write(&path).await?;
+                backed_up = true;
+            }
+        }
+    }
+    // Chapters saved with `--split` are found from their files.
+    let mut split_files = if opt.split && incremental {
+        split_files(
+            path.parent().unwrap_or(&path),
+            selectors,
+            opt.format,
+            opt.output_encoding,
+        )
+        .await?
+    } else {
+        Vec::new()
+    };
+    let mut cached_chapters = previous_download
+        .as_ref()
+        .map(|x| {
+            meta.as_ref()
+                .and_then(|meta| meta.cached_chapters(x))
+                .unwrap_or_else(|| {
+                    cached_chapters(
+                        selectors,
+                        opt.format,
+                        &opt.output_encoding.decode(&x.content).0,
+                    )
+                })
+        })
+        .unwrap_or_else(|| split_files.iter().map(|x| x.1.clone()).collect())
+        .into_iter()
+        .collect::<HashSet<_>>();
+    // An output without previous chapters is replaced so nothing saved about it applies.
+    if let (true, Some(meta)) = (cached_chapters.is_empty(), &mut meta) {
+        *meta = OutputMeta::default();
+    }
+    // Chapters in the volume written to, which starts a new one when full.
+    let mut in_volume = cached_chapters.len();
+    if incremental && opt.volume_size.is_some() {
+        for earlier in 1..volume {
+            let content = read_previous_download(&volume_path(&base_path, earlier))
+                .await?
+                .content;
+            cached_chapters.extend(crate::cached_chapters(
+                selectors,
+                opt.format,
+                &opt.output_encoding.decode(&content).0,
+            ));
+        }
+    }
+
+    let mut chapters = toc
+        .iter()
+        .map(|x| x.url.clone())
+        .enumerate()
+        .collect::<Vec<_>>();
+    let chapters_len = chapters.len();
+
+    if let Some(between) = &opt.between {
+        let range = between.range(&chapters.iter().map(|(_, x)| x.clone()).collect::<Vec<_>>())?;
+        chapters.retain(|(i, _)| range.contains(i));
+    }
+    let range = index_range(opt.from, opt.to, chapters_len)?;
+    chapters.retain(|(i, _)| range.contains(i));
+    if let Some(since) = opt.since {
+        let mut undated = 0;
+        chapters.retain(|(i, _)| match toc[*i].published {
+            Some(published) => published >= since,
+            None => {
+                undated += 1;
+                true
+            }
+        });
+        if undated > 0 {
+            warn!(
+                "{}",
+                format!("{undated} chapters have no publication date so are downloaded despite `--since`.")
+                    .yellow()
+            );
+        }
+    }
+    if opt.reverse {
+        // Number chapters from the last so progress, `--list`, and split file names follow the reversed order.
+        toc.reverse();
+        chapters.reverse();
+        for (i, _) in &mut chapters {
+            *i = chapters_len - 1 - *i;
+        }
+    }
+    let in_range = chapters.len();
+
+    if opt.dry_run() {
+        let new_chapters = chapters
+            .iter()
+            .filter(|(_, x)| !cached_chapters.contains(x))
+            .count();
+        if opt.list {
+            list_chapters(&toc, &chapters, opt.incremental.then_some(&cached_chapters));
+        }
+        if opt.check {
+            println!("{new_chapters} new chapters: {url}");
+        }
+        return Ok(new_chapters);
+    }
+
+    if let Some(meta) = &mut meta {
+        meta.title = Some(main_title.clone());
+    }
+    let mut refreshed = 0;
+    if let (true, Some(previous_download), Some(meta)) =
+        (opt.refresh, &mut previous_download, &mut meta)
+    {
+        let cached = chapters
+            .iter()
+            .filter(|(_, x)| cached_chapters.contains(x))
+            .cloned()
+            .collect();
+        let chapters = refresh_chapters(
+            client,
+            selectors,
+            limiter,
+            cached,
+            chapters_len,
+            opt.chapter_title_suffix(&main_title),
+            opt.processing(),
+            opt.retries,
+            meta,
+        )
+        .await;
+        let format = OutputFormat {
+            kind: opt.format,
+            encoding: opt.output_encoding,
+            wrap: opt.wrap,
+        };
+        let (content, replaced) = replace_chapters(
+            format,
+            &opt.output_encoding.decode(&previous_download.content).0,
+            &chapters,
+        );
+        for chapter in &chapters {
+            meta.set(chapter, None);
+        }
+        if replaced != 0 {
+            if !backed_up {
+                backup(&path, opt.assume_yes, || std::io::stdin().lock()).await?;
+            }
+            previous_download.content = opt.output_encoding.encode(&content).0.into_owned();
+            previous_download.write(&path).await?;
+            meta.locate(format, &content);
+            previous_download.saved_offset =
+                meta.resume_offset(opt.format, &previous_download.content);
+            info!(
+                "{}",
+                format!("Refreshed {replaced} edited chapters").green()
+            );
+        }
+        refreshed = replaced;
+    }
+
+    // Don't download chapters already downloaded.
+    chapters.retain(|(_, x)| !cached_chapters.contains(x));
+
+    let new_chapters = chapters.len();
+    let mut summary = Summary {
+        url: url.as_str(),
+        found: chapters_len,
+        downloaded: 0,
+        cached: in_range - new_chapters,
+        refreshed,
+        skipped: chapters_len - in_range,
+        missing: 0,
+        empty: 0,
+        failed: 0,
+        elapsed_secs: 0.,
+        bytes_written: 0,
+        words: None,
+    };
+    let chapter_urls = chapters
+        .iter()
+        .map(|(i, x)| (*i, x.0.clone()))
+        .collect::<Vec<_>>();
+    let mut chapter_urls = chapter_urls.into_iter();
+    let mut failures =
+        Failures::new(opt.keep_going, chapters_len).empty_chapters(opt.min_chars, opt.skip_empty);
+    let mut downloaded = 0;
+    let mut words = WordStats::default();
+    // Chapters in the output once the download is done, listed by `--feed`.
+    let mut in_output = cached_chapters.clone();
+    let progress = opt.progress_bar().then(|| progress_bar(new_chapters));
+    // Advance progress past a finished chapter.
+    let advance = |title: Option<&str>, buffered: usize| {
+        if let Some(progress) = &progress {
+            progress.set_prefix(buffered.to_string());
+            if let Some(title) = title {
+                progress.set_message(title.to_owned());
+            }
+            progress.inc(1);
+        }
+    };
+
+    // Only new files start with the cover. The index of split chapters is always rewritten.
+    let cover = if !opt.no_cover
+        && (opt.split
+            || matches!(opt.format, Format::Html | Format::Epub)
+                && (in_volume == 0 || opt.volume_size.is_some()))
+    {
+        match &cover_url {
+            Some(cover_url) => match fetch_image(client, limiter, cover_url).await {
+                Ok(cover) => Some(cover),
+                Err(e) => {
+                    warn!("{} {e}", "Couldn't download cover:".yellow());
+                    None
+                }
+            },
+            None => None,
+        }
+    } else {
+        None
+    };
+    // Bundles save images next to the output instead of embedding them.
+    let image_dir = opt.bundle_dir.then(|| base_path.with_file_name("images"));
+    let cover_src = match (&cover, &image_dir) {
+        (Some(cover), Some(image_dir)) => {
+            let name = format!("cover.{}", cover.extension());
+            tokio::fs::write(image_dir.join(&name), &cover.bytes).await?;
+            Some(format!("images/{name}"))
+        }
+        (Some(cover), None) => Some(cover.data_uri()),
+        (None, _) => None,
+    };
+
+    // GET urls and Buffer tasks for concurrency.
+    // Tasks start as soon as they are buffered, before the output file is opened.
+    let mut chapter_responses = download_chapters(
+        client,
+        selectors,
+        limiter,
+        chapters,
+        chapters_len,
+        opt.chapter_title_suffix(&main_title),
+        &DownloadOptions {
+            image_dir,
+            ..opt.download_options()
+        },
+    );
+
+    if opt.format == Format::Epub {
+        // EPUB is a zip archive so it is written once all chapters are downloaded.
+        let mut chapters = Vec::with_capacity(new_chapters);
+        while let Some(chapter) = interrupt.or(chapter_responses.next_result()).await {
+            let url = chapter_urls.next().expect("a result per chapter");
+            let chapter = failures.check(url, chapter?)?;
+            advance(chapter.as_ref().map(|x| &*x.title), chapter_responses.len());
+            if let Some(chapter) = chapter.as_ref().filter(|_| opt.stats) {
+                words.add(chapter);
+            }
+            chapters.extend(chapter);
+        }
+        if interrupt.is_set() {
+            anyhow::bail!("Interrupted before the EPUB was written.");
+        }
+        downloaded = chapters.len();
+        in_output.extend(chapters.iter().map(|x| ChapterUrl(x.url.clone())));
+        let volumes = match opt.volume_size {
+            Some(size) if !chapters.is_empty() => chapters.chunks(size.get()).collect(),
+            _ => vec![&chapters[..]],
+        };
+        for (i, chapters) in volumes.into_iter().enumerate() {
+            if i != 0 {
+                volume += 1;
+                path = volume_path(&base_path, volume);
+                confirm_overwrite(&path, opt.assume_yes).await?;
+            }
+            let mut epub = std::io::Cursor::new(Vec::new());
+            royalroad_dl::epub::write_epub(
+                &mut epub,
+                opt.fiction_title(site, &main_title),
+                url,
+                &metadata,
+                cover.as_ref(),
+                chapters,
+            )?;
+            let epub = epub.into_inner();
+            summary.bytes_written += epub.len() as u64;
+            tokio::fs::write(&path, epub).await?;
+        }
+    } else if opt.split {
+        let dir = path.parent().unwrap_or(&path);
+        let format = OutputFormat {
+            kind: opt.format,
+            encoding: opt.output_encoding,
+            wrap: opt.wrap,
+        };
+        // Save each chapter to its own file. Stops before the next chapter when interrupted.
+        while let Some(chapter) = interrupt.or(chapter_responses.next_result()).await {
+            let url = chapter_urls.next().expect("a result per chapter");
+            let chapter = failures.check(url, chapter?)?;
+            advance(chapter.as_ref().map(|x| &*x.title), chapter_responses.len());
+            if let Some(chapter) = chapter {
+                let name = split_file_name(chapter.index, chapters_len, &chapter.title, opt.format);
+                let header = Header {
+                    main_title: &chapter.title,
+                    cover: None,
+                    metadata: None,
+                    style: style.as_deref(),
+                };
+                let mut output = Output::create(&dir.join(&name), format, false, header).await?;
+                output.write_chapter(&chapter.to_html()).await?;
+                if opt.stats {
+                    words.add(&chapter);
+                }
+                output.shutdown().await?;
+                summary.bytes_written += output.written;
+                downloaded += 1;
+                in_output.insert(ChapterUrl(chapter.url.clone()));
+                split_files.push((name, ChapterUrl(chapter.url)));
+            }
+        }
+        if downloaded == 0 && incremental {
+            info!("{}", "No new chapters".green());
+        } else {
+            split_files.sort_by_key(|(name, _)| split_file_index(name));
+            let header = Header {
+                main_title: &main_title,
+                cover: cover_src.as_deref(),
+                metadata: Some(&metadata),
+                style: style.as_deref(),
+            };
+            summary.bytes_written +=
+                write_split_index(&path, &split_files, header, opt.output_encoding).await?;
+        }
+    } else {
+        let format = OutputFormat {
+            kind: opt.format,
+            encoding: opt.output_encoding,
+            wrap: opt.wrap,
+        };
+        let header = Header {
+            main_title: &main_title,
+            cover: cover_src.as_deref(),
+            metadata: Some(&metadata),
+            style: style.as_deref(),
+        };
+        let Some(mut output) = open_output(
+            &path,
+            format,
+            previous_download.as_ref(),
+            opt.gzip,
+            in_volume != 0,
+            new_chapters,
+            header,
+            opt.assume_yes,
+        )
+        .await?
+        else {
+            info!("{}", "No new chapters".green());
+            if let Some(meta) = &meta {
+                meta.save(&path).await?;
+            }
+            if let Some(feed) = &opt.feed {
+                write_feed(feed, name, url, &metadata, &toc, &in_output).await?;
+            }
+            summary.elapsed_secs = start.elapsed().as_secs_f64();
+            summary.print(opt.summary_json)?;
+            return Ok(0);
+        };
+
+        // Save each chapter to file. Stops before the next chapter when interrupted.
+        while let Some(chapter) = interrupt.or(chapter_responses.next_result()).await {
+            let url = chapter_urls.next().expect("a result per chapter");
+            let chapter = failures.check(url, chapter?)?;
+            advance(chapter.as_ref().map(|x| &*x.title), chapter_responses.len());
+            if let Some(chapter) = chapter {
+                if opt.volume_size.is_some_and(|x| in_volume >= x.get()) {
+                    summary.bytes_written +=
+                        finish_output(output, &path, selectors, meta.as_mut()).await?;
+                    volume += 1;
+                    path = volume_path(&base_path, volume);
+                    info!("Saving to {}", path.display());
+                    confirm_overwrite(&path, opt.assume_yes).await?;
+                    let compressed =
+                        opt.gzip || previous_download.as_ref().is_some_and(|x| x.compressed);
+                    output = Output::create(&path, format, compressed, header).await?;
+                    meta = meta.map(|_| OutputMeta::default());
+                    in_volume = 0;
+                }
+                let offset = output.write_chapter(&chapter.to_html()).await?;
+                in_volume += 1;
+                downloaded += 1;
+                in_output.insert(ChapterUrl(chapter.url.clone()));
+                if opt.stats {
+                    words.add(&chapter);
+                }
+                if let Some(meta) = &mut meta {
+                    meta.set(&chapter, Some(offset));
+                }
+            }
+        }
+
+        summary.bytes_written += finish_output(output, &path, selectors, meta.as_mut()).await?;
+    }
+    if let Some(progress) = &progress {
+        progress.finish_and_clear();
+    }
+    let failed = failures.failed.len();
+    info!(
+        "{}",
+        format!("Downloaded {downloaded} new chapters to {}", path.display()).green()
+    );
+    if interrupt.is_set() {
+        warn!(
+            "{}",
+            "Interrupted. Run again with `--incremental` to download the remaining chapters."
+                .yellow()
+        );
+    }
+    if failed != 0 {
+        error!("{}", format!("Failed to download {failed} chapters:").red());
+        for (i, url) in &failures.failed {
+            error!("  {}: {url}", format!("{}/{chapters_len}", i + 1).cyan());
+        }
+    }
+
+    if opt.bundle_dir {
+        let metadata = Metadata {
+            title: &main_title,
+            url: url.as_str(),
+            chapters: cached_chapters.len() + downloaded,
+            details: &metadata,
+        };
+        tokio::fs::write(
+            path.with_file_name("metadata.json"),
+            serde_json::to_vec_pretty(&metadata)?,
+        )
+        .await?;
+    }
+    if let Some(feed) = &opt.feed {
+        write_feed(feed, name, url, &metadata, &toc, &in_output).await?;
+    }
+    if let (Some(webhook), true) = (&opt.notify, downloaded != 0) {
+        let chapters = toc
+            .iter()
+            .filter(|x| in_output.contains(&x.url) && !cached_chapters.contains(&x.url))
+            .map(|x| x.title.as_str())
+            .collect();
+        let notification = Notification {
+            title: name,
+            url: url.as_str(),
+            count: downloaded,
+            chapters,
+        };
+        if let Err(e) = notification.send(client, webhook).await {
+            warn!("{} {e}", "Couldn't send notification:".yellow());
+        }
+    }
+    summary.downloaded = downloaded;
+    summary.missing = failures.missing.len();
+    summary.empty = failures.empty.len();
+    summary.failed = failed;
+    summary.elapsed_secs = start.elapsed().as_secs_f64();
+    if opt.stats {
+        words.print(chapters_len);
+        summary.words = words.totals();
+    }
+    summary.print(opt.summary_json)?;
+    if failed != 0 {
+        anyhow::bail!("{failed} chapters failed to download");
+    }
+    Ok(downloaded)
+}
+
+/// Site of `url` after checking it is the main page of a fiction before anything is downloaded.
+///
+/// Chapter urls are pointed to the main page of their fiction.
+fn fiction_site(url: &Url) -> anyhow::Result<&'static Site> {
+    let Some(site) = Site::for_url(url) else {
+        anyhow::bail!(
+            "{url} isn't on a supported site. Pass the main page of a fiction (e.g. {}).",
+            ROYAL_ROAD.example_url
+        );
+    };
+    let segments = url
+        .path_segments()
+        .map(|x| x.filter(|x| !x.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match segments[..] {
+        [fiction, id, ..] if fiction == site.fiction_segment && id.parse::<u64>().is_ok() => {
+            if segments.contains(&site.chapter_segment) {
+                anyhow::bail!(
+                    "{url} is a chapter. Pass the main page of its fiction instead: {}",
+                    chapter_fiction_url(url)
+                );
+            }
+            Ok(site)
+        }
+        _ => anyhow::bail!(
+            "{url} isn't the main page of a {} fiction. Pass a url like {}.",
+            site.name,
+            site.example_url
+        ),
+    }
+}
+
+/// Print the details of the fiction at `url` without downloading its chapters.
+async fn info(url: &Url, confirm_age: bool, user_agent: &str) -> anyhow::Result<()> {
+    let site = fiction_site(url)?;
+    let jar = Arc::new(Jar::default());
+    let client = reqwest::Client::builder()
+        .cookie_provider(jar.clone())
+        .user_agent(user_agent)
+        .build()?;
+    let selectors = (site.selectors)();
+    // Only paginated tables of contents need more requests.
+    let limiter = Limiter::new(Duration::from_millis(1500), Duration::from_millis(300));
+
+    let main_html = fetch_main_page(&client, &jar, &selectors, &limiter, url, confirm_age).await?;
+    let title = parse_main_title(&selectors, &main_html)?;
+    let metadata = parse_metadata(&selectors, &main_html);
+    let first_page = parse_toc_page(&selectors, &main_html, url);
+    let mut chapters = fetch_chapter_links(&client, &selectors, &limiter, url, first_page).await?;
+    dedup_chapters(&mut chapters);
+    print!(
+        "{}",
+        info_text(site.strip_title_suffix(&title), &metadata, chapters.len())
+    );
+    Ok(())
+}
+
+/// Details of a fiction printed by the `info` command.
+fn info_text(title: &str, metadata: &FictionMetadata, chapters: usize) -> String {
+    let mut out = format!("Title: {title}\n");
+    if let Some(author) = &metadata.author {
+        out += &format!("Author: {author}\n");
+    }
+    out += &format!("Chapters: {chapters}\n");
+    if let Some(status) = &metadata.status {
+        out += &format!("Status: {status}\n");
+    }
+    if !metadata.tags.is_empty() {
+        out += &format!("Tags: {}\n", metadata.tags.join(", "));
+    }
+    if let Some(description) = &metadata.description {
+        out += &format!("\n{description}\n");
+    }
+    out
+}
+
+/// Write an RSS feed of the chapters of `toc` in `in_output` to `path`.
+async fn write_feed(
+    path: &Path,
+    title: &str,
+    url: &Url,
+    metadata: &FictionMetadata,
+    toc: &[TocChapter],
+    in_output: &HashSet<ChapterUrl>,
+) -> std::io::Result<()> {
+    let chapters = toc
+        .iter()
+        .filter(|x| in_output.contains(&x.url))
+        .cloned()
+        .collect::<Vec<_>>();
+    let feed = royalroad_dl::feed::rss(title, url, metadata.description.as_deref(), &chapters);
+    tokio::fs::write(path, feed).await?;
+    info!(
+        "Wrote feed of {} chapters to {}",
+        chapters.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Notification of new chapters sent to the `--notify` webhook.
+#[derive(Debug, serde::Serialize)]
+struct Notification<'a> {
+    /// Title of the fiction.
+    title: &'a str,
+    url: &'a str,
+    /// Number of new chapters.
+    count: usize,
+    /// Titles of the new chapters.
+    chapters: Vec<&'a str>,
+}
+
+impl Notification<'_> {
+    /// POST the notification as JSON to `webhook`.
+    async fn send(&self, client: &reqwest::Client, webhook: &Url) -> reqwest::Result<()> {
+        client
+            .post(webhook.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(self).expect("notification serializes"))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Exit code of `--check` when there are new chapters.
+const NEW_CHAPTERS_EXIT_CODE: u8 = 3;
+
+/// URLs listed one per line in `content` read from `name`.
+///
+/// Blank lines and `#` comments are skipped. Lines that aren't URLs are logged and skipped.
+fn parse_url_file(content: &str, name: &str) -> Vec<Url> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|(line_number, line)| match Url::parse(line) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("Skipping {name}:{line_number}: {line:?} isn't a URL: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Download the fictions given by `opt`.
+///
+/// Returns the number of new chapters as [`download`].
+async fn run(mut opt: Options, interrupt: &Interrupt) -> anyhow::Result<usize> {
+    if let Some(path) = &opt.url_file {
+        let (content, name) = if path == Path::new("-") {
+            (
+                std::io::read_to_string(std::io::stdin())?,
+                "stdin".to_owned(),
+            )
+        } else {
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("reading url file {}: {e}", path.display()))?;
+            (content, path.display().to_string())
+        };
+        for url in parse_url_file(&content, &name) {
+            if !opt.urls.contains(&url) {
+                opt.urls.push(url);
+            }
+        }
+    }
+    if opt.urls.is_empty() {
+        anyhow::bail!("No URLs to download. Pass URLs as arguments or with `--url-file`.");
+    }
+    if (opt.follows || opt.urls.len() > 1) && opt.path.is_some() {
+        anyhow::bail!(
+            "`--path` can't be used with `--follows` or several URLs since each fiction is saved to its own file."
+        );
+    }
+    if (opt.follows || opt.urls.len() > 1) && opt.feed.is_some() {
+        anyhow::bail!("`--feed` can't be used with `--follows` or several URLs.");
+    }
+    // Fictions on a follows page are on its site.
+    let sites = opt
+        .urls
+        .iter()
+        .map(|url| match opt.follows {
+            true => Ok(Site::for_url(url).unwrap_or(&ROYAL_ROAD)),
+            false => fiction_site(url),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Fictions on follows pages are downloaded again and again to keep up with them.
+    if opt.follows {
+        opt.incremental = true;
+    }
+    if opt.refresh && !opt.incremental {
+        anyhow::bail!("`--refresh` can only be used with `--incremental`.");
+    }
+    if opt.volume_size.is_some() && (opt.split || opt.bundle_dir || opt.refresh) {
+        anyhow::bail!(
+            "`--volume-size` can't be used with `--split`, `--bundle-dir`, or `--refresh`."
+        );
+    }
+    if opt.bundle_dir && opt.format != Format::Html {
+        anyhow::bail!("`--bundle-dir` can only be used with `--format html`.");
+    }
+    if opt.gzip && (opt.format == Format::Epub || opt.split || opt.bundle_dir) {
+        anyhow::bail!("`--gzip` can't be used with `--format epub`, `--split`, or `--bundle-dir`.");
+    }
+    if opt.no_strip_title && opt.title_suffix.is_some() {
+        anyhow::bail!("`--no-strip-title` can't be used with `--title-suffix`.");
+    }
+    if opt.no_cache && opt.cache_dir.is_none() {
+        anyhow::bail!("`--no-cache` can only be used with `--cache-dir`.");
+    }
+    if opt.check && !opt.incremental {
+        anyhow::bail!("`--check` can only be used with `--incremental`.");
+    }
+    if opt.split && (opt.format == Format::Epub || opt.bundle_dir || opt.refresh) {
+        anyhow::bail!(
+            "`--split` can't be used with `--format epub`, `--bundle-dir`, or `--refresh`."
+        );
+    }
+    if opt.keep_warnings && opt.warning_selector.is_some() {
+        anyhow::bail!("`--keep-warnings` can't be used with `--warning-selector`.");
+    }
+    if opt.theme.is_some() && matches!(opt.style, Style::Path(_)) {
+        anyhow::bail!("`--theme` can't be used with a CSS file given to `--style`. Add the theme to the file instead.");
+    }
+    if opt.reverse && opt.incremental {
+        anyhow::bail!("`--reverse` can't be used with `--incremental` or `--follows` since new chapters are appended to the end.");
+    }
+    if opt.incremental && opt.format == Format::Epub {
+        anyhow::bail!("`--incremental` and `--follows` can't be used with `--format epub` since EPUB files can't be appended to.");
+    }
+
+    // Cookies are only sent to the site of the given url.
+    let jar = Arc::new(Jar::default());
+    let mut cookies = opt
+        .cookie
+        .iter()
+        .flat_map(|x| parse_cookies(&x.0))
+        .collect::<Vec<_>>();
+    if let Some(path) = &opt.cookie_file {
+        let file = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("reading cookie file {}: {e}", path.display()))?;
+        cookies.extend(parse_cookies(&file));
+    }
+    for cookie in cookies {
+        for url in &opt.urls {
+            jar.add_cookie_str(&cookie, url);
+        }
+    }
+    let client = http_client(&opt, jar.clone());
+
+    let site_selectors = |site: &Site| {
+        let mut selectors = (site.selectors)();
+        if opt.keep_warnings {
+            selectors.warning_paragraphs = None;
+        } else if let Some(selector) = &opt.warning_selector {
+            selectors.warning_paragraphs = Some(selector.clone());
+        }
+        Arc::new(selectors)
+    };
+
+    // Requests are rate limited across all downloads.
+    let limiter = Arc::new(
+        Limiter::new(
+            Duration::from_millis(opt.time_limit.get()),
+            Duration::from_millis(opt.jitter),
+        )
+        .with_burst(opt.burst),
+    );
+
+    // Fictions with their site, after expanding follows pages.
+    let mut fictions = Vec::new();
+    // Follows pages that couldn't be read with `--keep-going`.
+    let mut failed_pages = 0;
+    for (url, site) in opt.urls.iter().zip(sites) {
+        if opt.follows {
+            match follows_fictions(&client, &site_selectors(site), &limiter, url).await {
+                Ok(listed) => {
+                    info!("Found {} fictions", listed.len());
+                    fictions.extend(listed.into_iter().map(|x| (x, site)));
+                }
+                Err(e) if opt.keep_going => {
+                    error!("{} {url}: {e:?}", "Error:".red());
+                    failed_pages += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            fictions.push((url.clone(), site));
+        }
+    }
+    if let ([(url, site)], 0) = (&fictions[..], failed_pages) {
+        return download(
+            &client,
+            &jar,
+            site,
+            &site_selectors(site),
+            &limiter,
+            &opt,
+            url,
+            interrupt,
+        )
+        .await;
+    }
+
+    let mut failed = 0;
+    let mut new_chapters = 0;
+    for (url, site) in &fictions {
+        if interrupt.is_set() {
+            break;
+        }
+        match download(
+            &client,
+            &jar,
+            site,
+            &site_selectors(site),
+            &limiter,
+            &opt,
+            url,
+            interrupt,
+        )
+        .await
+        {
+            Err(e) if opt.keep_going => {
+                error!("{} {url}: {e:?}", "Error:".red());
+                failed += 1;
+            }
+            res => new_chapters += res?,
+        }
+    }
+    if failed_pages != 0 {
+        anyhow::bail!(
+            "{failed_pages} follows pages couldn't be read and {failed} of {} fictions failed to download",
+            fictions.len()
+        );
+    }
+    if failed != 0 {
+        anyhow::bail!("{failed} of {} fictions failed to download", fictions.len());
+    }
+    Ok(new_chapters)
+}
+
+/// Client making the requests of downloads with `opt`, storing cookies in `jar`.
+fn http_client(opt: &Options, jar: Arc<Jar>) -> reqwest::Client {
+    let mut client = reqwest::Client::builder()
+        .cookie_provider(jar)
+        .user_agent(&opt.user_agent);
+    // Timed out requests fail with a transient error so they are retried.
+    if opt.timeout != 0 {
+        client = client.timeout(Duration::from_secs(opt.timeout));
+    }
+    if opt.connect_timeout != 0 {
+        client = client.connect_timeout(Duration::from_secs(opt.connect_timeout));
+    }
+    if opt.no_compression {
+        client = client.no_gzip().no_brotli();
+    }
+    let client = match opt.proxy.clone() {
+        Some(proxy) => client.proxy(proxy),
+        None => client,
+    };
+    let client = match opt.http_version {
+        Some(HttpVersion::Http1Only) => client.http1_only(),
+        Some(HttpVersion::Http2) => client.http2_prior_knowledge(),
+        None => client,
+    };
+    client.build().unwrap()
+}
+
+/// Remove every byte order mark, `</body>`, and `</html>` from `content` then end it with a single `END_HTML`.
+fn repair(content: &[u8]) -> Vec<u8> {
+    const REMOVE: [&[u8]; 3] = ["\u{feff}".as_bytes(), b"</body>", b"</html>"];
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(&byte) = rest.first() {
+        if let Some(remove) = REMOVE.iter().find(|x| rest.starts_with(x)) {
+            rest = &rest[remove.len()..];
+        } else {
+            out.push(byte);
+            rest = &rest[1..];
+        }
+    }
+    out.truncate(out.trim_ascii_end().len());
+    out.extend_from_slice(END_HTML.as_bytes());
+    out
+}
+
+/// Rewrite the output at `path` as repaired by [`repair`] after backing it up.
+///
+/// Replaces an existing backup without asking if `assume_yes`.
+async fn repair_output(path: &Path, assume_yes: bool) -> anyhow::Result<()> {
+    let previous_download = read_previous_download(path).await?;
+    let repaired = repair(&previous_download.content);
+    if repaired == previous_download.content {
+        info!("{}", "Nothing to repair".green());
+        return Ok(());
+    }
+
+    backup(path, assume_yes, || std::io::stdin().lock()).await?;
+    PreviousDownload {
+        content: repaired,
+        compressed: previous_download.compressed,
+        saved_offset: None,
+    }
+    .write(path)
+    .await?;
+    info!("{} {}", "Repaired".green(), path.display());
+    Ok(())
+}
+
+/// Remove the chapter sections of the html output `content` which repeat an earlier chapter and put the rest in
+/// table of contents order.
+///
+/// Chapters are ordered by their index in `meta` if every chapter has one, otherwise they keep the order they are
+/// first written in. Returns the new content and the number of sections removed.
+fn dedupe(content: &str, meta: &OutputMeta) -> (String, usize) {
+    let sections = chapter_sections(Format::Html, content);
+    let (Some(first), Some(last)) = (sections.first(), sections.last()) else {
+        return (content.to_owned(), 0);
+    };
+    let (start, end) = (first.1.start, last.1.end);
+    let mut seen = HashSet::new();
+    let mut kept = sections
+        .iter()
+        .filter(|(url, _)| seen.insert(url.clone()))
+        .collect::<Vec<_>>();
+    let removed = sections.len() - kept.len();
+    let indices = kept
+        .iter()
+        .map(|(url, _)| meta.get(url).and_then(|x| x.index))
+        .collect::<Option<Vec<_>>>();
+    if let Some(indices) = indices {
+        let mut order = kept.into_iter().zip(indices).collect::<Vec<_>>();
+        order.sort_by_key(|x| x.1);
+        kept = order.into_iter().map(|x| x.0).collect();
+    }
+    let mut out = content[..start].to_owned();
+    for (_, range) in kept {
+        out.push_str(&content[range.clone()]);
+    }
+    out.push_str(&content[end..]);
+    (out, removed)
+}
+
+/// Rewrite the html output at `path` as deduplicated by [`dedupe`] after backing it up, updating its table of
+/// contents and saved metadata.
+///
+/// Replaces an existing backup without asking if `assume_yes`.
+async fn dedupe_output(path: &Path, assume_yes: bool) -> anyhow::Result<()> {
+    let previous_download = read_previous_download(path).await?;
+    let encoding = declared_encoding(&previous_download.content);
+    let content = encoding.decode(&previous_download.content).0;
+    let mut meta = OutputMeta::open(path).await?;
+    let (deduped, removed) = dedupe(&content, &meta);
+    if deduped == content {
+        info!("{}", "Nothing to dedupe".green());
+        return Ok(());
+    }
+
+    backup(path, assume_yes, || std::io::stdin().lock()).await?;
+    let format = OutputFormat {
+        kind: Format::Html,
+        encoding,
+        wrap: 0,
+    };
+    meta.locate(format, &deduped);
+    let mut content = encoding.encode(&deduped).0.into_owned();
+    if let Some(shift) = insert_toc(&mut content, &Selectors::default(), encoding, Some(&meta)) {
+        meta.shift(shift);
+    }
+    PreviousDownload {
+        content,
+        compressed: previous_download.compressed,
+        saved_offset: None,
+    }
+    .write(path)
+    .await?;
+    if !meta.chapters.is_empty() {
+        meta.save(path).await?;
+    }
+    info!(
+        "{} {}: removed {removed} duplicate chapters",
+        "Deduped".green(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Title, details, and cover written to the header of an html output by [`Output::write_header`].
+fn parse_html_header(html: &Html) -> (Option<String>, FictionMetadata, Option<Image>) {
+    let select = |css: &str| html.select(&Selector::parse(css).unwrap()).next();
+    let meta = |name: &str| {
+        select(&format!(r#"head > meta[name="{name}"][content]"#))
+            .and_then(|x| x.attr("content"))
+            .map(str::to_owned)
+    };
+    let text = |css: &str| select(css).map(|x| x.text().collect::<String>());
+    // The description's paragraphs keep its lines which its meta tag joins.
+    let description = select(".fiction-info > .description")
+        .map(|x| {
+            x.child_elements()
+                .map(|x| x.text().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .or_else(|| meta("description"));
+    let metadata = FictionMetadata {
+        author: meta("author"),
+        description,
+        tags: meta("keywords")
+            .map(|x| x.split(", ").map(str::to_owned).collect())
+            .unwrap_or_default(),
+        status: text(".fiction-info > .status"),
+    };
+    let cover = select("body > img.cover[src]")
+        .and_then(|x| x.attr("src"))
+        .and_then(Image::from_data_uri);
+    (text("head > title"), metadata, cover)
+}
+
+/// Main page of the fiction of the chapter at `url`, or `url` if it isn't a chapter of a supported site.
+fn chapter_fiction_url(url: &Url) -> Url {
+    let Some(site) = Site::for_url(url) else {
+        return url.clone();
+    };
+    let segments = url
+        .path_segments()
+        .map(|x| x.filter(|x| !x.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let Some(chapter) = segments.iter().position(|x| *x == site.chapter_segment) else {
+        return url.clone();
+    };
+    let mut fiction = url.clone();
+    fiction.set_path(&segments[..chapter].join("/"));
+    fiction.set_query(None);
+    fiction.set_fragment(None);
+    fiction
+}
+
+/// Encoding an html output declares in its first tag, or UTF-8 if it doesn't.
+fn declared_encoding(content: &[u8]) -> &'static Encoding {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX
+        .get_or_init(|| Regex::new(r#"<meta charset="([^"]+)">"#).unwrap())
+        .captures(&String::from_utf8_lossy(
+            &content[..content.len().min(1024)],
+        ))
+        .and_then(|x| Encoding::for_label(x[1].as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8)
+}
+
+/// Write the chapters of the html output at `input` to an EPUB at `output`, or `input` with the `epub` extension.
+async fn convert(input: &Path, output: Option<PathBuf>, assume_yes: bool) -> anyhow::Result<()> {
+    let content = read_previous_download(input)
+        .await
+        .map_err(|e| anyhow::anyhow!("reading {}: {e}", input.display()))?
+        .content;
+    let (content, _, _) = declared_encoding(&content).decode(&content);
+
+    let chapters = parse_output(&content)
+        .into_iter()
+        .enumerate()
+        .map(|(index, x)| Chapter {
+            index,
+            title: x.title,
+            url: x.url,
+            body_html: x.content,
+            published: None,
+            validators: Validators::default(),
+        })
+        .collect::<Vec<_>>();
+    let Some(first) = chapters.first() else {
+        anyhow::bail!(
+            "{} has no chapters. Only html outputs can be converted.",
+            input.display()
+        );
+    };
+    let url = chapter_fiction_url(&first.url);
+    let (title, metadata, cover) = parse_html_header(&Html::parse_document(&content));
+    let title = title.map_or_else(
+        || {
+            let name = input.file_stem().unwrap_or_default().to_string_lossy();
+            name.strip_suffix(".html").unwrap_or(&name).to_owned()
+        },
+        |x| match Site::for_url(&url) {
+            Some(site) => site.strip_title_suffix(&x).to_owned(),
+            None => x,
+        },
+    );
+
+    let output = output.unwrap_or_else(|| {
+        let input = match input.extension() {
+            Some(x) if x == "gz" => input.with_extension(""),
+            _ => input.to_owned(),
+        };
+        input.with_extension("epub")
+    });
+    if output.exists()
+        && !ask(
+            format!("Path ({}) already exists. Overwrite it?", output.display()),
+            assume_yes,
+            || std::io::stdin().lock(),
+        )
+        .await?
+    {
+        anyhow::bail!("Path ({}) already exists.", output.display());
+    }
+    let mut epub = std::io::Cursor::new(Vec::new());
+    royalroad_dl::epub::write_epub(
+        &mut epub,
+        &title,
+        &url,
+        &metadata,
+        cover.as_ref(),
+        &chapters,
+    )?;
+    tokio::fs::write(&output, epub.into_inner()).await?;
+    info!(
+        "{} {} chapters to {}",
+        "Converted".green(),
+        chapters.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Formats events as just their message.
+///
+/// Messages are colored by what logs them, which the default format would escape.
+struct MessageOnly;
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        struct Message<'a, 'w>(&'a mut Writer<'w>, std::fmt::Result);
+        impl Visit for Message<'_, '_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.1 = write!(self.0, "{value:?}");
+                }
+            }
+        }
+        let mut message = Message(&mut writer, Ok(()));
+        event.record(&mut message);
+        message.1?;
+        writeln!(writer)
+    }
+}
+
+/// Log events at `level` and above to stdout as lines of their message.
+fn init_logging(level: Level) {
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(anstream::stdout)
+        .event_format(MessageOnly)
+        .init();
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    // Parse cli options, after the defaults of the config file.
+    let args = match args_with_config() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{} {e:?}", "Error:".red());
+            return ExitCode::FAILURE;
+        }
+    };
+    let cli = match cli().run_inner(bpaf::Args::from(args.as_slice()).set_name("royalroad-dl")) {
+        Ok(cli) => cli,
+        Err(e) => {
+            e.print_message(100);
+            return ExitCode::from(e.exit_code() as u8);
+        }
+    };
+    let res = match cli {
+        Cli::Repair { path, assume_yes } => {
+            init_logging(Level::INFO);
+            repair_output(&path, assume_yes).await
+        }
+        Cli::Dedupe { path, assume_yes } => {
+            init_logging(Level::INFO);
+            dedupe_output(&path, assume_yes).await
+        }
+        Cli::Info {
+            confirm_age,
+            user_agent,
+            url,
+        } => {
+            init_logging(Level::INFO);
+            info(&url, confirm_age, &user_agent).await
+        }
+        Cli::Convert {
+            assume_yes,
+            input,
+            output,
+        } => {
+            init_logging(Level::INFO);
+            convert(&input, output, assume_yes).await
+        }
+        Cli::Download(opt) | Cli::DownloadCommand(opt) => {
+            if opt.no_color {
+                anstream::ColorChoice::Never.write_global();
+            }
+            init_logging(opt.log_level());
+            let interrupt = Interrupt::install();
+            let check = opt.check;
+            match run(*opt, &interrupt).await {
+                // Conventional exit code for ctrl-c.
+                Ok(_) if interrupt.is_set() => return ExitCode::from(130),
+                Ok(new_chapters) if check && new_chapters != 0 => {
+                    return ExitCode::from(NEW_CHAPTERS_EXIT_CODE)
+                }
+                res => res.map(drop),
+            }
+        }
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{} {e:?}", "Error:".red());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backup, cached_chapters, cli, config_path, confirm, convert, dedup_chapters, dedupe,
+        download, drop_truncated_chapter, fiction_site, http_client, index_range, info_text,
+        open_output, options, parse_cookies, parse_encoding, parse_html_header, parse_toc_page,
+        parse_url_file, read_previous_download, refresh_chapters, repair, repair_output,
+        replace_chapters, resume_offset, sanitize_path, since_date, split_file_name, split_files,
+        temp_path, toc_html, volume_path, write_toc, Chapter, ChapterMeta, ChapterUrl, Cli, Config,
+        Failures, FictionMetadata, Format, Header, Html, HttpVersion, Image, Interrupt, Limiter,
+        Notification, Output, OutputFormat, OutputMeta, PreviousDownload, Selectors, Style, Theme,
+        WordStats, WordTotals, MAX_FILE_NAME_LEN,
+    };
+    use bpaf::Parser;
+    use royalroad_dl::site::ROYAL_ROAD;
+    use royalroad_dl::text::html_to_text;
+    use royalroad_dl::{chapter_response_to_content, parse_output, ParsedChapter, Processing};
+    use std::borrow::Cow;
+    use std::ffi::OsString;
+    use std::io::{BufRead, Read};
+    use std::num::NonZeroUsize;
+    use std::ops::RangeInclusive;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+    use time::OffsetDateTime;
+    use tokio::io::AsyncWriteExt;
+    use tracing::Level;
+    use url::Url;
+
+    #[tokio::test]
+    async fn incremental_without_new_chapters_leaves_file_untouched() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let previous_download = r#"<html><head><meta charset="UTF-8"><title>Title</title></head><body><h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title">Chapter</a></h1><div class="chapter-content">Content</div></body></html>"#;
+        std::fs::write(&path, previous_download)?;
+        let mtime = SystemTime::now() - Duration::from_secs(60 * 60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(mtime)?;
+
+        let cached = cached_chapters(&Selectors::default(), Format::Html, previous_download);
+        assert_eq!(cached.len(), 1);
+        let f = open_output(
+            &path,
+            HTML,
+            Some(&PreviousDownload {
+                content: previous_download.into(),
+                compressed: false,
+                saved_offset: None,
+            }),
+            false,
+            !cached.is_empty(),
+            0,
+            TITLE,
+            false,
+        )
+        .await?;
+        assert!(f.is_none());
+
+        assert_eq!(std::fs::metadata(&path)?.modified()?, mtime);
+        assert_eq!(std::fs::read_to_string(&path)?, previous_download);
+        Ok(())
+    }
+
+    #[test]
+    fn cookies() {
+        assert_eq!(parse_cookies("a=1; b=2;"), ["a=1", "b=2"]);
+        assert_eq!(
+            parse_cookies(
+                "# Netscape HTTP Cookie File\n\
+                 .royalroad.com\tTRUE\t/\tTRUE\t0\ta\t1\n\
+                 #HttpOnly_.royalroad.com\tTRUE\t/\tTRUE\t0\tb\t2\n"
+            ),
+            ["a=1", "b=2"]
+        );
+        let cli = options()
+            .to_options()
+            .run_inner(&["--cookie", "session=secret", "https://example.com"])
+            .unwrap();
+        assert!(!format!("{cli:?}").contains("secret"));
+    }
+
+    #[test]
+    fn config_file() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("royalroad-dl.toml");
+        std::fs::write(
+            &path,
+            "time_limit = 2000\nconnections = 2\nformat = \"epub\"\nuser_agent = \"agent one\"\n\
+             embed_images = true\nno_cover = false\n",
+        )?;
+        let config = Config::load(Some(&path))?;
+        let parse = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            let args = config
+                .args(&args)
+                .into_iter()
+                .chain(args)
+                .collect::<Vec<_>>();
+            options().to_options().run_inner(args.as_slice()).unwrap()
+        };
+        let opt = parse(&["https://example.com"]);
+        assert_eq!(opt.time_limit.get(), 2000);
+        assert_eq!(opt.connections, 2);
+        assert_eq!(opt.format, Format::Epub);
+        assert_eq!(opt.user_agent, "agent one");
+        assert!(opt.embed_images);
+        assert!(!opt.no_cover);
+        // The command line overrides the config file.
+        let opt = parse(&[
+            "-t500",
+            "--connections=8",
+            "--format",
+            "text",
+            "https://example.com",
+        ]);
+        assert_eq!(opt.time_limit.get(), 500);
+        assert_eq!(opt.connections, 8);
+        assert_eq!(opt.format, Format::Text);
+        let opt = parse(&["-yc", "1", "https://example.com"]);
+        assert_eq!(opt.connections, 1);
+        assert_eq!(opt.time_limit.get(), 2000);
+
+        assert_eq!(
+            config_path(&[
+                "--config".into(),
+                "a.toml".into(),
+                "https://example.com".into()
+            ]),
+            Some("a.toml".into())
+        );
+        assert_eq!(
+            config_path(&["--config=a.toml".into()]),
+            Some("a.toml".into())
+        );
+        assert_eq!(config_path(&["--".into(), "--config=a.toml".into()]), None);
+
+        std::fs::write(&path, "time_limt = 2000\n")?;
+        assert!(format!("{:?}", Config::load(Some(&path)).unwrap_err()).contains("time_limt"));
+        assert!(Config::load(Some(&dir.path().join("missing.toml"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn proxy_flag() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.proxy.is_some())
+                .map_err(|e| e.unwrap_stderr())
+        };
+        assert_eq!(parse(&["https://example.com"]), Ok(false));
+        assert_eq!(
+            parse(&["--proxy", "socks5://127.0.0.1:9050", "https://example.com"]),
+            Ok(true)
+        );
+        assert!(parse(&["--proxy", "not a url", "https://example.com"])
+            .unwrap_err()
+            .contains("invalid proxy"));
+    }
+
+    #[test]
+    fn warning_selector_flag() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.warning_selector.is_some())
+                .map_err(|e| e.unwrap_stderr())
+        };
+        assert_eq!(parse(&["https://example.com"]), Ok(false));
+        assert_eq!(
+            parse(&["--warning-selector", "[class^=xy]", "https://example.com"]),
+            Ok(true)
+        );
+        assert!(parse(&["--warning-selector", "p[", "https://example.com"])
+            .unwrap_err()
+            .contains("invalid CSS selector"));
+    }
+
+    #[test]
+    fn word_stats() -> Result<(), url::ParseError> {
+        let mut words = WordStats::default();
+        assert_eq!(words.totals(), None);
+        for (index, body_html) in ["<p>One two</p>", "<p>One</p><p>two three four</p>"]
+            .into_iter()
+            .enumerate()
+        {
+            words.add(&Chapter {
+                index,
+                title: format!("Chapter {index}"),
+                url: Url::parse(&format!(
+                    "https://www.royalroad.com/fiction/1/t/chapter/{index}/c"
+                ))?,
+                body_html: body_html.to_owned(),
+                published: None,
+                validators: Default::default(),
+            });
+        }
+        assert_eq!(
+            words.totals(),
+            Some(WordTotals {
+                total: 6,
+                min: 2,
+                max: 4,
+                mean: 3.
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn since_dates() -> Result<(), time::error::ComponentRange> {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000)?;
+        assert_eq!(
+            since_date("2023-11-01", now),
+            Ok(
+                time::Date::from_calendar_date(2023, time::Month::November, 1)?
+                    .midnight()
+                    .assume_utc()
+            )
+        );
+        assert_eq!(since_date("7d", now), Ok(now - time::Duration::days(7)));
+        assert_eq!(since_date("12h", now), Ok(now - time::Duration::hours(12)));
+        assert_eq!(since_date("2w", now), Ok(now - time::Duration::weeks(2)));
+        for since in ["", "d", "7", "7y", "-7d", "2023-13-01", "yesterday"] {
+            assert!(since_date(since, now).is_err(), "{since}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn theme_flag() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.theme)
+                .map_err(|e| e.unwrap_stderr())
+        };
+        assert_eq!(parse(&["https://example.com"]), Ok(None));
+        assert_eq!(
+            parse(&["--theme", "dark", "https://example.com"]),
+            Ok(Some(Theme::Dark))
+        );
+        assert!(parse(&["--theme", "light", "https://example.com"])
+            .unwrap_err()
+            .contains("unknown theme"));
+    }
+
+    #[test]
+    fn url_positionals() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.urls.iter().map(Url::to_string).collect::<Vec<_>>())
+                .map_err(|e| e.unwrap_stderr())
+        };
+        assert_eq!(
+            parse(&["https://example.com/a", "-i", "https://example.com/b"]),
+            Ok(vec![
+                "https://example.com/a".to_owned(),
+                "https://example.com/b".to_owned()
+            ])
+        );
+        assert_eq!(parse(&["-i"]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn info_command() {
+        let parse = |args: &[&str]| cli().run_inner(args).unwrap();
+        assert!(matches!(
+            parse(&["info", "https://example.com"]),
+            Cli::Info { confirm_age: false, url, .. } if url.as_str() == "https://example.com/"
+        ));
+        assert!(matches!(parse(&["https://example.com"]), Cli::Download(_)));
+        assert!(matches!(
+            parse(&["download", "-i", "https://example.com"]),
+            Cli::DownloadCommand(opt) if opt.incremental
+        ));
+
+        let metadata = FictionMetadata {
+            author: Some("Author".to_owned()),
+            description: Some("First.\nSecond.".to_owned()),
+            tags: vec!["Fantasy".to_owned(), "Magic".to_owned()],
+            status: None,
+        };
+        assert_eq!(
+            info_text("The Title", &metadata, 12),
+            "Title: The Title\nAuthor: Author\nChapters: 12\nTags: Fantasy, Magic\n\nFirst.\nSecond.\n"
+        );
+    }
+
+    #[test]
+    fn url_file() {
+        let urls = parse_url_file(
+            "# Reading list\nhttps://example.com/a\n\n  https://example.com/b  \nnot a url\n",
+            "list.txt",
+        );
+        assert_eq!(
+            urls.iter().map(Url::as_str).collect::<Vec<_>>(),
+            ["https://example.com/a", "https://example.com/b"]
+        );
+    }
+
+    #[test]
+    fn timeout_flags() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| (x.timeout, x.connect_timeout))
+                .ok()
+        };
+        assert_eq!(parse(&["https://example.com"]), Some((30, 10)));
+        assert_eq!(
+            parse(&[
+                "--timeout",
+                "0",
+                "--connect-timeout",
+                "5",
+                "https://example.com"
+            ]),
+            Some((0, 5))
+        );
+    }
+
+    #[test]
+    fn verbosity_flags() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.log_level())
+                .ok()
+        };
+        assert_eq!(parse(&["https://example.com"]), Some(Level::INFO));
+        assert_eq!(parse(&["-v", "https://example.com"]), Some(Level::DEBUG));
+        assert_eq!(parse(&["-vv", "https://example.com"]), Some(Level::TRACE));
+        assert_eq!(parse(&["-q", "https://example.com"]), Some(Level::ERROR));
+    }
+
+    #[test]
+    fn http_version_flags() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(args)
+                .map(|x| x.http_version)
+                .ok()
+        };
+        assert_eq!(
+            parse(&["https://www.royalroad.com/fiction/12345"]),
+            Some(None)
+        );
+        assert_eq!(
+            parse(&["--http1-only", "https://www.royalroad.com/fiction/12345"]),
+            Some(Some(HttpVersion::Http1Only))
+        );
+        assert_eq!(
+            parse(&["--http2", "https://www.royalroad.com/fiction/12345"]),
+            Some(Some(HttpVersion::Http2))
+        );
+        assert_eq!(
+            parse(&[
+                "--http1-only",
+                "--http2",
+                "https://www.royalroad.com/fiction/12345"
+            ]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn latin1_output_round_trips() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let encoding = parse_encoding("latin1".to_owned()).unwrap();
+        let mut output = open_output(
+            &path,
+            OutputFormat {
+                kind: Format::Html,
+                encoding,
+                wrap: 0,
+            },
+            None,
+            false,
+            false,
+            1,
+            Header {
+                main_title: "Café ☃",
+                cover: None,
+                metadata: None,
+                style: None,
+            },
+            false,
+        )
+        .await?
+        .unwrap();
+        output.write_chapter(r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title">Crème brûlée</a></h1>"#).await?;
+        output.shutdown().await?;
+
+        let bytes = std::fs::read(&path)?;
+        assert!(bytes.windows(4).any(|x| x == b"Caf\xE9"));
+        let (decoded, _, had_errors) = encoding.decode(&bytes);
+        assert!(!had_errors);
+        assert!(decoded.contains(r#"<meta charset="windows-1252">"#));
+        assert!(decoded.contains("<title>Café &#9731;</title>"));
+        assert!(decoded.contains("Crème brûlée"));
+        assert_eq!(
+            cached_chapters(&Selectors::default(), Format::Html, &decoded).len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn header_includes_metadata() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let metadata = FictionMetadata {
+            author: Some(r#"The "Author""#.to_owned()),
+            description: Some("First & more.\nSecond.".to_owned()),
+            tags: vec!["Fantasy".to_owned(), "Action".to_owned()],
+            status: Some("Ongoing".to_owned()),
+        };
+        let mut output = Output::create(
+            &path,
+            HTML,
+            false,
+            Header {
+                metadata: Some(&metadata),
+                ..TITLE
+            },
+        )
+        .await?;
+        output.shutdown().await?;
+
+        let html = std::fs::read_to_string(&path)?;
+        assert!(html.contains(r#"<meta name="author" content="The &quot;Author&quot;">"#));
+        assert!(html.contains(r#"<meta name="description" content="First &amp; more. Second.">"#));
+        assert!(html.contains(r#"<meta name="keywords" content="Fantasy, Action">"#));
+        assert!(html
+            .contains(r#"<div class="description"><p>First &amp; more.</p><p>Second.</p></div>"#));
+        assert!(html.contains(r#"<p class="status">Ongoing</p>"#));
+        assert!(cached_chapters(&Selectors::default(), Format::Html, &html).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convert_html_to_epub() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let metadata = FictionMetadata {
+            author: Some("Author".to_owned()),
+            description: Some("First.\nSecond.".to_owned()),
+            tags: vec!["Fantasy".to_owned(), "Action".to_owned()],
+            status: Some("Ongoing".to_owned()),
+        };
+        let cover = Image {
+            content_type: "image/png".to_owned(),
+            bytes: b"png".to_vec(),
+        };
+        let mut output = Output::create(
+            &path,
+            HTML,
+            false,
+            Header {
+                main_title: "The Title | Royal Road",
+                cover: Some(&cover.data_uri()),
+                metadata: Some(&metadata),
+                style: None,
+            },
+        )
+        .await?;
+        for (n, title) in [(1, "One"), (2, "Two")] {
+            output
+                .write_chapter(&format!(r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/{n}/{title}">{title}</a></h1><p>Content {n}</p>"#))
+                .await?;
+        }
+        output.shutdown().await?;
+
+        assert_eq!(
+            parse_html_header(&Html::parse_document(&std::fs::read_to_string(&path)?)),
+            (
+                Some("The Title | Royal Road".to_owned()),
+                metadata,
+                Some(cover)
+            )
+        );
+        convert(&path, None, false).await?;
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(dir.path().join("fiction.epub"))?)?;
+        let mut read = |name: &str| -> anyhow::Result<String> {
+            let mut out = String::new();
+            zip.by_name(name)?.read_to_string(&mut out)?;
+            Ok(out)
+        };
+        let opf = read("OEBPS/content.opf")?;
+        assert!(opf.contains("<dc:title>The Title</dc:title>"));
+        assert!(opf.contains(
+            "<dc:identifier id=\"id\">https://www.royalroad.com/fiction/12345/the-title</dc:identifier>"
+        ));
+        assert!(opf.contains("<dc:creator>Author</dc:creator>"));
+        assert!(opf.contains("cover-image"));
+        assert!(read("OEBPS/chapter1.xhtml")?.contains("<p>Content 1</p>"));
+        assert!(read("OEBPS/chapter2.xhtml")?.contains("<p>Content 2</p>"));
+        assert!(convert(&dir.path().join("missing.html"), None, true)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notification_webhook() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/hook"))
+            .and(matchers::header("content-type", "application/json"))
+            .and(matchers::body_json(serde_json::json!({
+                "title": "The Title",
+                "url": "https://www.royalroad.com/fiction/12345/the-title",
+                "count": 2,
+                "chapters": ["One", "Two"],
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let notification = Notification {
+            title: "The Title",
+            url: "https://www.royalroad.com/fiction/12345/the-title",
+            count: 2,
+            chapters: vec!["One", "Two"],
+        };
+        let client = reqwest::Client::new();
+        let webhook = Url::parse(&server.uri())?;
+        notification.send(&client, &webhook.join("/hook")?).await?;
+        assert!(notification
+            .send(&client, &webhook.join("/missing")?)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_retries_rate_limited_chapters() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/limited"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/limited"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"<html><head><title>Chapter - Title</title></head><body><div class="chapter-content">Edited</div></body></html>"#))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/removed"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let base = Url::parse(&server.uri())?;
+        let chapters = vec![
+            (0, ChapterUrl(base.join("/limited")?)),
+            (1, ChapterUrl(base.join("/removed")?)),
+        ];
+        let refreshed = refresh_chapters(
+            &reqwest::Client::new(),
+            &Selectors::default(),
+            &Limiter::new(Duration::from_millis(1), Duration::ZERO),
+            chapters,
+            2,
+            "Title",
+            Processing::default(),
+            1,
+            &OutputMeta::default(),
+        )
+        .await;
+        // The removed chapter isn't retried.
+        assert_eq!(refreshed.len(), 1);
+        assert!(refreshed[0].body_html.contains("Edited"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_responses() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        let mut gzip = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+        gzip.write_all(b"compressed").await?;
+        gzip.shutdown().await?;
+        Mock::given(matchers::header_regex("accept-encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_bytes(gzip.into_inner()),
+            )
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("plain"))
+            .mount(&server)
+            .await;
+        let text = |args: &[&str]| {
+            let opt = options().to_options().run_inner(args).unwrap();
+            let client = http_client(&opt, Arc::default());
+            let url = server.uri();
+            async move { client.get(url).send().await?.text().await }
+        };
+        let url = "https://www.royalroad.com/fiction/12345";
+        assert_eq!(text(&[url]).await?, "compressed");
+        assert_eq!(text(&["--no-compression", url]).await?, "plain");
+        Ok(())
+    }
 
-        // Write chapter content and end with `END_HTML` in case of ctrl-c.
-        let mut chapter_content = chapter_response_to_content(
-            &format!("{}/{}", i + 1, chapters_len),
-            chapter_response?,
-            &main_title,
+    #[tokio::test]
+    async fn header_style() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let css_path = dir.path().join("style.css");
+        std::fs::write(&css_path, "p{color:red}</style>")?;
+        let parse = |style: &str| style.parse::<Style>();
+        assert_eq!(parse("default"), Ok(Style::Default));
+        assert_eq!(parse("none"), Ok(Style::None));
+        assert_eq!(parse("style.css"), Ok(Style::Path("style.css".into())));
+        assert_eq!(Style::None.css().await?, None);
+        assert!(Style::Path(dir.path().join("missing.css"))
+            .css()
+            .await
+            .is_err());
+
+        let css = Style::Path(css_path).css().await?;
+        let mut output = Output::create(
+            &path,
+            HTML,
+            false,
+            Header {
+                style: css.as_deref(),
+                ..TITLE
+            },
         )
         .await?;
-        chapter_content.push_str(END_HTML);
-        f.write_all(chapter_content.as_bytes()).await?;
+        output.shutdown().await?;
+        let html = std::fs::read_to_string(&path)?;
+        assert!(html.contains(r"<style>p{color:red}<\/style></style></head>"));
+        Ok(())
+    }
 
-        // Seek before `END_HTML` so it is overwritten on next chapter content
-        f.seek(std::io::SeekFrom::Current(
-            -i64::try_from(END_HTML.len()).unwrap(),
-        ))
-        .await?;
+    const TITLE: Header = Header {
+        main_title: "Title",
+        cover: None,
+        metadata: None,
+        style: None,
+    };
+    const HTML: OutputFormat = OutputFormat {
+        kind: Format::Html,
+        encoding: encoding_rs::UTF_8,
+        wrap: 0,
+    };
+
+    /// Input that panics if read from.
+    struct NoInput;
+    impl Read for NoInput {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("input read")
+        }
+    }
+    impl BufRead for NoInput {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            panic!("input read")
+        }
+        fn consume(&mut self, _: usize) {}
     }
 
-    f.shutdown().await?;
-    Ok(())
-}
+    #[tokio::test]
+    async fn assume_yes_proceeds_without_reading_input() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let backup_path = dir.path().join("fiction.html.bk");
+        std::fs::write(&path, "previous")?;
+        std::fs::write(&backup_path, "older")?;
 
-#[cfg(test)]
-mod tests {
-    use crate::ChapterUrl;
-    use url::Url;
+        // Conflict with existing output.
+        assert!(confirm("Overwrite?", true, NoInput)?);
+        // Conflict with existing backup.
+        backup(&path, true, || NoInput).await?;
+        assert_eq!(std::fs::read_to_string(&backup_path)?, "previous");
+
+        // Declining keeps the existing backup.
+        std::fs::write(&path, "newer")?;
+        assert!(!confirm("Overwrite?", false, "n\n".as_bytes())?);
+        assert!(backup(&path, false, || "\n".as_bytes()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&backup_path)?, "previous");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupt_stops_before_next_chapter() {
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let interrupt = Interrupt(rx);
+        assert_eq!(interrupt.or(async { Some(1) }).await, Some(1));
+        tx.send(true).unwrap();
+        assert!(interrupt.is_set());
+        assert_eq!(interrupt.or(async { Some(2) }).await, None);
+    }
+
+    #[test]
+    fn duplicate_toc_chapters() -> anyhow::Result<()> {
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/the-title")?;
+        let html = Html::parse_document(include_str!("../tests/fixtures/toc_duplicate.html"));
+        let mut toc = parse_toc_page(&Selectors::default(), &html, &url).chapters;
+        assert_eq!(toc.len(), 4);
+        dedup_chapters(&mut toc);
+        assert_eq!(
+            toc.iter().map(|x| x.title.as_str()).collect::<Vec<_>>(),
+            ["Chapter 1", "Chapter 2", "Chapter 3"]
+        );
+        assert_eq!(
+            toc[0].url.0.as_str(),
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1000001/chapter-1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keep_going_records_failures() -> anyhow::Result<()> {
+        let url = Url::parse("https://www.royalroad.com/fiction/12345/t/chapter/1/c")?;
+        let failed = || Err(royalroad_dl::Error::AgeGate);
+        let e = Failures::new(false, 2)
+            .check((0, url.clone()), failed())
+            .unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with(&format!("chapter 1 ({url}): fiction is behind")),
+            "{e}"
+        );
+        let mut failures = Failures::new(true, 2);
+        assert!(failures.check((1, url.clone()), failed())?.is_none());
+        assert_eq!(failures.failed, [(1, url.clone())]);
+
+        // Removed chapters are skipped without `--keep-going`.
+        let mut failures = Failures::new(false, 2);
+        let missing = Err(royalroad_dl::Error::ChapterMissing(
+            reqwest::StatusCode::NOT_FOUND,
+        ));
+        assert!(failures.check((0, url.clone()), missing)?.is_none());
+        assert_eq!(failures.missing, [(0, url.clone())]);
+        assert!(failures.failed.is_empty());
+
+        // Nearly empty chapters are only warned about unless skipped.
+        let chapter = Chapter {
+            index: 0,
+            title: "Announcement".to_owned(),
+            url: url.clone(),
+            body_html: r#"<div class="chapter-content"><p>Hiatus.</p></div>"#.to_owned(),
+            published: None,
+            validators: Default::default(),
+        };
+        let mut failures = Failures::new(false, 2).empty_chapters(20, false);
+        assert!(failures
+            .check((0, url.clone()), Ok(chapter.clone()))?
+            .is_some());
+        let mut failures = Failures::new(false, 2).empty_chapters(20, true);
+        assert!(failures
+            .check((0, url.clone()), Ok(chapter.clone()))?
+            .is_none());
+        assert_eq!(failures.empty, [(0, url.clone())]);
+        let mut failures = Failures::new(false, 2).empty_chapters(5, true);
+        assert!(failures.check((0, url), Ok(chapter))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn fiction_url_site() {
+        let check = |url: &str| {
+            fiction_site(&Url::parse(url).unwrap())
+                .map(drop)
+                .map_err(|e| e.to_string())
+        };
+        assert!(check("https://www.royalroad.com/fiction/12345/the-title").is_ok());
+        assert!(check("https://royalroad.com/fiction/12345").is_ok());
+        assert!(check("https://www.royalroadl.com/fiction/12345").is_ok());
+        assert!(check("https://www.royalroad.com/fiction/12345/the-title/").is_ok());
+        assert!(check("https://example.com/fiction/12345/the-title")
+            .unwrap_err()
+            .contains("isn't on a supported site"));
+        assert!(check("https://www.royalroad.com/fictions/best-rated")
+            .unwrap_err()
+            .contains("isn't the main page of a Royal Road fiction"));
+        assert!(check(
+            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter-title?x=1"
+        )
+        .unwrap_err()
+        .ends_with("Pass the main page of its fiction instead: https://www.royalroad.com/fiction/12345/the-title"));
+    }
+
+    #[test]
+    fn from_to_range() -> anyhow::Result<()> {
+        let n = |x| NonZeroUsize::new(x);
+        assert_eq!(index_range(None, None, 5)?, 0..=4);
+        assert_eq!(index_range(n(2), n(4), 5)?, 1..=3);
+        assert_eq!(index_range(n(3), n(100), 5)?, 2..=4);
+        assert!(index_range(n(6), None, 5).is_err());
+        assert!(index_range(n(4), n(2), 5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn between_range() -> anyhow::Result<()> {
+        let chapter = |id: u32| {
+            Url::parse(&format!(
+                "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+            ))
+        };
+        let chapters = (1..=5)
+            .map(|x| chapter(x).map(ChapterUrl))
+            .collect::<Result<Vec<_>, _>>()?;
+        let between = |start: &Url, end: &Url| {
+            let args = [
+                "--between",
+                start.as_str(),
+                end.as_str(),
+                "https://www.royalroad.com/fiction/12345",
+            ];
+            options()
+                .to_options()
+                .run_inner(&args[..])
+                .unwrap()
+                .between
+                .unwrap()
+        };
+
+        assert_eq!(between(&chapter(2)?, &chapter(4)?).range(&chapters)?, 1..=3);
+        // Endpoints match regardless of title in url.
+        let renamed = Url::parse(
+            "https://www.royalroad.com/fiction/12345/new-title/chapter/3/chapter-title",
+        )?;
+        assert_eq!(between(&renamed, &renamed).range(&chapters)?, 2..=2);
+
+        assert!(between(&chapter(4)?, &chapter(2)?)
+            .range(&chapters)
+            .is_err());
+        assert!(between(&chapter(1)?, &chapter(6)?)
+            .range(&chapters)
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_incremental_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html.gz");
+        let chapter = |id: u32| {
+            format!(
+                r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title">Chapter {id}</a></h1><div class="chapter-content">Content {id}</div>"#
+            )
+        };
+
+        // Download compressed with `--gzip`.
+        let mut output = open_output(&path, HTML, None, true, false, 1, TITLE, false)
+            .await?
+            .unwrap();
+        output.write_chapter(&chapter(1)).await?;
+        output.shutdown().await?;
+
+        // Incrementally append.
+        let previous_download = read_previous_download(&path).await?;
+        assert!(previous_download.compressed);
+        let cached = cached_chapters(
+            &Selectors::default(),
+            Format::Html,
+            std::str::from_utf8(&previous_download.content)?,
+        );
+        assert_eq!(cached.len(), 1);
+        let mut output = open_output(
+            &path,
+            HTML,
+            Some(&previous_download),
+            false,
+            true,
+            2,
+            TITLE,
+            false,
+        )
+        .await?
+        .unwrap();
+        output.write_chapter(&chapter(2)).await?;
+        // Failed before finishing the stream. The file it was replacing is left as it was.
+        drop(output);
+        let previous_download = read_previous_download(&path).await?;
+        assert_eq!(
+            cached_chapters(
+                &Selectors::default(),
+                Format::Html,
+                std::str::from_utf8(&previous_download.content)?,
+            )
+            .len(),
+            1
+        );
+        let mut output = open_output(
+            &path,
+            HTML,
+            Some(&previous_download),
+            false,
+            true,
+            2,
+            TITLE,
+            false,
+        )
+        .await?
+        .unwrap();
+        output.write_chapter(&chapter(2)).await?;
+        output.write_chapter(&chapter(3)).await?;
+        output.shutdown().await?;
+
+        assert_eq!(std::fs::read(&path)?[..2], crate::GZIP_MAGIC);
+        assert!(!temp_path(&path).exists());
+        let content = String::from_utf8(read_previous_download(&path).await?.content)?;
+        assert_eq!(
+            content,
+            format!(
+                r#"<html><head><meta charset="UTF-8"><title>Title</title></head><body>{}{}{}</body></html>"#,
+                chapter(1),
+                chapter(2),
+                chapter(3)
+            )
+        );
+        assert_eq!(
+            cached_chapters(&Selectors::default(), Format::Html, &content).len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_replaces_edited_chapters() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let chapter = |id: u32, content: &str| -> anyhow::Result<Chapter> {
+            Ok(Chapter {
+                index: id as usize - 1,
+                title: format!("Chapter {id}"),
+                url: Url::parse(&format!(
+                    "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                ))?,
+                body_html: format!(r#"<div class="chapter-content"><p>{content}</p></div>"#),
+                published: None,
+                validators: Default::default(),
+            })
+        };
+        for kind in [Format::Html, Format::Markdown, Format::Text] {
+            let format = OutputFormat { kind, ..HTML };
+            let write = |path, chapters: Vec<Chapter>| async move {
+                let mut output = Output::create(path, format, false, TITLE).await?;
+                for chapter in chapters {
+                    output.write_chapter(&chapter.to_html()).await?;
+                }
+                output.shutdown().await?;
+                anyhow::Ok(std::fs::read_to_string(path)?)
+            };
+            let original = [chapter(1, "One")?, chapter(2, "Two")?, chapter(3, "Three")?];
+            let mut edited = original.clone();
+            edited[1] = chapter(2, "Two <em>edited</em>")?;
+
+            let path = dir.path().join(format!("original.{}", kind.extension()));
+            let previous = write(&path, original.to_vec()).await?;
+            let path = dir.path().join(format!("edited.{}", kind.extension()));
+            let expected = write(&path, edited.to_vec()).await?;
+            assert_eq!(
+                replace_chapters(format, &previous, &edited[..2]),
+                (expected, 1),
+                "{kind}"
+            );
+            assert_eq!(
+                replace_chapters(format, &previous, &original),
+                (previous, 0),
+                "{kind}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sidecar_offsets() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for kind in [Format::Html, Format::Markdown, Format::Text] {
+            let format = OutputFormat { kind, ..HTML };
+            let path = dir.path().join(format!("fiction.{}", kind.extension()));
+            let mut meta = OutputMeta::default();
+            let mut output = Output::create(&path, format, false, TITLE).await?;
+            for id in 1..=2 {
+                let chapter = Chapter {
+                    index: id - 1,
+                    title: format!("Chapter {id} – é"),
+                    url: Url::parse(&format!(
+                        "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                    ))?,
+                    body_html: format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#),
+                    published: None,
+                    validators: Default::default(),
+                };
+                let offset = output.write_chapter(&chapter.to_html()).await?;
+                meta.set(&chapter, Some(offset));
+            }
+            meta.end = Some(output.offset);
+            output.shutdown().await?;
+
+            let content = std::fs::read_to_string(&path)?;
+            let end = meta.end.unwrap() as usize;
+            assert_eq!(
+                meta.resume_offset(kind, content.as_bytes()),
+                Some(end),
+                "{kind}"
+            );
+            assert_eq!(
+                resume_offset(kind, content.as_bytes()).unwrap_or(content.len()),
+                end,
+                "{kind}"
+            );
+            let mut located = OutputMeta {
+                chapters: meta
+                    .chapters
+                    .iter()
+                    .map(|x| ChapterMeta {
+                        offset: None,
+                        ..x.clone()
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+            located.locate(format, &content);
+            assert_eq!(located, meta, "{kind}");
+
+            let edited = format!("edited {content}");
+            assert_eq!(meta.resume_offset(kind, edited.as_bytes()), None, "{kind}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn toc_grows_with_appended_chapters() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let selectors = Selectors::default();
+        let mut meta = OutputMeta::default();
+        /// Write chapters `ids` then the table of contents.
+        async fn write_chapters(
+            path: &Path,
+            mut output: Output,
+            mut meta: OutputMeta,
+            ids: RangeInclusive<usize>,
+        ) -> anyhow::Result<OutputMeta> {
+            for id in ids {
+                let chapter = Chapter {
+                    index: id - 1,
+                    title: format!("Chapter {id} <&>"),
+                    url: Url::parse(&format!(
+                        "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                    ))?,
+                    body_html: format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#),
+                    published: None,
+                    validators: Default::default(),
+                };
+                let offset = output.write_chapter(&chapter.to_html()).await?;
+                meta.set(&chapter, Some(offset));
+            }
+            meta.end = Some(output.offset);
+            output.shutdown().await?;
+            let shift =
+                write_toc(path, &Selectors::default(), encoding_rs::UTF_8, Some(&meta)).await?;
+            meta.shift(shift);
+            Ok(meta)
+        }
+
+        let output = Output::create(&path, HTML, false, TITLE).await?;
+        meta = write_chapters(&path, output, meta, 1..=2).await?;
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.contains(
+            r##"<nav id="toc"><h2>Contents</h2><ol><li><a href="#chapter-1">Chapter 1 &lt;&amp;&gt;</a></li><li><a href="#chapter-2">Chapter 2 &lt;&amp;&gt;</a></li></ol></nav><h1>"##
+        ));
+        assert!(content.contains(r#"id="chapter-2">Chapter 2 &lt;&amp;&gt;</a></h1>"#));
+
+        let mut previous_download = read_previous_download(&path).await?;
+        previous_download.saved_offset =
+            meta.resume_offset(Format::Html, &previous_download.content);
+        assert!(previous_download.saved_offset.is_some());
+        let output = open_output(
+            &path,
+            HTML,
+            Some(&previous_download),
+            false,
+            true,
+            1,
+            TITLE,
+            false,
+        )
+        .await?
+        .unwrap();
+        meta = write_chapters(&path, output, meta, 3..=3).await?;
+
+        let content = std::fs::read_to_string(&path)?;
+        assert_eq!(content.matches(r#"<nav id="toc">"#).count(), 1);
+        assert!(content
+            .contains(r##"<li><a href="#chapter-3">Chapter 3 &lt;&amp;&gt;</a></li></ol></nav>"##));
+        assert!(content.ends_with("</body></html>"));
+        assert_eq!(cached_chapters(&selectors, Format::Html, &content).len(), 3);
+        assert_eq!(
+            meta.resume_offset(Format::Html, content.as_bytes()),
+            resume_offset(Format::Html, content.as_bytes())
+        );
+        assert_eq!(meta.headings(content.as_bytes()).map(|x| x.len()), Some(3));
+        Ok(())
+    }
+
+    /// The table of contents is built from the headings at the saved offsets, and by parsing the output if they
+    /// aren't all saved.
+    #[test]
+    fn toc_from_saved_offsets() -> anyhow::Result<()> {
+        let selectors = Selectors::default();
+        let mut content = String::from("<html><body>");
+        let mut meta = OutputMeta::default();
+        for id in 1..=3 {
+            let chapter = Chapter {
+                index: id - 1,
+                title: format!("Chapter {id}"),
+                url: Url::parse(&format!(
+                    "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                ))?,
+                body_html: format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#),
+                published: None,
+                validators: Default::default(),
+            };
+            meta.set(&chapter, Some(content.len() as u64));
+            content += &chapter.to_html();
+        }
+        content += "</body></html>";
+        let content = content.as_bytes();
+
+        let headings = meta.headings(content).unwrap();
+        assert_eq!(headings.len(), 3);
+        assert!(headings[2].starts_with(br#"<h1><a class="chapter""#));
+        assert!(headings[2].ends_with(b"Chapter 3</a></h1>"));
+        let parsed = toc_html(&selectors, content, encoding_rs::UTF_8, None);
+        assert_eq!(
+            toc_html(&selectors, content, encoding_rs::UTF_8, Some(&meta)),
+            parsed
+        );
+        assert!(parsed.contains(r##"<li><a href="#chapter-3">Chapter 3</a></li>"##));
+
+        // A chapter missing from the metadata or moved since it was saved.
+        let last = meta.chapters.pop().unwrap();
+        assert!(meta.headings(content).is_none());
+        meta.chapters.push(last);
+        meta.shift(1);
+        assert!(meta.headings(content).is_none());
+        assert_eq!(
+            toc_html(&selectors, content, encoding_rs::UTF_8, Some(&meta)),
+            parsed
+        );
+        Ok(())
+    }
+
+    /// Rewriting an output leaves it as it was if the replacement can't be written.
+    #[tokio::test]
+    async fn rewrite_keeps_output_on_failure() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        std::fs::write(&path, "previous")?;
+        let previous_download = PreviousDownload {
+            content: b"replaced".to_vec(),
+            compressed: false,
+            saved_offset: None,
+        };
+
+        // The replacement can't be created where a directory is.
+        std::fs::create_dir(temp_path(&path))?;
+        assert!(previous_download.write(&path).await.is_err());
+        assert_eq!(std::fs::read(&path)?, b"previous");
+
+        std::fs::remove_dir(temp_path(&path))?;
+        previous_download.write(&path).await?;
+        assert_eq!(std::fs::read(&path)?, b"replaced");
+        assert!(!temp_path(&path).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn markdown_incremental_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.md");
+        let format = OutputFormat {
+            kind: Format::Markdown,
+            encoding: encoding_rs::UTF_8,
+            wrap: 0,
+        };
+        let chapter = |id: u32| {
+            format!(
+                r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title">Chapter {id}</a></h1><div class="chapter-content"><p>Content <em>{id}</em></p></div>"#
+            )
+        };
+
+        let mut output = Output::create(&path, format, false, TITLE).await?;
+        output.write_chapter(&chapter(1)).await?;
+        output.shutdown().await?;
+
+        let previous_download = read_previous_download(&path).await?;
+        let cached = cached_chapters(
+            &Selectors::default(),
+            Format::Markdown,
+            std::str::from_utf8(&previous_download.content)?,
+        );
+        assert_eq!(cached.len(), 1);
+        let mut output = open_output(
+            &path,
+            format,
+            Some(&previous_download),
+            false,
+            true,
+            1,
+            TITLE,
+            false,
+        )
+        .await?
+        .unwrap();
+        output.write_chapter(&chapter(2)).await?;
+        output.shutdown().await?;
+
+        let content = std::fs::read_to_string(&path)?;
+        assert_eq!(
+            content,
+            "# [Chapter 1](https://www.royalroad.com/fiction/12345/the-title/chapter/1/chapter-title)\n\n\
+             Content *1*\n\n\
+             ---\n\n\
+             # [Chapter 2](https://www.royalroad.com/fiction/12345/the-title/chapter/2/chapter-title)\n\n\
+             Content *2*\n"
+        );
+        assert_eq!(
+            cached_chapters(&Selectors::default(), Format::Markdown, &content).len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sanitize_path_windows_names() {
+        assert_eq!(sanitize_path("Title: Subtitle"), "Title_ Subtitle");
+        assert!(matches!(sanitize_path("Title"), Cow::Borrowed("Title")));
+        assert_eq!(sanitize_path("CON"), "CON_");
+        assert_eq!(sanitize_path("nul.html"), "nul_.html");
+        assert_eq!(sanitize_path("PRN."), "PRN_");
+        assert_eq!(sanitize_path("Lpt1 .tar.gz"), "Lpt1_ .tar.gz");
+        assert_eq!(sanitize_path("Console"), "Console");
+        assert_eq!(sanitize_path("The End... "), "The End");
+        assert_eq!(sanitize_path("..."), "_");
+
+        assert_eq!(sanitize_path("a:::b"), "a_b");
+        assert_eq!(sanitize_path("a_:_/b"), "a_b");
+        assert_eq!(sanitize_path("a__b"), "a__b");
+
+        let long = format!("{}.html", "é".repeat(300));
+        let sanitized = sanitize_path(&long);
+        assert!(sanitized.len() <= MAX_FILE_NAME_LEN);
+        assert!(sanitized.starts_with("éééé"));
+        assert!(sanitized.ends_with("é.html"));
+    }
+
+    #[test]
+    fn fiction_titles() {
+        let parse = |args: &[&str]| {
+            options()
+                .to_options()
+                .run_inner(&[args, &["https://www.royalroad.com/fiction/12345"]].concat()[..])
+                .unwrap()
+        };
+        let title = "A | B | Royal Road";
+        assert_eq!(parse(&[]).fiction_title(&ROYAL_ROAD, title), "A | B");
+        assert_eq!(
+            parse(&["--title-suffix", " | B | Royal Road"]).fiction_title(&ROYAL_ROAD, title),
+            "A"
+        );
+        assert_eq!(
+            parse(&["--title-suffix", " | Mirror"]).fiction_title(&ROYAL_ROAD, title),
+            title
+        );
+        let keep = parse(&["--no-strip-title"]);
+        assert_eq!(keep.fiction_title(&ROYAL_ROAD, title), title);
+        assert_eq!(keep.chapter_title_suffix(title), "");
+        assert_eq!(parse(&[]).chapter_title_suffix(title), title);
+    }
+
+    #[test]
+    fn volume_paths() {
+        assert_eq!(
+            volume_path(Path::new("dir/The Title.html"), 2),
+            Path::new("dir/The Title - Vol 02.html")
+        );
+        assert_eq!(
+            volume_path(Path::new("The Title.md.gz"), 12),
+            Path::new("The Title - Vol 12.md.gz")
+        );
+        assert_eq!(
+            volume_path(Path::new("The Title"), 1),
+            Path::new("The Title - Vol 01")
+        );
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn sanitize_path_normalizes() {
+        let composed = "Caf\u{e9} Ame\u{301}lie";
+        let decomposed = "Cafe\u{301} Ame\u{301}lie";
+        assert_eq!(sanitize_path(decomposed), "Caf\u{e9} Am\u{e9}lie");
+        assert_eq!(sanitize_path(composed), sanitize_path(decomposed));
+        assert!(matches!(
+            sanitize_path("Caf\u{e9}"),
+            Cow::Borrowed("Caf\u{e9}")
+        ));
+    }
+
+    #[test]
+    fn text_cached_chapters() {
+        let content = (1..=2)
+            .map(|id| {
+                html_to_text(
+                    &format!(r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title">Chapter {id}</a></h1><p>Content</p>"#),
+                    10,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        assert_eq!(
+            cached_chapters(&Selectors::default(), Format::Text, &content),
+            [1, 2]
+                .map(|id| ChapterUrl(
+                    Url::parse(&format!(
+                        "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                    ))
+                    .unwrap()
+                ))
+                .to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn split_files_are_found_again() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let selectors = Selectors::default();
+        assert_eq!(
+            split_file_name(8, 120, "What? Why", Format::Html),
+            "009 - What_ Why.html"
+        );
+        assert_eq!(split_file_name(0, 9, "One", Format::Text), "1 - One.txt");
+
+        for kind in [Format::Html, Format::Markdown] {
+            let format = OutputFormat { kind, ..HTML };
+            let mut expected = Vec::new();
+            for id in [10, 2] {
+                let chapter = Chapter {
+                    index: id - 1,
+                    title: format!("Chapter {id}"),
+                    url: Url::parse(&format!(
+                        "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+                    ))?,
+                    body_html: format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#),
+                    published: None,
+                    validators: Default::default(),
+                };
+                let name = split_file_name(chapter.index, 10, &chapter.title, kind);
+                let mut output =
+                    Output::create(&dir.path().join(&name), format, false, TITLE).await?;
+                output.write_chapter(&chapter.to_html()).await?;
+                output.shutdown().await?;
+                expected.insert(0, (name, ChapterUrl(chapter.url)));
+            }
+            assert_eq!(
+                split_files(dir.path(), &selectors, kind, encoding_rs::UTF_8).await?,
+                expected,
+                "{kind}"
+            );
+        }
+        Ok(())
+    }
+
+    /// Response from `url` with `body` as if it was downloaded.
+    fn response(url: &Url, body: String) -> reqwest::Response {
+        use reqwest::ResponseBuilderExt;
+        http::Response::builder()
+            .url(url.clone())
+            .body(body)
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn parse_output_round_trips() -> anyhow::Result<()> {
+        let selectors = Selectors::default();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let url = |id: u32| {
+            Url::parse(&format!(
+                "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-title"
+            ))
+        };
+
+        let mut output = Output::create(&path, HTML, false, TITLE).await?;
+        for id in 1..=2 {
+            let chapter = chapter_response_to_content(
+                &selectors,
+                id as usize - 1,
+                &format!("{id}/2"),
+                response(
+                    &url(id)?,
+                    format!(
+                        r#"<html><head><title>Chapter {id} &amp; more - Title</title></head><body><div class="chapter-content"><p>Content {id}</p><p class="cjWarning">Stolen content warning</p></div></body></html>"#
+                    ),
+                ),
+                "Title",
+                Processing::default(),
+            )
+            .await?;
+            output.write_chapter(&chapter.to_html()).await?;
+        }
+        output.shutdown().await?;
+
+        let parsed = parse_output(&std::fs::read_to_string(&path)?);
+        assert_eq!(
+            parsed,
+            (1..=2)
+                .map(|id| Ok(ParsedChapter {
+                    title: format!("Chapter {id} & more"),
+                    url: url(id)?,
+                    content: format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#),
+                }))
+                .collect::<Result<Vec<_>, url::ParseError>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_chapters() {
+        let content = include_str!("../tests/fixtures/duplicate_chapters.html");
+        let titles = |content: &str| {
+            parse_output(content)
+                .into_iter()
+                .map(|x| x.title)
+                .collect::<Vec<_>>()
+        };
+        // Without saved indices chapters stay in the order they were first written.
+        let (deduped, removed) = dedupe(content, &OutputMeta::default());
+        assert_eq!(removed, 1);
+        assert_eq!(titles(&deduped), ["Chapter 1", "Chapter 3", "Chapter 2"]);
+        assert!(deduped.ends_with(crate::END_HTML));
+
+        let meta = OutputMeta {
+            chapters: [(1, 0), (2, 1), (3, 2)]
+                .into_iter()
+                .map(|(id, index)| ChapterMeta {
+                    url: format!(
+                        "https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-{id}"
+                    ),
+                    index: Some(index),
+                    offset: None,
+                    validators: Default::default(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let (deduped, removed) = dedupe(content, &meta);
+        assert_eq!(removed, 1);
+        assert_eq!(titles(&deduped), ["Chapter 1", "Chapter 2", "Chapter 3"]);
+        // Already deduped.
+        assert_eq!(dedupe(&deduped, &meta), (deduped.clone(), 0));
+    }
+
+    #[test]
+    fn truncated_last_chapter() {
+        let previous = |content: &str| PreviousDownload {
+            content: content.as_bytes().to_vec(),
+            compressed: false,
+            saved_offset: None,
+        };
+        let url = |id: u32| {
+            format!("https://www.royalroad.com/fiction/12345/the-title/chapter/{id}/chapter-{id}")
+        };
+        let chapter = |id: u32| {
+            format!(
+                r#"<h1><a class="chapter" href="{}" id="chapter-{id}">Chapter {id}</a></h1><div class="chapter-content"><p>Content {id}</p></div>"#,
+                url(id)
+            )
+        };
+        let start = r#"<html><head><meta charset="UTF-8"></head><body>"#;
+        let complete = format!("{start}{}{}</body></html>", chapter(1), chapter(2));
+        let mut download = previous(&complete);
+        assert_eq!(
+            drop_truncated_chapter(Format::Html, encoding_rs::UTF_8, &mut download),
+            None
+        );
+        assert_eq!(download.content, complete.as_bytes());
+
+        // Interrupted while writing the second chapter over the end of the output.
+        let chapter_2 = chapter(2);
+        let truncated = format!(
+            "{start}{}{}",
+            chapter(1),
+            &chapter_2[..chapter_2.len() - 20]
+        );
+        let mut download = previous(&truncated);
+        assert_eq!(
+            drop_truncated_chapter(Format::Html, encoding_rs::UTF_8, &mut download),
+            Some(ChapterUrl(Url::parse(&url(2)).unwrap()))
+        );
+        assert_eq!(
+            String::from_utf8(download.content).unwrap(),
+            format!("{start}{}</body></html>", chapter(1))
+        );
+
+        let markdown = format!(
+            "# [Chapter 1]({})\n\nContent 1\n\n---\n\n# [Chapter 2]({})\n",
+            url(1),
+            url(2)
+        );
+        let mut download = previous(&markdown);
+        assert_eq!(
+            drop_truncated_chapter(Format::Markdown, encoding_rs::UTF_8, &mut download),
+            Some(ChapterUrl(Url::parse(&url(2)).unwrap()))
+        );
+        assert_eq!(
+            String::from_utf8(download.content).unwrap(),
+            format!("# [Chapter 1]({})\n\nContent 1\n", url(1))
+        );
+    }
 
     #[test]
-    fn chapter_url_partial_eq() -> anyhow::Result<()> {
-        let chapter_1 = ChapterUrl(Url::parse(
-            "https://www.royalroad.com/fiction/12345/the-title/chapter/1234567/chapter_title",
-        )?);
-        let chapter_2 = ChapterUrl(Url::parse("https://www.royalroad.com/fiction/12345/the-title-but-different/chapter/1234567/chapter_title")?);
-        assert_eq!(chapter_1, chapter_2);
-        assert_ne!(chapter_1.0, chapter_2.0);
+    fn repair_corrupted_outputs() {
+        for corrupted in [
+            include_str!("../tests/fixtures/corrupted_duplicate_end.html"),
+            include_str!("../tests/fixtures/corrupted_bom_unterminated.html"),
+        ] {
+            let repaired = String::from_utf8(repair(corrupted.as_bytes())).unwrap();
+            assert!(!repaired.contains('\u{feff}'));
+            assert_eq!(repaired.matches("</body>").count(), 1);
+            assert_eq!(repaired.matches("</html>").count(), 1);
+            assert!(repaired.ends_with(crate::END_HTML));
+            assert_eq!(
+                parse_output(&repaired)
+                    .into_iter()
+                    .map(|x| x.title)
+                    .collect::<Vec<_>>(),
+                vec!["Chapter 1", "Chapter 2", "Chapter 3"]
+            );
+            // Already repaired.
+            assert_eq!(repair(repaired.as_bytes()), repaired.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn repair_assume_yes() -> anyhow::Result<()> {
+        let parse = |args: &[&str]| cli().run_inner(args).unwrap();
+        assert!(matches!(
+            parse(&["--repair", "fiction.html", "-y"]),
+            Cli::Repair {
+                assume_yes: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(&["--dedupe", "fiction.html"]),
+            Cli::Dedupe {
+                assume_yes: false,
+                ..
+            }
+        ));
+
+        // An existing backup is replaced without asking.
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let backup_path = dir.path().join("fiction.html.bk");
+        let corrupted = include_str!("../tests/fixtures/corrupted_duplicate_end.html");
+        std::fs::write(&path, corrupted)?;
+        std::fs::write(&backup_path, "older")?;
+        repair_output(&path, true).await?;
+        assert_eq!(std::fs::read_to_string(&backup_path)?, corrupted);
+        assert_eq!(std::fs::read(&path)?, repair(corrupted.as_bytes()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fictions_share_rate_limit() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::path_regex("^/fiction/[0-9]+/the-title$"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .mount(&server)
+            .await;
+        let opt = options()
+            .to_options()
+            .run_inner(&["--check", "https://www.royalroad.com/fiction/1/the-title"][..])
+            .unwrap();
+        let client = http_client(&opt, Arc::default());
+        let selectors = Arc::new((ROYAL_ROAD.selectors)());
+        let limiter = Arc::new(Limiter::new(Duration::from_millis(300), Duration::ZERO));
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let interrupt = Interrupt(rx);
+
+        // Checking for new chapters only gets the main page of each fiction.
+        let start = Instant::now();
+        for id in 1..=2 {
+            let url = Url::parse(&server.uri())?.join(&format!("/fiction/{id}/the-title"))?;
+            let new_chapters = download(
+                &client,
+                &Arc::default(),
+                &ROYAL_ROAD,
+                &selectors,
+                &limiter,
+                &opt,
+                &url,
+                &interrupt,
+            )
+            .await?;
+            assert_eq!(new_chapters, 2);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follows_keep_going() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/my/follows"))
+            .and(matchers::query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/follows_page_2.html")),
+            )
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/my/follows"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/follows_page_1.html")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/my/reading-list"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_string(include_str!("../tests/fixtures/cloudflare_challenge.html")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path_regex("^/fiction/[0-9]+/[a-z-]+$"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .expect(3)
+            .mount(&server)
+            .await;
+        let base = Url::parse(&server.uri())?;
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let run = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend(["--time-limit", "1", "--jitter", "0"]);
+            let opt = options().to_options().run_inner(&args[..]).unwrap();
+            let interrupt = Interrupt(rx.clone());
+            async move { crate::run(opt, &interrupt).await }
+        };
+        let follows = base.join("/my/follows")?.to_string();
+        let reading_list = base.join("/my/reading-list")?.to_string();
+
+        // Without `--keep-going` the unreadable page stops the batch before any fiction.
+        assert!(run(&["--follows", "--check", &reading_list, &follows])
+            .await
+            .is_err());
+        // `--check` needs `--incremental`, which `--follows` implies.
+        let e = run(&[
+            "--follows",
+            "--check",
+            "--keep-going",
+            &reading_list,
+            &follows,
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "1 follows pages couldn't be read and 0 of 3 fictions failed to download"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bundle_dir_saves_images() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path_regex("^/fiction/12345/the-title/chapter/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"<html><head><title>Chapter</title></head><body><div class="chapter-content"><img src="/banner.png"></div></body></html>"#,
+            ))
+            .mount(&server)
+            .await;
+        for path in ["/covers/12345.png", "/banner.png"] {
+            Mock::given(matchers::path(path))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("content-type", "image/png")
+                        .set_body_bytes(path.as_bytes()),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+        let dir = tempfile::tempdir()?;
+        let bundle = dir.path().join("bundle");
+        let opt = options()
+            .to_options()
+            .run_inner(
+                &[
+                    "--bundle-dir",
+                    "--embed-images",
+                    "--time-limit",
+                    "1",
+                    "--jitter",
+                    "0",
+                    "--path",
+                    bundle.to_str().unwrap(),
+                    "https://www.royalroad.com/fiction/12345/the-title",
+                ][..],
+            )
+            .unwrap();
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        download(
+            &http_client(&opt, Arc::default()),
+            &Arc::default(),
+            &ROYAL_ROAD,
+            &Arc::new((ROYAL_ROAD.selectors)()),
+            &Arc::new(Limiter::new(Duration::from_millis(1), Duration::ZERO)),
+            &opt,
+            &url,
+            &Interrupt(rx),
+        )
+        .await?;
+
+        let index = std::fs::read_to_string(bundle.join("index.html"))?;
+        assert!(index.contains(r#"<img class="cover" src="images/cover.png" alt="Cover">"#));
+        assert_eq!(
+            std::fs::read(bundle.join("images/cover.png"))?,
+            b"/covers/12345.png"
+        );
+        let images = std::fs::read_dir(bundle.join("images"))?
+            .map(|x| Ok(x?.file_name().into_string().unwrap()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let banner = images.iter().find(|x| *x != "cover.png").unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(
+            index
+                .matches(&format!(r#"<img src="images/{banner}">"#))
+                .count(),
+            2
+        );
+        assert!(bundle.join("metadata.json").exists());
+
+        let opt = options()
+            .to_options()
+            .run_inner(
+                &[
+                    "--bundle-dir",
+                    "--format",
+                    "epub",
+                    "https://www.royalroad.com/fiction/12345/the-title",
+                ][..],
+            )
+            .unwrap();
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        assert_eq!(
+            crate::run(opt, &Interrupt(rx))
+                .await
+                .unwrap_err()
+                .to_string(),
+            "`--bundle-dir` can only be used with `--format html`."
+        );
+        Ok(())
+    }
+
+    /// `--refresh` backs up the output before replacing edited chapters in it.
+    #[tokio::test]
+    async fn refresh_backs_up_output() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        let mount = |content: &'static str| {
+            let server = &server;
+            async move {
+                server.reset().await;
+                Mock::given(matchers::path("/fiction/12345/the-title"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+                    )
+                    .mount(server)
+                    .await;
+                Mock::given(matchers::path_regex("^/fiction/12345/the-title/chapter/"))
+                    .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                        r#"<html><head><title>Chapter</title></head><body><div class="chapter-content">{content}</div></body></html>"#
+                    )))
+                    .mount(server)
+                    .await;
+            }
+        };
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html");
+        let run = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend([
+                "--time-limit",
+                "1",
+                "--jitter",
+                "0",
+                "--path",
+                path.to_str().unwrap(),
+                "https://www.royalroad.com/fiction/12345/the-title",
+            ]);
+            let opt = options().to_options().run_inner(&args[..]).unwrap();
+            let url = &url;
+            async move {
+                let (_tx, rx) = tokio::sync::watch::channel(false);
+                download(
+                    &http_client(&opt, Arc::default()),
+                    &Arc::default(),
+                    &ROYAL_ROAD,
+                    &Arc::new((ROYAL_ROAD.selectors)()),
+                    &Arc::new(Limiter::new(Duration::from_millis(1), Duration::ZERO)),
+                    &opt,
+                    url,
+                    &Interrupt(rx),
+                )
+                .await
+            }
+        };
+
+        mount("Original").await;
+        run(&[]).await?;
+        let original = std::fs::read(&path)?;
+        mount("Edited").await;
+        run(&["--incremental", "--refresh"]).await?;
+
+        let refreshed = std::fs::read_to_string(&path)?;
+        assert!(refreshed.contains("Edited"));
+        assert!(!refreshed.contains("Original"));
+        let mut backup_path = path.clone().into_os_string();
+        backup_path.push(".bk");
+        assert_eq!(std::fs::read(backup_path)?, original);
+        assert!(!temp_path(&path).exists());
+        Ok(())
+    }
+
+    /// Time of an incremental download of one new chapter to a gzipped output.
+    ///
+    /// The chapter's response is delayed to stand in for network latency, which overlaps with rewriting the previous chapters of the output.
+    /// Run with `cargo test --release -- --ignored --nocapture incremental_download_time`.
+    #[tokio::test]
+    #[ignore = "timing benchmark"]
+    async fn incremental_download_time() -> anyhow::Result<()> {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        const LATENCY: Duration = Duration::from_millis(300);
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/fiction/12345/the-title"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../tests/fixtures/toc_promo.html")),
+            )
+            .mount(&server)
+            .await;
+        // A long first chapter so the previous output takes a while to rewrite.
+        let words = "<p>Some words of a long chapter.</p>".repeat(300_000);
+        Mock::given(matchers::path(
+            "/fiction/12345/the-title/chapter/1000001/chapter-1",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+            r#"<html><head><title>Chapter 1</title></head><body><div class="chapter-content">{words}</div></body></html>"#
+        )))
+        .mount(&server)
+        .await;
+        Mock::given(matchers::path(
+            "/fiction/12345/the-title/chapter/1000002/chapter-2",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string(r#"<html><head><title>Chapter 2</title></head><body><div class="chapter-content"><p>New</p></div></body></html>"#)
+                .set_delay(LATENCY),
+        )
+        .mount(&server)
+        .await;
+        let url = Url::parse(&server.uri())?.join("/fiction/12345/the-title")?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("fiction.html.gz");
+
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let interrupt = Interrupt(rx);
+        let selectors = Arc::new((ROYAL_ROAD.selectors)());
+        let download_with = |args: &[&str]| {
+            let mut args = args.to_vec();
+            let path = path.to_str().unwrap();
+            args.extend([
+                "-y",
+                "--gzip",
+                "--no-cover",
+                "--time-limit",
+                "1",
+                "--jitter",
+                "0",
+                "--path",
+                path,
+                "https://www.royalroad.com/fiction/12345/the-title",
+            ]);
+            let opt = options().to_options().run_inner(&args[..]).unwrap();
+            let client = http_client(&opt, Arc::default());
+            let limiter = Arc::new(Limiter::new(Duration::from_millis(1), Duration::ZERO));
+            let (url, selectors, interrupt) = (url.clone(), selectors.clone(), interrupt.clone());
+            async move {
+                download(
+                    &client,
+                    &Arc::default(),
+                    &ROYAL_ROAD,
+                    &selectors,
+                    &limiter,
+                    &opt,
+                    &url,
+                    &interrupt,
+                )
+                .await
+            }
+        };
+
+        let mut times = Vec::new();
+        for _ in 0..5 {
+            assert_eq!(download_with(&["--to", "1"]).await?, 1);
+            let start = Instant::now();
+            assert_eq!(download_with(&["--incremental"]).await?, 1);
+            times.push(start.elapsed());
+        }
+        times.sort();
+        println!(
+            "download took {:?} (median of {:?}) with {LATENCY:?} latency",
+            times[times.len() / 2],
+            times
+        );
         Ok(())
     }
 }
@@ -0,0 +1,231 @@
+//! Markdown output
+
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+
+/// Convert chapter html to Markdown.
+///
+/// Headers, paragraphs, emphasis, links, lists, and block quotes are converted. Other tags are stripped keeping their text.
+pub fn html_to_markdown(html: &str) -> String {
+    let html = Html::parse_fragment(html);
+    let mut converter = Converter::default();
+    converter.children(*html.root_element());
+    let mut out = converter.finish().join("\n\n");
+    out.push('\n');
+    out
+}
+
+/// Elements which start a new block of text.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "article", "div", "footer", "header", "main", "section", "table", "tr",
+];
+
+#[derive(Default)]
+struct Converter {
+    blocks: Vec<String>,
+    /// Text of the block being built.
+    inline: String,
+}
+
+impl Converter {
+    /// End the current block of text.
+    fn flush(&mut self) {
+        let block = self.inline.trim();
+        if !block.is_empty() {
+            self.blocks.push(block.to_owned());
+        }
+        self.inline.clear();
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        self.blocks
+    }
+
+    fn children(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    /// Convert the children of `node` as inline text.
+    fn inline_children(&mut self, node: NodeRef<Node>) -> String {
+        let outer = std::mem::take(&mut self.inline);
+        self.children(node);
+        std::mem::replace(&mut self.inline, outer)
+    }
+
+    /// Convert the children of `node` as separate blocks.
+    fn block_children(node: NodeRef<Node>) -> Vec<String> {
+        let mut converter = Converter::default();
+        converter.children(node);
+        converter.finish()
+    }
+
+    fn node(&mut self, node: NodeRef<Node>) {
+        let element = match node.value() {
+            Node::Text(text) => {
+                self.text(text);
+                return;
+            }
+            Node::Element(element) => element,
+            _ => return,
+        };
+        match element.name() {
+            name @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => {
+                self.flush();
+                let level = name[1..].parse().unwrap();
+                let text = self.inline_children(node);
+                self.blocks
+                    .push(format!("{} {}", "#".repeat(level), text.trim()));
+            }
+            "p" => {
+                self.flush();
+                self.children(node);
+                self.flush();
+            }
+            "br" => self.inline.push_str("\\\n"),
+            "hr" => {
+                self.flush();
+                // Not `---` which separates chapters.
+                self.blocks.push("* * *".to_owned());
+            }
+            "em" | "i" => {
+                let text = self.inline_children(node);
+                self.inline.push_str(&wrap(&text, "*"));
+            }
+            "strong" | "b" => {
+                let text = self.inline_children(node);
+                self.inline.push_str(&wrap(&text, "**"));
+            }
+            "a" => {
+                let text = self.inline_children(node);
+                match element.attr("href") {
+                    Some(href) => self.inline.push_str(&link(text.trim(), href)),
+                    None => self.inline.push_str(&text),
+                }
+            }
+            "blockquote" => {
+                self.flush();
+                let quote = Self::block_children(node).join("\n\n");
+                self.blocks.push(
+                    quote
+                        .lines()
+                        .map(|x| {
+                            if x.is_empty() {
+                                ">".to_owned()
+                            } else {
+                                format!("> {x}")
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                );
+            }
+            list @ ("ul" | "ol") => {
+                self.flush();
+                let items = node
+                    .children()
+                    .filter(|x| x.value().as_element().is_some_and(|x| x.name() == "li"))
+                    .enumerate()
+                    .map(|(i, item)| {
+                        let marker = if list == "ol" {
+                            format!("{}. ", i + 1)
+                        } else {
+                            "- ".to_owned()
+                        };
+                        let indent = " ".repeat(marker.len());
+                        let content = Self::block_children(item).join("\n\n");
+                        let mut lines = content.lines();
+                        let mut out = format!("{marker}{}", lines.next().unwrap_or_default());
+                        for line in lines {
+                            out.push('\n');
+                            if !line.is_empty() {
+                                out.push_str(&indent);
+                                out.push_str(line);
+                            }
+                        }
+                        out
+                    })
+                    .collect::<Vec<_>>();
+                if !items.is_empty() {
+                    self.blocks.push(items.join("\n"));
+                }
+            }
+            "script" | "style" | "head" => {}
+            name if BLOCK_ELEMENTS.contains(&name) => {
+                self.flush();
+                self.children(node);
+                self.flush();
+            }
+            _ => self.children(node),
+        }
+    }
+
+    /// Add text collapsing whitespace like html would.
+    fn text(&mut self, text: &str) {
+        for (i, word) in text.split(|x: char| x.is_ascii_whitespace()).enumerate() {
+            if i != 0 && !self.inline.is_empty() && !self.inline.ends_with([' ', '\n']) {
+                self.inline.push(' ');
+            }
+            self.inline.push_str(&escape(word));
+        }
+    }
+}
+
+/// Escape characters Markdown would interpret as formatting.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '[' | ']' | '`') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Wrap `text` with the emphasis `marker` keeping surrounding whitespace outside of it.
+fn wrap(text: &str, marker: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return text.to_owned();
+    }
+    let before = if text.starts_with(trimmed) { "" } else { " " };
+    let after = if text.ends_with(trimmed) { "" } else { " " };
+    format!("{before}{marker}{trimmed}{marker}{after}")
+}
+
+/// Markdown link to `href` with already converted `text`.
+fn link(text: &str, href: &str) -> String {
+    if href.contains(|x: char| x.is_whitespace() || x == '(' || x == ')') {
+        format!("[{text}](<{href}>)")
+    } else {
+        format!("[{text}]({href})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_markdown;
+
+    #[test]
+    fn converts_chapter() {
+        let html = r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/1/t/chapter/2/c">Chapter [2]</a></h1><div class="chapter-content">
+            <p>Some <em>emphasized</em> and <strong> strong </strong>text_here.</p>
+            <p>Line<br>break <span class="unknown">kept</span></p>
+            <blockquote><p>Quoted</p><p>Twice</p></blockquote>
+            <ul><li>One</li><li><p>Two</p></li></ul>
+            <hr><script>ignored()</script>
+        </div>"#;
+        assert_eq!(
+            html_to_markdown(html),
+            "# [Chapter \\[2\\]](https://www.royalroad.com/fiction/1/t/chapter/2/c)\n\n\
+             Some *emphasized* and **strong** text\\_here.\n\n\
+             Line\\\nbreak kept\n\n\
+             > Quoted\n>\n> Twice\n\n\
+             - One\n- Two\n\n\
+             * * *\n"
+        );
+    }
+}
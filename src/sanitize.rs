@@ -0,0 +1,124 @@
+//! Sanitization of chapter content
+//!
+//! Chapter content is written as is, so anything on the chapter's page that runs when the output is opened in a
+//! browser is removed: script-like elements, redirects, SVG animations, event handler attributes, and `javascript:` urls.
+
+use ego_tree::NodeId;
+use scraper::{Html, Node};
+
+/// Elements removed with their content.
+///
+/// `meta` can redirect (`http-equiv="refresh"`) to a `javascript:` url, and the SVG animation elements can set any
+/// attribute (e.g. `href`) to one with their `to` or `values` attributes.
+const REMOVED_ELEMENTS: &[&str] = &[
+    "animate",
+    "animateMotion",
+    "animateTransform",
+    "applet",
+    "base",
+    "embed",
+    "frame",
+    "frameset",
+    "iframe",
+    "meta",
+    "noscript",
+    "object",
+    "script",
+    "set",
+];
+
+/// Attributes holding urls which may be `javascript:` urls, by local name so `xlink:href` is included.
+const URL_ATTRIBUTES: &[&str] = &["action", "formaction", "href", "src"];
+
+/// If `url` runs script when followed. Browsers ignore whitespace and control characters in the scheme.
+fn is_script_url(url: &str) -> bool {
+    let scheme = url
+        .chars()
+        .filter(|x| !x.is_ascii_whitespace() && !x.is_ascii_control())
+        .take("javascript:".len())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    scheme.starts_with("javascript:") || scheme.starts_with("vbscript:")
+}
+
+/// If the attribute with local name `name` and `value` runs script.
+fn is_script(name: &str, value: &str) -> bool {
+    name.to_ascii_lowercase().starts_with("on")
+        || (URL_ATTRIBUTES.contains(&name) && is_script_url(value))
+}
+
+/// Remove what runs script from inside the element `root` of `html`.
+pub fn sanitize(html: &mut Html, root: NodeId) {
+    let mut removed = Vec::new();
+    let mut elements = Vec::new();
+    for node in html.tree.get(root).unwrap().descendants() {
+        let Some(element) = node.value().as_element() else {
+            continue;
+        };
+        if REMOVED_ELEMENTS.contains(&element.name()) {
+            removed.push(node.id());
+        } else if element.attrs().any(|(name, value)| is_script(name, value)) {
+            elements.push(node.id());
+        }
+    }
+    for id in removed {
+        html.tree.get_mut(id).unwrap().detach();
+    }
+    for id in elements {
+        let mut node = html.tree.get_mut(id).unwrap();
+        let Node::Element(element) = node.value() else {
+            unreachable!("only elements are edited")
+        };
+        element
+            .attrs
+            .retain(|name, value| !is_script(&name.local, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+    use scraper::{Html, Selector};
+
+    #[test]
+    fn sanitize_chapter() {
+        let mut html = Html::parse_fragment(
+            r#"<div class="chapter-content"><p onclick="steal()">One <a href="https://example.com" onmouseover="steal()">two</a></p><script>steal()</script><iframe src="https://example.com"></iframe><a href=" Java&#9;Script:steal()">three</a><img src="image.png" onerror="steal()"><object data="x.swf"><p>four</p></object></div>"#,
+        );
+        let root = html
+            .select(&Selector::parse(".chapter-content").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        sanitize(&mut html, root);
+        assert_eq!(
+            html.tree
+                .get(root)
+                .and_then(scraper::ElementRef::wrap)
+                .unwrap()
+                .html(),
+            r#"<div class="chapter-content"><p>One <a href="https://example.com">two</a></p><a>three</a><img src="image.png"></div>"#
+        );
+    }
+
+    #[test]
+    fn sanitize_redirects_and_animations() {
+        let mut html = Html::parse_fragment(
+            r#"<div class="chapter-content"><meta http-equiv="refresh" content="0;url=javascript:steal()"><p>One</p><svg><a><animate attributeName="href" values="javascript:steal()"/><set attributeName="href" to="javascript:steal()"/><animateMotion path="M0,0"/><text>two</text></a></svg></div>"#,
+        );
+        let root = html
+            .select(&Selector::parse(".chapter-content").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        sanitize(&mut html, root);
+        assert_eq!(
+            html.tree
+                .get(root)
+                .and_then(scraper::ElementRef::wrap)
+                .unwrap()
+                .html(),
+            r#"<div class="chapter-content"><p>One</p><svg><a><text>two</text></a></svg></div>"#
+        );
+    }
+}
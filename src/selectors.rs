@@ -1,35 +1,129 @@
 //! Selectors for content
 
-use scraper::{selector, Selector};
-use std::sync::OnceLock;
+use scraper::Selector;
 
-pub fn title() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    CELL.get_or_init(|| selector::Selector::parse("title").unwrap())
+/// CSS selector that couldn't be parsed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid CSS selector {css:?}: {message}")]
+pub struct SelectorError {
+    pub css: String,
+    /// Why it couldn't be parsed.
+    pub message: String,
 }
-/// Select chapters from urls table of contents.
-pub fn chapter_links() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    CELL.get_or_init(|| {
-        selector::Selector::parse(r#"#chapters tr[data-url^="/fiction/"]"#).unwrap()
+
+/// Parse the CSS selector `css`, such as one given by a user.
+pub fn parse(css: &str) -> Result<Selector, SelectorError> {
+    Selector::parse(css).map_err(|e| SelectorError {
+        css: css.to_owned(),
+        message: e.to_string(),
     })
 }
-pub fn chapter_content() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    CELL.get_or_init(|| selector::Selector::parse("div.chapter-content").unwrap())
+
+/// Parse a built-in selector, which is known to be valid.
+fn builtin(css: &str) -> Selector {
+    parse(css).unwrap_or_else(|e| panic!("built-in selector is valid: {e}"))
+}
+
+/// Compiled selectors used to find content in pages.
+///
+/// Constructed once and passed to everything that scrapes pages so the set can be swapped out.
+/// The default selectors scrape Royal Road. Other sites give theirs in their [`Site`](crate::site::Site).
+#[derive(Debug, Clone)]
+pub struct Selectors {
+    /// Select the title of a page. Chapter titles are taken from it, without the fiction's title, when their heading
+    /// and [`Selectors::chapter_meta_title`] aren't found.
+    pub title: Selector,
+    /// Select the name of a chapter in the heading of its page, which is used as is. `None` if pages have none.
+    pub chapter_heading: Option<Selector>,
+    /// Select the meta tag with the title of a chapter's page (e.g. `og:title`), used like the page title when its
+    /// heading isn't found. `None` if pages have none.
+    pub chapter_meta_title: Option<Selector>,
+    /// Select chapters from urls table of contents.
+    pub chapter_links: Selector,
+    /// Select the link with the title of a chapter in a table of contents row.
+    pub chapter_link_title: Selector,
+    pub chapter_content: Selector,
+    /// Select the time a chapter was published on its page.
+    pub chapter_time: Selector,
+    pub paragraphs: Selector,
+    /// Warning paragraphs are always included in html. They are hidden by inline css matching this.
+    /// `None` keeps them in chapter content.
+    pub warning_paragraphs: Option<Selector>,
+    /// Select the author notes before, after, or inside chapter content. `None` if the site has none.
+    pub author_notes: Option<Selector>,
+    /// Select chapter links from previously downloaded output.
+    pub downloaded_chapters: Selector,
+    /// Select fiction links from a follows/reading list page.
+    pub follows_fictions: Selector,
+    /// Select the link to the next page of a paginated list.
+    pub next_page: Selector,
+    /// Select the age confirmation shown instead of an age restricted page.
+    ///
+    /// The default is a guess at the site's markup that wasn't checked against a real age confirmation page.
+    pub age_gate: Selector,
+    /// Select the cover image or the meta tag linking to it.
+    pub cover: Selector,
+    /// Select images in chapter content.
+    pub images: Selector,
+    /// Select the author link in the fiction header or the meta tag naming them.
+    pub author: Selector,
+    /// Select the fiction description or the meta tag summarizing it.
+    pub description: Selector,
+    /// Select the tags/genres of a fiction.
+    pub tags: Selector,
+    /// Select labels in the fiction header. One of them is the completion status.
+    pub labels: Selector,
+}
+
+impl Default for Selectors {
+    fn default() -> Self {
+        Self {
+            title: builtin("title"),
+            chapter_heading: Some(builtin(".fic-header h1")),
+            chapter_meta_title: Some(builtin(r#"meta[property="og:title"][content]"#)),
+            chapter_links: builtin(r#"#chapters tr[data-url^="/fiction/"]"#),
+            chapter_link_title: builtin("a"),
+            chapter_content: builtin("div.chapter-content"),
+            chapter_time: builtin("time[datetime], time[unixtime]"),
+            paragraphs: builtin("p"),
+            warning_paragraphs: Some(builtin(r#"[class^=cj],[class^=cm]"#)),
+            author_notes: Some(builtin(".author-note")),
+            downloaded_chapters: builtin(r#"h1 > a[class="chapter"][href]"#),
+            follows_fictions: builtin(r#".fiction-list-item .fiction-title a[href^="/fiction/"]"#),
+            next_page: builtin(r#".pagination a[rel="next"][href]"#),
+            age_gate: builtin(r#".age-gate, form[action*="confirm-age"]"#),
+            cover: builtin(r#"img.thumbnail[src], meta[property="og:image"][content]"#),
+            images: builtin("img[src]"),
+            author: builtin(
+                r#".fic-title h4 a[href^="/profile/"], meta[property="books:author"][content]"#,
+            ),
+            description: builtin(
+                r#".fiction-info .description, meta[property="og:description"][content]"#,
+            ),
+            tags: builtin(".tags .fiction-tag"),
+            labels: builtin(".fiction-info span.label"),
+        }
+    }
 }
-/* pub fn paragraphs() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    CELL.get_or_init(|| selector::Selector::parse("p").unwrap())
-} */
-pub fn warning_paragraphs() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    // Warning paragraphs are always included in html. They are hidden by inline css matching the following.
-    CELL.get_or_init(|| selector::Selector::parse(r#"[class^=cj],[class^=cm]"#).unwrap())
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Selectors};
+
+    #[test]
+    fn selector_errors() {
+        assert!(parse("p.warning").is_ok());
+        let e = parse("p[").unwrap_err();
+        assert_eq!(e.css, "p[");
+        assert!(e.to_string().starts_with(r#"invalid CSS selector "p[": "#));
+        // Built-in selectors are valid.
+        let _ = Selectors::default();
+    }
 }
+
 /*
 /// If paragraph content contains a warning
-/// Not needed because of [`warning_paragraphs()`] selector
+/// Not needed because of [`Selectors::warning_paragraphs`] selector
 pub fn is_warning(msg: &str) -> bool {
     static REGEX: OnceLock<Regex> = OnceLock::new();
     msg.len() < 150 && {
@@ -66,7 +160,3 @@ pub fn is_warning(msg: &str) -> bool {
     }
 }
  */
-pub fn downloaded_chapters() -> &'static Selector {
-    static CELL: OnceLock<Selector> = OnceLock::new();
-    CELL.get_or_init(|| selector::Selector::parse(r#"h1 > a[class="chapter"][href]"#).unwrap())
-}
@@ -0,0 +1,104 @@
+//! Sites fictions are downloaded from
+//!
+//! Each site is a profile of where its pages are and the [`Selectors`] which scrape them, so sites laid out like
+//! Royal Road (a table of contents linking to chapter pages) can be downloaded the same way.
+
+use crate::selectors::Selectors;
+use url::Url;
+
+/// Hosts, page conventions, and selectors of a site.
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    /// Name shown in messages.
+    pub name: &'static str,
+    /// Domains of the site. Their subdomains (e.g. `www.`) are included.
+    pub domains: &'static [&'static str],
+    /// Suffixes the site appends to the titles of its pages, current first.
+    pub title_suffixes: &'static [&'static str],
+    /// Main page of a fiction shown when a url isn't one.
+    pub example_url: &'static str,
+    /// First path segment of the main page of fictions, which is followed by their id (e.g. `fiction` in `/fiction/12345`).
+    pub fiction_segment: &'static str,
+    /// Path segment of chapter urls which is followed by the id of the chapter.
+    pub chapter_segment: &'static str,
+    /// Selectors finding content in the site's pages.
+    pub selectors: fn() -> Selectors,
+}
+
+/// Royal Road, including its legacy `royalroadl.com` domain.
+pub const ROYAL_ROAD: Site = Site {
+    name: "Royal Road",
+    domains: &["royalroad.com", "royalroadl.com"],
+    title_suffixes: &[" | Royal Road", " | RoyalRoadL"],
+    example_url: "https://www.royalroad.com/fiction/12345/the-title",
+    fiction_segment: "fiction",
+    chapter_segment: "chapter",
+    selectors: Selectors::default,
+};
+
+/// Sites that can be downloaded from.
+pub const SITES: &[Site] = &[ROYAL_ROAD];
+
+impl Site {
+    /// The supported site `url` is on, if any.
+    pub fn for_url(url: &Url) -> Option<&'static Site> {
+        SITES.iter().find(|x| x.matches(url))
+    }
+    /// If `url` is on one of the site's domains.
+    pub fn matches(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|host| {
+            self.domains.iter().any(|domain| {
+                host.strip_suffix(domain)
+                    .is_some_and(|x| x.is_empty() || x.ends_with('.'))
+            })
+        })
+    }
+    /// `title` without the suffix the site appends to page titles, if any.
+    pub fn strip_title_suffix<'a>(&self, title: &'a str) -> &'a str {
+        self.title_suffixes
+            .iter()
+            .find_map(|x| title.strip_suffix(x))
+            .unwrap_or(title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Site, ROYAL_ROAD};
+    use url::Url;
+
+    #[test]
+    fn royal_road_hosts() -> Result<(), url::ParseError> {
+        for url in [
+            "https://www.royalroad.com/fiction/12345",
+            "https://royalroad.com/fiction/12345",
+            "https://www.royalroadl.com/fiction/12345",
+        ] {
+            assert!(ROYAL_ROAD.matches(&Url::parse(url)?), "{url}");
+        }
+        for url in [
+            "https://example.com/fiction/12345",
+            "https://notroyalroad.com/fiction/12345",
+            "https://royalroad.com.example.com/fiction/12345",
+        ] {
+            assert!(!ROYAL_ROAD.matches(&Url::parse(url)?), "{url}");
+            assert!(Site::for_url(&Url::parse(url)?).is_none(), "{url}");
+        }
+        let url = Url::parse("https://www.royalroadl.com/fiction/12345")?;
+        assert_eq!(Site::for_url(&url).map(|x| x.name), Some("Royal Road"));
+        Ok(())
+    }
+
+    #[test]
+    fn title_suffixes() {
+        assert_eq!(
+            ROYAL_ROAD.strip_title_suffix("The Title | Royal Road"),
+            "The Title"
+        );
+        assert_eq!(
+            ROYAL_ROAD.strip_title_suffix("The Title | RoyalRoadL"),
+            "The Title"
+        );
+        assert_eq!(ROYAL_ROAD.strip_title_suffix("The Title"), "The Title");
+    }
+}
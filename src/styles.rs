@@ -0,0 +1,82 @@
+//! Presentational attributes of chapter content
+
+use ego_tree::NodeId;
+use scraper::{Html, Node};
+
+/// What is done with the `style`, `class`, and `id` attributes of elements in chapter content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Styles {
+    /// As they are on the chapter's page.
+    #[default]
+    Keep,
+    /// Removed from every element inside the chapter content, leaving plain semantic html.
+    ///
+    /// The `author-note` class of notes wrapped by [`AuthorNotes::Separate`](crate::author_notes::AuthorNotes::Separate)
+    /// is kept so they can still be styled.
+    Strip,
+}
+
+/// Attributes removed by [`Styles::Strip`].
+const STYLE_ATTRIBUTES: &[&str] = &["class", "id", "style"];
+
+impl Styles {
+    /// Apply to the elements inside the element `root` of `html`. `root` itself is left as it is.
+    pub fn apply(self, html: &mut Html, root: NodeId) {
+        if self == Self::Keep {
+            return;
+        }
+        let elements = html
+            .tree
+            .get(root)
+            .unwrap()
+            .descendants()
+            .skip(1)
+            .filter(|x| {
+                x.value().as_element().is_some_and(|x| {
+                    !(x.name() == "aside" && x.classes().any(|x| x == "author-note"))
+                        && x.attrs().any(|(name, _)| STYLE_ATTRIBUTES.contains(&name))
+                })
+            })
+            .map(|x| x.id())
+            .collect::<Vec<_>>();
+        for id in elements {
+            let mut node = html.tree.get_mut(id).unwrap();
+            let Node::Element(element) = node.value() else {
+                unreachable!("only elements are edited")
+            };
+            element
+                .attrs
+                .retain(|name, _| !STYLE_ATTRIBUTES.contains(&&*name.local));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Styles;
+    use scraper::{Html, Selector};
+
+    #[test]
+    fn strip_styles() {
+        let html = r#"<div class="chapter-content"><p id="p1" class="text" style="color: red">One <span style="font-weight: bold">two</span></p><aside class="author-note"><p class="note">Note</p></aside><img src="image.png" class="image"></div>"#;
+        let apply = |styles: Styles| {
+            let mut html = Html::parse_fragment(html);
+            let root = html
+                .select(&Selector::parse(".chapter-content").unwrap())
+                .next()
+                .unwrap()
+                .id();
+            styles.apply(&mut html, root);
+            html.tree
+                .get(root)
+                .and_then(scraper::ElementRef::wrap)
+                .unwrap()
+                .html()
+        };
+        assert_eq!(apply(Styles::Keep), html);
+        assert_eq!(
+            apply(Styles::Strip),
+            r#"<div class="chapter-content"><p>One <span>two</span></p><aside class="author-note"><p>Note</p></aside><img src="image.png"></div>"#
+        );
+    }
+}
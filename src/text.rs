@@ -0,0 +1,226 @@
+//! Plain text output
+
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+
+/// Convert chapter html to plain text hard wrapped to `wrap` columns. `0` indicates no wrapping.
+///
+/// Headings are underlined and followed by the url they link to, if any. Other tags are stripped keeping their text.
+pub fn html_to_text(html: &str, wrap: usize) -> String {
+    let html = Html::parse_fragment(html);
+    let mut converter = Converter::new(wrap);
+    converter.children(*html.root_element());
+    let mut out = converter.finish().join("\n\n");
+    out.push('\n');
+    out
+}
+
+/// Elements which start a new block of text.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "article", "div", "footer", "header", "main", "p", "section", "table", "tr",
+];
+
+struct Converter {
+    wrap: usize,
+    blocks: Vec<String>,
+    /// Text of the block being built.
+    inline: String,
+}
+
+impl Converter {
+    fn new(wrap: usize) -> Self {
+        Self {
+            wrap,
+            blocks: Vec::new(),
+            inline: String::new(),
+        }
+    }
+
+    /// End the current block of text.
+    fn flush(&mut self) {
+        let block = self.inline.trim();
+        if !block.is_empty() {
+            self.blocks.push(wrap(block, self.wrap));
+        }
+        self.inline.clear();
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        self.blocks
+    }
+
+    fn children(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    /// Convert the children of `node` as separate blocks indented by `indent` columns.
+    fn indented_children(&self, node: NodeRef<Node>, indent: usize) -> Vec<String> {
+        let mut converter = Converter::new(if self.wrap > indent {
+            self.wrap - indent
+        } else {
+            self.wrap
+        });
+        converter.children(node);
+        converter.finish()
+    }
+
+    fn node(&mut self, node: NodeRef<Node>) {
+        let element = match node.value() {
+            Node::Text(text) => {
+                self.text(text);
+                return;
+            }
+            Node::Element(element) => element,
+            _ => return,
+        };
+        match element.name() {
+            name @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => {
+                self.flush();
+                let outer = std::mem::take(&mut self.inline);
+                self.children(node);
+                let title = std::mem::replace(&mut self.inline, outer);
+                let title = title.trim();
+                let underline = if name == "h1" { "=" } else { "-" };
+                let mut heading = format!(
+                    "{title}\n{}",
+                    underline.repeat(title.chars().count().max(1))
+                );
+                let url = node
+                    .descendants()
+                    .filter_map(|x| x.value().as_element())
+                    .find(|x| x.name() == "a")
+                    .and_then(|x| x.attr("href"));
+                if let Some(url) = url {
+                    heading.push('\n');
+                    heading.push_str(url);
+                }
+                self.blocks.push(heading);
+            }
+            "br" => self.inline.push('\n'),
+            "hr" => {
+                self.flush();
+                self.blocks.push("* * *".to_owned());
+            }
+            "blockquote" => {
+                self.flush();
+                let quote = self.indented_children(node, 4).join("\n\n");
+                self.blocks.push(indent(&quote, "    ", "    "));
+            }
+            "ul" | "ol" => {
+                self.flush();
+                let items = node
+                    .children()
+                    .filter(|x| x.value().as_element().is_some_and(|x| x.name() == "li"))
+                    .enumerate()
+                    .map(|(i, item)| {
+                        let marker = if element.name() == "ol" {
+                            format!("{}. ", i + 1)
+                        } else {
+                            "- ".to_owned()
+                        };
+                        let content = self.indented_children(item, marker.len()).join("\n\n");
+                        indent(&content, &marker, &" ".repeat(marker.len()))
+                    })
+                    .collect::<Vec<_>>();
+                if !items.is_empty() {
+                    self.blocks.push(items.join("\n"));
+                }
+            }
+            "script" | "style" | "head" => {}
+            name if BLOCK_ELEMENTS.contains(&name) => {
+                self.flush();
+                self.children(node);
+                self.flush();
+            }
+            _ => self.children(node),
+        }
+    }
+
+    /// Add text collapsing whitespace like html would.
+    fn text(&mut self, text: &str) {
+        for (i, word) in text.split(|x: char| x.is_ascii_whitespace()).enumerate() {
+            if i != 0 && !self.inline.is_empty() && !self.inline.ends_with([' ', '\n']) {
+                self.inline.push(' ');
+            }
+            self.inline.push_str(word);
+        }
+    }
+}
+
+/// Prefix the first line of `text` with `first` and the rest with `rest`. Empty lines stay empty.
+fn indent(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("{}{line}", if i == 0 { first } else { rest })
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Hard wrap each line of `text` to `width` columns. `0` indicates no wrapping.
+///
+/// Words longer than `width` are left on their own line.
+fn wrap(text: &str, width: usize) -> String {
+    if width == 0 {
+        return text.to_owned();
+    }
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i != 0 {
+            out.push('\n');
+        }
+        let mut column = 0;
+        for word in line.split(' ').filter(|x| !x.is_empty()) {
+            let len = word.chars().count();
+            if column != 0 && column + 1 + len > width {
+                out.push('\n');
+                column = 0;
+            } else if column != 0 {
+                out.push(' ');
+                column += 1;
+            }
+            out.push_str(word);
+            column += len;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_text;
+
+    #[test]
+    fn converts_and_wraps_chapter() {
+        let html = r#"<h1><a class="chapter" href="https://www.royalroad.com/fiction/1/t/chapter/2/c">Chapter 2</a></h1><div class="chapter-content">
+            <p>The <em>quick</em> brown fox jumps over the lazy dog.</p>
+            <p>Line<br>break <span>kept</span></p>
+            <blockquote><p>Quoted text that wraps</p></blockquote>
+            <ul><li>One</li><li>Two</li></ul>
+        </div>"#;
+        assert_eq!(
+            html_to_text(html, 0),
+            "Chapter 2\n=========\nhttps://www.royalroad.com/fiction/1/t/chapter/2/c\n\n\
+             The quick brown fox jumps over the lazy dog.\n\n\
+             Line\nbreak kept\n\n    \
+             Quoted text that wraps\n\n\
+             - One\n- Two\n"
+        );
+        assert_eq!(
+            html_to_text(html, 16),
+            "Chapter 2\n=========\nhttps://www.royalroad.com/fiction/1/t/chapter/2/c\n\n\
+             The quick brown\nfox jumps over\nthe lazy dog.\n\n\
+             Line\nbreak kept\n\n    \
+             Quoted text\n    that wraps\n\n\
+             - One\n- Two\n"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Whitespace of chapter content
+
+use ego_tree::{NodeId, NodeRef};
+use scraper::{node::Text, Html, Node};
+use std::iter;
+
+/// How the whitespace of chapter content is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Whitespace {
+    /// As it is on the chapter's page.
+    #[default]
+    Keep,
+    /// Minified with block elements on their own lines indented by their depth.
+    Pretty,
+    /// Runs of whitespace collapsed to a space, whitespace next to block elements and comments removed.
+    Minify,
+}
+
+/// Elements laid out as blocks so whitespace next to them isn't rendered.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "center",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+/// Elements whose whitespace is rendered as is.
+const PREFORMATTED_ELEMENTS: &[&str] = &["pre", "script", "style", "textarea"];
+
+impl Whitespace {
+    /// Rewrite the whitespace inside the element `root` of `html`, which is treated as a block.
+    pub fn apply(self, html: &mut Html, root: NodeId) {
+        if self == Self::Keep {
+            return;
+        }
+        minify(html, root);
+        if self == Self::Pretty {
+            indent(html, root);
+        }
+    }
+}
+
+fn is_element(node: NodeRef<Node>, names: &[&str]) -> bool {
+    node.value()
+        .as_element()
+        .is_some_and(|x| names.contains(&x.name()))
+}
+
+/// If `node` is or is inside an element whose whitespace is rendered.
+fn is_preformatted(node: NodeRef<Node>) -> bool {
+    iter::once(node)
+        .chain(node.ancestors())
+        .any(|x| is_element(x, PREFORMATTED_ELEMENTS))
+}
+
+/// Collapse runs of ASCII whitespace to a single space.
+fn collapse(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            space = true;
+            continue;
+        }
+        if space {
+            out.push(' ');
+            space = false;
+        }
+        out.push(c);
+    }
+    if space {
+        out.push(' ');
+    }
+    out
+}
+
+fn minify(html: &mut Html, root: NodeId) {
+    let is_block = |x: NodeRef<Node>| x.id() == root || is_element(x, BLOCK_ELEMENTS);
+    // Replacement text of nodes, or `None` to remove them.
+    let mut edits = Vec::new();
+    for node in html.tree.get(root).unwrap().descendants() {
+        match node.value() {
+            Node::Comment(_) => edits.push((node.id(), None)),
+            Node::Text(text) if !is_preformatted(node) => {
+                // Whitespace at the start or end of a block or next to one isn't rendered.
+                let edge = |sibling: Option<NodeRef<Node>>| match sibling {
+                    Some(sibling) => is_block(sibling),
+                    None => node.parent().is_some_and(is_block),
+                };
+                let mut text = collapse(text);
+                if edge(node.prev_sibling()) {
+                    text = text.trim_start().to_owned();
+                }
+                if edge(node.next_sibling()) {
+                    text = text.trim_end().to_owned();
+                }
+                edits.push((node.id(), (!text.is_empty()).then_some(text)));
+            }
+            _ => {}
+        }
+    }
+    for (id, text) in edits {
+        let mut node = html.tree.get_mut(id).unwrap();
+        match text {
+            Some(text) => *node.value() = Node::Text(Text { text: text.into() }),
+            None => node.detach(),
+        }
+    }
+}
+
+fn indent(html: &mut Html, root: NodeId) {
+    let is_block = |x: NodeRef<Node>| x.id() == root || is_element(x, BLOCK_ELEMENTS);
+    // Blocks containing blocks with their depth below `root`.
+    let parents = html
+        .tree
+        .get(root)
+        .unwrap()
+        .descendants()
+        .filter(|&x| is_block(x) && !is_preformatted(x) && x.children().any(is_block))
+        .map(|x| {
+            let depth = x
+                .ancestors()
+                .position(|x| x.id() == root)
+                .map_or(0, |x| x + 1);
+            (x.id(), depth)
+        })
+        .collect::<Vec<_>>();
+    let line = |depth: usize| {
+        Node::Text(Text {
+            text: format!("\n{}", "  ".repeat(depth)).into(),
+        })
+    };
+    for (id, depth) in parents {
+        let children = html
+            .tree
+            .get(id)
+            .unwrap()
+            .children()
+            .filter(|&x| is_block(x))
+            .map(|x| x.id())
+            .collect::<Vec<_>>();
+        for child in children {
+            html.tree
+                .get_mut(child)
+                .unwrap()
+                .insert_before(line(depth + 1));
+        }
+        html.tree.get_mut(id).unwrap().append(line(depth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Whitespace;
+    use scraper::{Html, Selector};
+
+    fn apply(whitespace: Whitespace, html: &str) -> String {
+        let mut html = Html::parse_fragment(html);
+        let root = html
+            .select(&Selector::parse(".chapter-content").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        whitespace.apply(&mut html, root);
+        html.tree
+            .get(root)
+            .and_then(scraper::ElementRef::wrap)
+            .unwrap()
+            .html()
+    }
+
+    #[test]
+    fn whitespace() {
+        let html = "<div class=\"chapter-content\">\n  <p>One  <b>two</b>\n three </p><!-- ad -->\n<div>  <p>Four</p> </div><pre>  five\n six</pre></div>";
+        assert_eq!(apply(Whitespace::Keep, html), html);
+        assert_eq!(
+            apply(Whitespace::Minify, html),
+            "<div class=\"chapter-content\"><p>One <b>two</b> three</p><div><p>Four</p></div><pre>  five\n six</pre></div>"
+        );
+        assert_eq!(
+            apply(Whitespace::Pretty, html),
+            "<div class=\"chapter-content\">\n  <p>One <b>two</b> three</p>\n  <div>\n    <p>Four</p>\n  </div>\n  <pre>  five\n six</pre>\n</div>"
+        );
+    }
+}
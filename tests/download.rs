@@ -0,0 +1,318 @@
+//! Downloads of a fiction served by a mock server, from its table of contents to the html written for its chapters.
+
+use reqwest::cookie::Jar;
+use royalroad_dl::{
+    download_chapters, download_fiction, fetch_chapter_links, fetch_main_page, parse_main_title,
+    parse_output, parse_toc_page, selectors::Selectors, ChapterUrl, DownloadOptions, Limiter,
+};
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use url::Url;
+use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+/// Path of the fiction's main page.
+const FICTION_PATH: &str = "/fiction/12345/the-title";
+
+/// Path of the chapter with `id`.
+fn chapter_path(id: u32) -> String {
+    format!("{FICTION_PATH}/chapter/{id}/chapter-{id}")
+}
+
+/// Main page of the fiction with a table of contents listing the chapters with `ids`.
+fn main_page(ids: impl IntoIterator<Item = u32>) -> String {
+    let rows = ids
+        .into_iter()
+        .map(|id| {
+            format!(
+                r#"<tr data-url="{0}"><td><a href="{0}">Chapter {id}</a></td></tr>"#,
+                chapter_path(id)
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<html><head><title>The Title | Royal Road</title></head><body><div class="fic-title"><h1>The Title</h1><h4><span>by</span> <span><a href="/profile/1">The Author</a></span></h4></div><table id="chapters"><tbody>{rows}</tbody></table></body></html>"#
+    )
+}
+
+/// Page of the chapter with `id`, including a warning paragraph hidden on the site.
+fn chapter_page(id: u32) -> String {
+    format!(
+        r#"<html><head><title>Chapter {id} - The Title | Royal Road</title></head><body><div class="chapter-content"><p>Content {id}</p><p class="cjWarningAbc">Stolen from Royal Road.</p></div></body></html>"#
+    )
+}
+
+/// Serve the main page listing the chapters with `ids`.
+async fn mount_main_page(server: &MockServer, ids: impl IntoIterator<Item = u32>) {
+    Mock::given(matchers::path(FICTION_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string(main_page(ids)))
+        .mount(server)
+        .await;
+}
+
+/// Serve the pages of the chapters with `ids`, each expected to be requested `expect` times.
+async fn mount_chapters(server: &MockServer, ids: impl IntoIterator<Item = u32>, expect: u64) {
+    for id in ids {
+        Mock::given(matchers::path(chapter_path(id)))
+            .respond_with(ResponseTemplate::new(200).set_body_string(chapter_page(id)))
+            .expect(expect)
+            .mount(server)
+            .await;
+    }
+}
+
+/// Client using `jar` as its cookie store.
+fn client(jar: &Arc<Jar>) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .cookie_provider(jar.clone())
+        .build()
+}
+
+/// Options which don't wait between requests.
+fn options() -> DownloadOptions {
+    DownloadOptions {
+        time_limit: Duration::from_millis(1),
+        jitter: Duration::ZERO,
+        print_progress: false,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn download_fiction_chapters() -> anyhow::Result<()> {
+    let server = MockServer::start().await;
+    mount_main_page(&server, 1..=3).await;
+    mount_chapters(&server, 1..=3, 1).await;
+    let url = Url::parse(&server.uri())?.join(FICTION_PATH)?;
+
+    let jar = Arc::new(Jar::default());
+    let fiction = download_fiction(&client(&jar)?, &jar, &url, &options()).await?;
+    assert_eq!(fiction.title, "The Title | Royal Road");
+    assert_eq!(fiction.metadata.author.as_deref(), Some("The Author"));
+    assert_eq!(fiction.chapters.len(), 3);
+    for (id, chapter) in (1..).zip(&fiction.chapters) {
+        assert_eq!(chapter.index, id as usize - 1);
+        assert_eq!(chapter.title, format!("Chapter {id}"));
+        assert_eq!(chapter.url.path(), chapter_path(id));
+        // Warning paragraphs are removed.
+        assert_eq!(
+            chapter.body_html,
+            format!(r#"<div class="chapter-content"><p>Content {id}</p></div>"#)
+        );
+    }
+
+    // Chapters are found again in the html written for them, whose headings have ids from their urls to link to.
+    let output = fiction
+        .chapters
+        .iter()
+        .map(|x| x.to_html())
+        .collect::<String>();
+    assert!(output.contains(&format!(
+        r#"<h1><a class="chapter" href="{}" id="chapter-3">Chapter 3</a></h1>"#,
+        fiction.chapters[2].url
+    )));
+    let parsed = parse_output(&output);
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[2].title, "Chapter 3");
+    assert_eq!(parsed[2].url, fiction.chapters[2].url);
+    assert_eq!(parsed[2].content, fiction.chapters[2].body_html);
+    Ok(())
+}
+
+#[tokio::test]
+async fn incremental_resume_skips_cached_chapters() -> anyhow::Result<()> {
+    let server = MockServer::start().await;
+    mount_main_page(&server, 1..=2).await;
+    mount_chapters(&server, 1..=2, 1).await;
+    let url = Url::parse(&server.uri())?.join(FICTION_PATH)?;
+    let jar = Arc::new(Jar::default());
+    let client = client(&jar)?;
+    let opts = options();
+
+    // First download.
+    let output = download_fiction(&client, &jar, &url, &opts)
+        .await?
+        .chapters
+        .iter()
+        .map(|x| x.to_html())
+        .collect::<String>();
+    server.verify().await;
+
+    // A chapter is released. Only it is downloaded.
+    server.reset().await;
+    mount_main_page(&server, 1..=3).await;
+    mount_chapters(&server, 1..=2, 0).await;
+    mount_chapters(&server, [3], 1).await;
+    let cached = parse_output(&output)
+        .into_iter()
+        .map(|x| ChapterUrl(x.url))
+        .collect::<HashSet<_>>();
+    assert_eq!(cached.len(), 2);
+
+    let selectors = Arc::new(Selectors::default());
+    let limiter = Arc::new(Limiter::new(opts.time_limit, opts.jitter));
+    let main_html = fetch_main_page(&client, &jar, &selectors, &limiter, &url, false).await?;
+    let main_title = parse_main_title(&selectors, &main_html)?;
+    let first_page = parse_toc_page(&selectors, &main_html, &url);
+    let toc = fetch_chapter_links(&client, &selectors, &limiter, &url, first_page).await?;
+    let new_chapters = toc
+        .into_iter()
+        .enumerate()
+        .filter(|(_, x)| !cached.contains(&x.url))
+        .map(|(i, x)| (i, x.url))
+        .collect::<Vec<_>>();
+    assert_eq!(new_chapters.len(), 1);
+
+    let mut downloads = download_chapters(
+        &client,
+        &selectors,
+        &limiter,
+        new_chapters,
+        3,
+        &main_title,
+        &opts,
+    );
+    let chapter = downloads.next_result().await.unwrap()??;
+    assert!(downloads.next_result().await.is_none());
+    assert_eq!(chapter.index, 2);
+    assert_eq!(chapter.title, "Chapter 3");
+
+    let output = output + &chapter.to_html();
+    assert_eq!(
+        parse_output(&output)
+            .iter()
+            .map(|x| x.title.as_str())
+            .collect::<Vec<_>>(),
+        ["Chapter 1", "Chapter 2", "Chapter 3"]
+    );
+    server.verify().await;
+    Ok(())
+}
+
+/// Requests served by [`counting_server`].
+struct Served {
+    /// Most requests in flight at once.
+    most_in_flight: usize,
+    /// When each request arrived, in order.
+    arrivals: Vec<Instant>,
+}
+
+/// Serve `count` chapter pages, one per connection. Returns the server's url and what it served.
+///
+/// Responses are held and sent together every 200ms so connections are released at once.
+fn counting_server(count: usize) -> std::io::Result<(Url, std::thread::JoinHandle<Served>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = Url::parse(&format!("http://{}", listener.local_addr()?)).unwrap();
+    let server = std::thread::spawn(move || {
+        let start = Instant::now();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let handlers = listener
+            .incoming()
+            .take(count)
+            .map(|stream| {
+                let in_flight = in_flight.clone();
+                let most = most.clone();
+                let arrivals = arrivals.clone();
+                std::thread::spawn(move || -> std::io::Result<()> {
+                    let mut reader = BufReader::new(stream?);
+                    let mut line = String::new();
+                    reader.read_line(&mut line)?;
+                    arrivals.lock().unwrap().push(Instant::now());
+                    let id = line
+                        .split('/')
+                        .nth(5)
+                        .and_then(|x| x.parse().ok())
+                        .unwrap_or(0);
+                    while reader.read_line(&mut line)? > 2 {
+                        line.clear();
+                    }
+                    most.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    let slot = Duration::from_millis(200);
+                    let held = start.elapsed().as_millis() / slot.as_millis() + 1;
+                    std::thread::sleep(start + slot * held as u32 - Instant::now());
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let page = chapter_page(id);
+                    write!(
+                        reader.into_inner(),
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{page}",
+                        page.len()
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        for handler in handlers {
+            handler.join().unwrap().unwrap();
+        }
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        Served {
+            most_in_flight: most.load(Ordering::SeqCst),
+            arrivals,
+        }
+    });
+    Ok((url, server))
+}
+
+#[tokio::test]
+async fn connections_limit_requests_in_flight() -> anyhow::Result<()> {
+    for connections in [2, 0] {
+        let (url, server) = counting_server(6)?;
+        let chapters = (1..=6)
+            .map(|id| {
+                (
+                    id as usize - 1,
+                    ChapterUrl(url.join(&chapter_path(id)).unwrap()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let selectors = Arc::new(Selectors::default());
+        let opts = DownloadOptions {
+            connections,
+            time_limit: Duration::from_millis(40),
+            ..options()
+        };
+        let limiter = Arc::new(Limiter::new(opts.time_limit, opts.jitter));
+        let client = reqwest::Client::new();
+        let mut downloads = download_chapters(
+            &client,
+            &selectors,
+            &limiter,
+            chapters,
+            6,
+            "The Title | Royal Road",
+            &opts,
+        );
+        let mut titles = Vec::new();
+        while let Some(chapter) = downloads.next_result().await {
+            titles.push(chapter??.title);
+        }
+        assert_eq!(titles.len(), 6);
+        assert_eq!(titles[5], "Chapter 6");
+
+        let Served {
+            most_in_flight: most,
+            arrivals,
+        } = server.join().unwrap();
+        match connections {
+            0 => assert!(most > 2, "{most} requests in flight without a limit"),
+            x => assert!(most <= x, "{most} requests in flight with {x} connections"),
+        }
+        // Requests waiting for a connection still wait for the rate limit once they have one.
+        for gap in arrivals.windows(2).map(|x| x[1] - x[0]) {
+            assert!(
+                gap >= Duration::from_millis(30),
+                "requests {gap:?} apart with {connections} connections"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Memory held while reading large chapter pages, counted by a global allocator.
+
+use royalroad_dl::read_text;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Allocator counting the bytes allocated at once.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn allocated(size: usize) {
+    let current = CURRENT.fetch_add(size, Ordering::SeqCst) + size;
+    PEAK.fetch_max(current, Ordering::SeqCst);
+}
+
+// SAFETY: Allocation is left to `System`, only counting what it allocated.
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[tokio::test]
+async fn large_chapter_text_is_held_once() -> anyhow::Result<()> {
+    // About 8 MB, in whole pieces so multibyte characters aren't cut.
+    let piece = "<p>Content é</p>\n".repeat(4096);
+    let len = piece.len() * 120;
+    // Served in pieces from a fixed buffer so the server doesn't hold the page.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = std::thread::spawn(move || -> std::io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\ncontent-length: {len}\r\n\r\n"
+        )?;
+        for _ in 0..len / piece.len() {
+            stream.write_all(piece.as_bytes())?;
+        }
+        Ok(())
+    });
+
+    let response = reqwest::get(format!("http://{addr}/fiction/1/t/chapter/1/c")).await?;
+    PEAK.store(CURRENT.load(Ordering::SeqCst), Ordering::SeqCst);
+    let before = CURRENT.load(Ordering::SeqCst);
+    let text = read_text(response).await?;
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    server.join().unwrap()?;
+
+    assert_eq!(text.len(), len);
+    assert!(text.starts_with("<p>Content é</p>\n"));
+    // Reading the body then decoding it holds at least twice the page.
+    assert!(peak < len + len / 4, "{peak} bytes held for {len}");
+    Ok(())
+}